@@ -0,0 +1,91 @@
+//! Monotonic clock from the `time` CSR, and timer deadlines through SBI.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use riscv::register::{sie, time};
+
+use crate::{interrupt, sbi};
+use crate::{Error, Result};
+
+/// Frequency of the `time` CSR in Hz
+static TIMEBASE_FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the SBI implementation has the TIME extension
+static HAS_TIME_EXTENSION: AtomicBool = AtomicBool::new(false);
+
+/// Set up the clock with the `timebase-frequency` from the device tree,
+/// and enable timer interrupts.
+pub fn init(timebase_frequency: u64) {
+    TIMEBASE_FREQUENCY.store(timebase_frequency as usize, Ordering::Release);
+    let has_time = sbi::probe_extension(sbi::EXTENSION_TIMER) != 0;
+    HAS_TIME_EXTENSION.store(has_time, Ordering::Release);
+    init_hart();
+}
+
+/// Enable timer interrupts on this hart.
+pub fn init_hart() {
+    unsafe { sie::set_stimer() };
+}
+
+/// Time since the hart is powered on.
+pub fn now() -> Duration {
+    let ticks = time::read() as u64;
+    let frequency = TIMEBASE_FREQUENCY.load(Ordering::Acquire) as u64;
+    let secs = ticks / frequency;
+    let nanos = (ticks % frequency) * 1_000_000_000 / frequency;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Raise a timer interrupt on this hart at `deadline`.
+///
+/// Only the latest deadline is kept.
+pub fn set_deadline(deadline: Duration) {
+    let frequency = TIMEBASE_FREQUENCY.load(Ordering::Acquire) as u64;
+    let ticks = deadline.as_secs() * frequency
+        + deadline.subsec_nanos() as u64 * frequency / 1_000_000_000;
+    set_timer(ticks);
+}
+
+/// Sleep for `duration`, waking up on the timer interrupt.
+pub fn sleep(duration: Duration) {
+    let deadline = now() + duration;
+    set_deadline(deadline);
+    interrupt::wait_until(|| now() >= deadline);
+}
+
+/// Wait until `condition` holds, or fail with `Error::Timeout` after `timeout`.
+///
+/// With `sleep` the hart sleeps between interrupts, otherwise it spins.
+pub fn wait_for(timeout: Duration, sleep: bool, condition: impl Fn() -> bool) -> Result {
+    let deadline = now() + timeout;
+    if sleep {
+        set_deadline(deadline);
+        interrupt::wait_until(|| condition() || now() >= deadline);
+    } else {
+        while !condition() && now() < deadline {
+            spin_loop();
+        }
+    }
+    if condition() {
+        Ok(())
+    } else {
+        Err(Error::Timeout)
+    }
+}
+
+/// Handle a supervisor timer interrupt.
+///
+/// Waiters check their own deadlines, so it's enough to clear the pending
+/// interrupt by pushing the timer to the far future.
+pub fn handle_timer() {
+    set_timer(u64::MAX);
+}
+
+fn set_timer(ticks: u64) {
+    if HAS_TIME_EXTENSION.load(Ordering::Acquire) && sbi::sbi_set_timer(ticks).is_ok() {
+        return;
+    }
+    sbi::set_timer(ticks as usize);
+}
@@ -0,0 +1,388 @@
+use core::mem::{size_of, size_of_val};
+use core::time::Duration;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use volatile_register::{RO, RW};
+
+use crate::hal;
+use crate::mmio::{DeviceType, INTERRUPT_CONFIG_CHANGE, PAGE_SIZE};
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 4;
+const QUEUE_INFLATE: usize = 0;
+const QUEUE_DEFLATE: usize = 1;
+const QUEUE_STATS: usize = 2;
+
+/// The most page frame numbers sent in one request.
+const PFNS_PER_REQUEST: usize = 256;
+
+/// How long the device may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The balloon always counts in 4 KiB pages, whatever the page size.
+const BALLOON_PAGE_SHIFT: usize = 12;
+
+/// The virtio memory balloon device lets the host take guest memory away
+/// and give it back.
+///
+/// Inflating takes page frames from the kernel heap and hands them to the
+/// host; deflating takes them back and frees them to the heap. The driver
+/// always tells the host before reusing a page. Pages still in the balloon
+/// when the driver is dropped are never freed.
+///
+/// Ref: 5.5 Traditional Memory Balloon Device
+pub struct VirtIoBalloon<'a, T: Transport> {
+    header: &'a mut T,
+    inflate_queue: VirtQueue,
+    deflate_queue: VirtQueue,
+    /// Only set up with VIRTIO_BALLOON_F_STATS_VQ
+    stats_queue: Option<VirtQueue>,
+    /// The buffer owned by the stats queue
+    stats: Box<[Stat; STATS_COUNT]>,
+    /// Addresses of the pages in the balloon
+    pages: Vec<usize>,
+    /// The interrupt line of the device, if registered
+    irq: Option<u32>,
+    /// The hart receiving the interrupts of the device, if any
+    irq_hart: Option<usize>,
+}
+
+impl<'a, T: Transport> VirtIoBalloon<'a, T> {
+    /// Create a new virtio-balloon driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::MemoryBallooning {
+            return Err(Error::InvalidParam);
+        }
+        let features = header.begin_init(|features| {
+            let features = BalloonFeature::from_bits_truncate(features);
+            let supported = BalloonFeature::MUST_TELL_HOST | BalloonFeature::STATS_VQ;
+            (features & supported).bits()
+        })?;
+        let features = BalloonFeature::from_bits_truncate(features);
+
+        let inflate_queue = VirtQueue::new(header, QUEUE_INFLATE, QUEUE_SIZE)?;
+        let deflate_queue = VirtQueue::new(header, QUEUE_DEFLATE, QUEUE_SIZE)?;
+        let stats = Box::new([Stat::default(); STATS_COUNT]);
+        let stats_queue = if features.contains(BalloonFeature::STATS_VQ) {
+            let mut queue = VirtQueue::new(header, QUEUE_STATS, QUEUE_SIZE)?;
+            // 初始化时就要放一个统计缓冲区，设备需要统计数据时把它还回来
+            queue.add(&[stats_as_buf(&stats)], &[])?;
+            Some(queue)
+        } else {
+            None
+        };
+        header.finish_init();
+        if let Some(queue) = stats_queue.as_ref() {
+            queue.notify(header);
+        }
+        Ok(VirtIoBalloon {
+            header,
+            inflate_queue,
+            deflate_queue,
+            stats_queue,
+            stats,
+            pages: Vec::new(),
+            irq: None,
+            irq_hart: None,
+        })
+    }
+
+    /// Learn about configuration changes through interrupt `irq`.
+    ///
+    /// The interrupt goes to the calling hart, other harts still poll.
+    /// Does nothing if external interrupts are not set up.
+    pub fn use_interrupt(&mut self, irq: u32)
+    where
+        T: 'static,
+    {
+        if hal::interrupt_enabled() {
+            // 驱动析构时注销，在此之前 transport 一直有效
+            unsafe { hal::register_interrupt(irq, self.header) };
+            self.irq = Some(irq);
+            self.irq_hart = Some(hal::hart_id());
+        }
+    }
+
+    /// The number of pages the host wants in the balloon.
+    pub fn target_pages(&self) -> u32 {
+        self.config().num_pages.read()
+    }
+
+    /// The number of pages in the balloon.
+    pub fn actual_pages(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Wait until the host changes the configuration, at most for `timeout`.
+    ///
+    /// Without an interrupt, this waits until the target differs from the
+    /// balloon size.
+    pub fn wait_config_change(&mut self, timeout: Duration) -> Result {
+        match self.irq {
+            Some(irq) => {
+                let sleep = self.irq_hart == Some(hal::hart_id());
+                hal::wait_for(timeout, sleep, || hal::interrupt_status(irq) & INTERRUPT_CONFIG_CHANGE != 0)?;
+                hal::take_interrupt_status(irq, INTERRUPT_CONFIG_CHANGE);
+                Ok(())
+            }
+            None => {
+                let config = self.config();
+                let actual = self.actual_pages();
+                hal::wait_for(timeout, false, || config.num_pages.read() != actual)
+            }
+        }
+    }
+
+    /// Inflate or deflate the balloon towards the target of the host, return
+    /// the new balloon size in pages.
+    ///
+    /// Inflating stops early when the heap runs out of pages. Fails with
+    /// `Error::NoMemory` before inflating when the heap cannot track all the
+    /// pages of the delta.
+    pub fn update(&mut self) -> Result<u32> {
+        let target = self.target_pages() as usize;
+        let mut pfns = [0u32; PFNS_PER_REQUEST];
+        // 先为整个差额预留 pages，push 时就不会因分配失败走到 OOM 处理
+        if target > self.pages.len() && self.pages.try_reserve(target - self.pages.len()).is_err() {
+            return Err(Error::NoMemory);
+        }
+        while self.pages.len() < target {
+            let count = (target - self.pages.len()).min(PFNS_PER_REQUEST);
+            let mut taken = 0;
+            while taken < count {
+                let page = unsafe { alloc(page_layout()) };
+                if page.is_null() {
+                    break;
+                }
+                pfns[taken] = (page as usize >> BALLOON_PAGE_SHIFT) as u32;
+                self.pages.push(page as usize);
+                taken += 1;
+            }
+            if taken == 0 {
+                break;
+            }
+            request(self.header, &mut self.inflate_queue, pfns_as_buf(&pfns[..taken]))?;
+        }
+        while self.pages.len() > target {
+            let count = (self.pages.len() - target).min(PFNS_PER_REQUEST);
+            let start = self.pages.len() - count;
+            for (pfn, page) in pfns.iter_mut().zip(&self.pages[start..]) {
+                *pfn = (page >> BALLOON_PAGE_SHIFT) as u32;
+            }
+            // 先告诉设备，再把页还给堆
+            request(self.header, &mut self.deflate_queue, pfns_as_buf(&pfns[..count]))?;
+            for page in self.pages.drain(start..) {
+                unsafe { dealloc(page as *mut u8, page_layout()) };
+            }
+        }
+        let actual = self.actual_pages();
+        unsafe { self.config().actual.write(actual) };
+        Ok(actual)
+    }
+
+    /// Answer a pending statistics request of the device with `stats`.
+    ///
+    /// Returns whether the device had asked for statistics.
+    pub fn serve_stats(&mut self, stats: &MemStats) -> Result<bool> {
+        let queue = match self.stats_queue.as_mut() {
+            Some(queue) => queue,
+            None => return Err(Error::Unsupported),
+        };
+        if !queue.can_pop() {
+            return Ok(false);
+        }
+        queue.pop_used()?;
+        self.stats[0] = Stat::new(VIRTIO_BALLOON_S_MEMTOT, stats.total);
+        self.stats[1] = Stat::new(VIRTIO_BALLOON_S_MEMFREE, stats.free);
+        self.stats[2] = Stat::new(VIRTIO_BALLOON_S_AVAIL, stats.available);
+        queue.add(&[stats_as_buf(&self.stats)], &[])?;
+        queue.notify(self.header);
+        Ok(true)
+    }
+
+    fn config(&self) -> &BalloonConfig {
+        unsafe { self.header.config::<BalloonConfig>() }
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoBalloon<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+        if let Some(irq) = self.irq {
+            hal::unregister_interrupt(irq);
+        }
+    }
+}
+
+/// Submit one request on `queue` and wait until the device has served it.
+///
+/// If the device hangs, it is reset so it stops touching the buffers,
+/// and the driver can't be used anymore.
+fn request<T: Transport>(header: &mut T, queue: &mut VirtQueue, input: &[u8]) -> Result {
+    let token = queue.add(&[input], &[])?;
+    queue.notify(header);
+    let waiting = &*queue;
+    if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || waiting.can_pop()) {
+        header.reset();
+        return Err(e);
+    }
+    let (used, _len) = queue.pop_used()?;
+    // 一次只提交一个请求，设备返回的一定是它
+    assert_eq!(used, token);
+    Ok(())
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+fn pfns_as_buf(pfns: &[u32]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(pfns.as_ptr() as *const u8, size_of_val(pfns)) }
+}
+
+fn stats_as_buf(stats: &[Stat; STATS_COUNT]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(stats.as_ptr() as *const u8, size_of::<[Stat; STATS_COUNT]>()) }
+}
+
+/// Memory statistics reported to the host, in bytes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemStats {
+    /// The amount of memory available to the guest
+    pub total: u64,
+    /// The amount of memory left unused
+    pub free: u64,
+    /// An estimate of how much memory can be allocated
+    pub available: u64,
+}
+
+#[repr(C)]
+struct BalloonConfig {
+    /// Number of pages host wants Guest to give up
+    num_pages: RO<u32>,
+    /// Number of pages we've actually got in balloon
+    actual: RW<u32>,
+}
+
+/// One statistic, `struct virtio_balloon_stat`.
+#[repr(C, packed)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Stat {
+    tag: u16,
+    val: u64,
+}
+
+impl Stat {
+    fn new(tag: u16, val: u64) -> Self {
+        Stat { tag, val }
+    }
+}
+
+/// The number of statistics the driver reports.
+const STATS_COUNT: usize = 3;
+
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+
+bitflags! {
+    struct BalloonFeature: u64 {
+        /// Host has to be told before pages from the balloon are used.
+        const MUST_TELL_HOST    = 1 << 0;
+        /// A virtqueue for reporting guest memory statistics is present.
+        const STATS_VQ          = 1 << 1;
+        /// Deflate balloon on guest out of memory condition.
+        const DEFLATE_ON_OOM    = 1 << 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::convert::TryInto;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::mock::{MockDevice, MockTransport};
+
+    const IRQ: u32 = 3;
+
+    /// A balloon device recording the page frame numbers it is given and
+    /// given back.
+    fn balloon() -> (MockTransport, MockDevice, Rc<RefCell<Vec<u32>>>, Rc<RefCell<Vec<u32>>>) {
+        let (transport, device) = MockTransport::new(DeviceType::MemoryBallooning, 0);
+        let inflated = record_pfns(&device, QUEUE_INFLATE);
+        let deflated = record_pfns(&device, QUEUE_DEFLATE);
+        (transport, device, inflated, deflated)
+    }
+
+    fn record_pfns(device: &MockDevice, queue: usize) -> Rc<RefCell<Vec<u32>>> {
+        let pfns = Rc::new(RefCell::new(Vec::new()));
+        let recorded = pfns.clone();
+        device.on_queue(queue as u32, move |chain| {
+            let chunks = chain.input.chunks_exact(4);
+            recorded.borrow_mut().extend(chunks.map(|pfn| u32::from_le_bytes(pfn.try_into().unwrap())));
+            Some(0)
+        });
+        pfns
+    }
+
+    fn set_target(device: &MockDevice, pages: u32) {
+        device.set_config(0, &pages.to_le_bytes());
+        device.config_changed();
+    }
+
+    fn actual(device: &MockDevice) -> u32 {
+        let mut actual = [0u8; 4];
+        device.read_config(4, &mut actual);
+        u32::from_le_bytes(actual)
+    }
+
+    #[test]
+    fn inflate_and_deflate_on_config_change() {
+        let (mut transport, device, inflated, deflated) = balloon();
+        let mut balloon = VirtIoBalloon::new(&mut transport).unwrap();
+        balloon.use_interrupt(IRQ);
+        let timeout = Duration::from_millis(10);
+        assert_eq!(balloon.wait_config_change(timeout), Err(Error::Timeout));
+
+        set_target(&device, 300);
+        assert_eq!(balloon.wait_config_change(timeout), Ok(()));
+        // 中断状态已经取走，不会再次返回
+        assert_eq!(balloon.wait_config_change(timeout), Err(Error::Timeout));
+        assert_eq!(balloon.update(), Ok(300));
+        assert_eq!(actual(&device), 300);
+        assert_eq!(device.notifications(QUEUE_INFLATE as u32), 2);
+        assert_eq!(inflated.borrow().len(), 300);
+
+        set_target(&device, 100);
+        balloon.wait_config_change(timeout).unwrap();
+        assert_eq!(balloon.update(), Ok(100));
+        assert_eq!(actual(&device), 100);
+        assert_eq!(&deflated.borrow()[..], &inflated.borrow()[100..]);
+    }
+
+    #[test]
+    fn polls_target_without_interrupt() {
+        let (mut transport, device, _, _) = balloon();
+        let mut balloon = VirtIoBalloon::new(&mut transport).unwrap();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(balloon.wait_config_change(timeout), Err(Error::Timeout));
+        // 没有中断时只看目标大小，不看配置变更中断
+        device.set_config(0, &2u32.to_le_bytes());
+        assert_eq!(balloon.wait_config_change(timeout), Ok(()));
+        assert_eq!(balloon.update(), Ok(2));
+        assert_eq!(balloon.wait_config_change(timeout), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn stats_need_the_feature() {
+        let (mut transport, _device, _, _) = balloon();
+        let mut balloon = VirtIoBalloon::new(&mut transport).unwrap();
+        assert_eq!(balloon.serve_stats(&MemStats::default()), Err(Error::Unsupported));
+    }
+}
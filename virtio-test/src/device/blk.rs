@@ -0,0 +1,503 @@
+use core::time::Duration;
+
+use bitflags::bitflags;
+use volatile_register::RO;
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+/// The size of a sector in bytes, which all block requests are counted in.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The length in bytes of the device id string.
+pub const ID_BYTES: usize = 20;
+
+const QUEUE_SIZE: u16 = 16;
+
+/// How long the device may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The virtio block device is a simple virtual block device (ie. disk).
+///
+/// Read and write requests (and other exotic requests) are placed in the
+/// queue, and serviced (probably out of order) by the device except where
+/// noted.
+///
+/// Ref: 5.2 Block Device
+pub struct VirtIoBlk<'a, T: Transport> {
+    header: &'a mut T,
+    queue: VirtQueue,
+    features: BlkFeature,
+    capacity: u64,
+    blk_size: u32,
+    seg_max: u32,
+    /// The interrupt line taken by the driver, if any
+    irq: Option<u32>,
+    /// The hart receiving the interrupts of the device, if any
+    irq_hart: Option<usize>,
+}
+
+impl<'a, T: Transport> VirtIoBlk<'a, T> {
+    /// Create a new virtio-blk driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Block {
+            return Err(Error::InvalidParam);
+        }
+        let features = header.begin_init(|features| {
+            let features = BlkFeature::from_bits_truncate(features);
+            let supported = BlkFeature::SEG_MAX | BlkFeature::BLK_SIZE | BlkFeature::RO | BlkFeature::FLUSH;
+            (features & supported).bits()
+        })?;
+        let features = BlkFeature::from_bits_truncate(features);
+
+        // read configuration space
+        let config = unsafe { header.config::<BlkConfig>() };
+        let capacity = config.capacity_low.read() as u64 | (config.capacity_high.read() as u64) << 32;
+        let blk_size = if features.contains(BlkFeature::BLK_SIZE) {
+            config.blk_size.read()
+        } else {
+            SECTOR_SIZE as u32
+        };
+        let seg_max = if features.contains(BlkFeature::SEG_MAX) {
+            config.seg_max.read()
+        } else {
+            1
+        };
+
+        let queue = VirtQueue::new(header, 0, QUEUE_SIZE)?;
+        header.finish_init();
+
+        Ok(VirtIoBlk {
+            header,
+            queue,
+            features,
+            capacity,
+            blk_size,
+            seg_max,
+            irq: None,
+            irq_hart: None,
+        })
+    }
+
+    /// Wait for completions on interrupt `irq` instead of busy-polling.
+    ///
+    /// The interrupt goes to the calling hart, other harts still poll.
+    /// Does nothing if external interrupts are not set up.
+    pub fn use_interrupt(&mut self, irq: u32)
+    where
+        T: 'static,
+    {
+        if hal::interrupt_enabled() {
+            // 驱动析构时注销，在此之前 transport 一直有效
+            unsafe { hal::register_interrupt(irq, self.header) };
+            self.irq = Some(irq);
+            self.irq_hart = Some(hal::hart_id());
+        }
+    }
+
+    /// The capacity of the device in 512-byte sectors.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The optimal block size of the device in bytes.
+    pub fn blk_size(&self) -> u32 {
+        self.blk_size
+    }
+
+    /// The maximum number of data segments in one request.
+    pub fn seg_max(&self) -> u32 {
+        self.seg_max
+    }
+
+    /// Whether the device is read-only.
+    pub fn readonly(&self) -> bool {
+        self.features.contains(BlkFeature::RO)
+    }
+
+    /// Read sectors starting from `sector` into `buf`.
+    ///
+    /// The length of `buf` must be a multiple of the sector size.
+    pub fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result {
+        self.check_range(sector, buf.len())?;
+        let req = BlkReq::new(ReqType::In, sector);
+        let mut resp = BlkResp::default();
+        self.request(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
+        resp.status()
+    }
+
+    /// Write sectors starting from `sector` with the data in `buf`.
+    ///
+    /// The length of `buf` must be a multiple of the sector size.
+    pub fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result {
+        if self.readonly() {
+            return Err(Error::Unsupported);
+        }
+        self.check_range(sector, buf.len())?;
+        let req = BlkReq::new(ReqType::Out, sector);
+        let mut resp = BlkResp::default();
+        self.request(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
+        resp.status()
+    }
+
+    /// Flush the write cache of the device.
+    pub fn flush(&mut self) -> Result {
+        if !self.features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        let req = BlkReq::new(ReqType::Flush, 0);
+        let mut resp = BlkResp::default();
+        self.request(&[req.as_buf()], &[resp.as_buf_mut()])?;
+        resp.status()
+    }
+
+    /// Get the device id string, return its length.
+    ///
+    /// The string is not NUL-terminated if it takes all 20 bytes.
+    pub fn get_id(&mut self, id: &mut [u8; ID_BYTES]) -> Result<usize> {
+        let req = BlkReq::new(ReqType::GetId, 0);
+        let mut resp = BlkResp::default();
+        self.request(&[req.as_buf()], &[&mut id[..], resp.as_buf_mut()])?;
+        resp.status()?;
+        Ok(id.iter().position(|&b| b == 0).unwrap_or(ID_BYTES))
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result {
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return Err(Error::InvalidParam);
+        }
+        match sector.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Submit one request and wait until the device has served it.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    fn request(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result {
+        let token = self.queue.add(inputs, outputs)?;
+        self.queue.notify(self.header);
+        let queue = &self.queue;
+        let sleep = self.irq_hart == Some(hal::hart_id());
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, sleep, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.queue.pop_used()?;
+        // 一次只提交一个请求，设备返回的一定是它
+        assert_eq!(used, token);
+        Ok(())
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoBlk<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+        if let Some(irq) = self.irq {
+            hal::unregister_interrupt(irq);
+        }
+    }
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct BlkConfig {
+    /// Number of 512 Bytes sectors
+    capacity_low: RO<u32>,
+    capacity_high: RO<u32>,
+    size_max: RO<u32>,
+    seg_max: RO<u32>,
+    cylinders: RO<u16>,
+    heads: RO<u8>,
+    sectors: RO<u8>,
+    blk_size: RO<u32>,
+}
+
+#[repr(C)]
+struct BlkReq {
+    type_: ReqType,
+    reserved: u32,
+    sector: u64,
+}
+
+impl BlkReq {
+    fn new(type_: ReqType, sector: u64) -> Self {
+        BlkReq {
+            type_,
+            reserved: 0,
+            sector,
+        }
+    }
+}
+
+unsafe impl AsBuf for BlkReq {}
+
+/// Response of a request, written by the device.
+#[repr(C)]
+struct BlkResp {
+    status: u8,
+}
+
+impl Default for BlkResp {
+    fn default() -> Self {
+        // 设备没有写回状态时按未支持处理
+        BlkResp {
+            status: VIRTIO_BLK_S_UNSUPP,
+        }
+    }
+}
+
+impl BlkResp {
+    fn status(&self) -> Result {
+        match self.status {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_UNSUPP => Err(Error::Unsupported),
+            // VIRTIO_BLK_S_IOERR, or anything the device should not write
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+unsafe impl AsBuf for BlkResp {}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+enum ReqType {
+    In = 0,
+    Out = 1,
+    Flush = 4,
+    GetId = 8,
+}
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+bitflags! {
+    struct BlkFeature: u64 {
+        /// Device supports request barriers. (legacy)
+        const BARRIER       = 1 << 0;
+        /// Maximum size of any single segment is in `size_max`.
+        const SIZE_MAX      = 1 << 1;
+        /// Maximum number of segments in a request is in `seg_max`.
+        const SEG_MAX       = 1 << 2;
+        /// Disk-style geometry specified in geometry.
+        const GEOMETRY      = 1 << 4;
+        /// Device is read-only.
+        const RO            = 1 << 5;
+        /// Block size of disk is in `blk_size`.
+        const BLK_SIZE      = 1 << 6;
+        /// Device supports scsi packet commands. (legacy)
+        const SCSI          = 1 << 7;
+        /// Cache flush command support.
+        const FLUSH         = 1 << 9;
+        /// Device exports information on optimal I/O alignment.
+        const TOPOLOGY      = 1 << 10;
+        /// Device can toggle its cache between writeback and writethrough modes.
+        const CONFIG_WCE    = 1 << 11;
+        /// Device can support discard command.
+        const DISCARD       = 1 << 13;
+        /// Device can support write zeroes command.
+        const WRITE_ZEROES  = 1 << 14;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::convert::TryInto;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::mmio::{DeviceStatus, VIRTIO_F_VERSION_1};
+    use crate::mock::{Chain, Fault, MockDevice, MockTransport};
+
+    const CAPACITY: u64 = 8;
+    const DEVICE_ID: &[u8] = b"mock-disk";
+    const VIRTIO_BLK_S_IOERR: u8 = 1;
+    /// Sectors from here on fail with VIRTIO_BLK_S_IOERR
+    const BAD_SECTOR: u64 = 6;
+
+    /// A disk of `CAPACITY` sectors in host memory.
+    fn disk(features: BlkFeature) -> (MockTransport, MockDevice, Rc<RefCell<Vec<u8>>>) {
+        let (transport, device) = MockTransport::new(DeviceType::Block, features.bits());
+        device.set_config(0, &CAPACITY.to_le_bytes());
+        device.set_config(12, &4u32.to_le_bytes());
+        device.set_config(20, &4096u32.to_le_bytes());
+        let data = Rc::new(RefCell::new(vec![0u8; CAPACITY as usize * SECTOR_SIZE]));
+        let disk = data.clone();
+        device.on_queue(0, move |chain| Some(serve(&mut disk.borrow_mut(), chain)));
+        (transport, device, data)
+    }
+
+    /// Serve one block request, return the number of bytes written.
+    fn serve(disk: &mut [u8], chain: &mut Chain) -> u32 {
+        let type_ = u32::from_le_bytes(chain.input[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(chain.input[8..16].try_into().unwrap());
+        let offset = sector as usize * SECTOR_SIZE;
+        let status_at = chain.output_len() - 1;
+        let (in_, out) = (ReqType::In as u32, ReqType::Out as u32);
+        let (status, len) = if (type_ == in_ || type_ == out) && sector >= BAD_SECTOR {
+            (VIRTIO_BLK_S_IOERR, 0)
+        } else if type_ == in_ {
+            chain.write(0, &disk[offset..offset + status_at]);
+            (VIRTIO_BLK_S_OK, status_at)
+        } else if type_ == out {
+            let data = &chain.input[16..];
+            disk[offset..offset + data.len()].copy_from_slice(data);
+            (VIRTIO_BLK_S_OK, 0)
+        } else if type_ == ReqType::Flush as u32 {
+            (VIRTIO_BLK_S_OK, 0)
+        } else if type_ == ReqType::GetId as u32 {
+            chain.write(0, DEVICE_ID);
+            (VIRTIO_BLK_S_OK, status_at)
+        } else {
+            (VIRTIO_BLK_S_UNSUPP, 0)
+        };
+        chain.write(status_at, &[status]);
+        (len + 1) as u32
+    }
+
+    #[test]
+    fn init_reads_config() {
+        let (mut transport, device, _) = disk(BlkFeature::SEG_MAX | BlkFeature::BLK_SIZE);
+        let blk = VirtIoBlk::new(&mut transport).unwrap();
+        assert_eq!(blk.capacity(), CAPACITY);
+        assert_eq!(blk.blk_size(), 4096);
+        assert_eq!(blk.seg_max(), 4);
+        assert!(!blk.readonly());
+        assert!(device.status().contains(DeviceStatus::DRIVER_OK));
+        assert_ne!(device.driver_features() & VIRTIO_F_VERSION_1, 0);
+    }
+
+    #[test]
+    fn config_ignored_without_features() {
+        let (mut transport, _device, _) = disk(BlkFeature::empty());
+        let blk = VirtIoBlk::new(&mut transport).unwrap();
+        assert_eq!(blk.blk_size(), SECTOR_SIZE as u32);
+        assert_eq!(blk.seg_max(), 1);
+    }
+
+    #[test]
+    fn legacy_device_never_gets_version_1() {
+        let (mut transport, device) = MockTransport::new_legacy(DeviceType::Block, BlkFeature::FLUSH.bits());
+        let _blk = VirtIoBlk::new(&mut transport).unwrap();
+        assert_eq!(device.driver_features(), BlkFeature::FLUSH.bits());
+    }
+
+    #[test]
+    fn drop_resets_device() {
+        let (mut transport, device, _) = disk(BlkFeature::empty());
+        let blk = VirtIoBlk::new(&mut transport).unwrap();
+        assert!(device.status().contains(DeviceStatus::DRIVER_OK));
+        drop(blk);
+        assert!(device.status().is_empty());
+    }
+
+    #[test]
+    fn write_then_read() {
+        let (mut transport, _device, data) = disk(BlkFeature::empty());
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        let sectors: Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| i as u8).collect();
+        blk.write_sectors(1, &sectors).unwrap();
+        assert_eq!(&data.borrow()[SECTOR_SIZE..3 * SECTOR_SIZE], &sectors[..]);
+        let mut buf = vec![0u8; 2 * SECTOR_SIZE];
+        blk.read_sectors(1, &mut buf).unwrap();
+        assert_eq!(buf, sectors);
+    }
+
+    #[test]
+    fn many_requests_wrap_the_rings() {
+        let (mut transport, _device, _) = disk(BlkFeature::empty());
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        let mut buf = [0u8; SECTOR_SIZE];
+        for i in 0..(QUEUE_SIZE as u64 * 5) {
+            let sector = i % BAD_SECTOR;
+            blk.write_sectors(sector, &[i as u8; SECTOR_SIZE]).unwrap();
+            blk.read_sectors(sector, &mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    #[test]
+    fn bad_range_never_reaches_device() {
+        let (mut transport, device, _) = disk(BlkFeature::empty());
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        let mut buf = [0u8; SECTOR_SIZE];
+        assert_eq!(blk.read_sectors(CAPACITY, &mut buf), Err(Error::InvalidParam));
+        assert_eq!(blk.read_sectors(u64::MAX, &mut buf), Err(Error::InvalidParam));
+        assert_eq!(blk.read_sectors(0, &mut buf[..100]), Err(Error::InvalidParam));
+        assert_eq!(blk.write_sectors(0, &[]), Err(Error::InvalidParam));
+        assert_eq!(device.notifications(0), 0);
+    }
+
+    #[test]
+    fn io_error_from_device() {
+        let (mut transport, _device, _) = disk(BlkFeature::empty());
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        let mut buf = [0u8; SECTOR_SIZE];
+        assert_eq!(blk.read_sectors(BAD_SECTOR, &mut buf), Err(Error::IoError));
+        // 出错的请求也归还了描述符，之后的请求照常
+        blk.read_sectors(0, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn readonly_and_flush() {
+        let (mut transport, device, _) = disk(BlkFeature::RO);
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        assert!(blk.readonly());
+        assert_eq!(blk.write_sectors(0, &[0; SECTOR_SIZE]), Err(Error::Unsupported));
+        assert_eq!(blk.flush(), Err(Error::Unsupported));
+        assert_eq!(device.notifications(0), 0);
+
+        let (mut transport, _device, _) = disk(BlkFeature::FLUSH);
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        blk.flush().unwrap();
+    }
+
+    #[test]
+    fn get_id() {
+        let (mut transport, _device, _) = disk(BlkFeature::empty());
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        let mut id = [0u8; ID_BYTES];
+        let len = blk.get_id(&mut id).unwrap();
+        assert_eq!(&id[..len], DEVICE_ID);
+    }
+
+    #[test]
+    fn hung_device_times_out_and_is_reset() {
+        let (mut transport, device, _) = disk(BlkFeature::empty());
+        device.inject(Fault::Hang(0));
+        let mut blk = VirtIoBlk::new(&mut transport).unwrap();
+        let mut buf = [0u8; SECTOR_SIZE];
+        assert_eq!(blk.read_sectors(0, &mut buf), Err(Error::Timeout));
+        assert!(device.status().is_empty());
+    }
+
+    #[test]
+    fn rejected_features() {
+        let (mut transport, device, _) = disk(BlkFeature::FLUSH);
+        device.inject(Fault::RejectFeatures);
+        assert_eq!(VirtIoBlk::new(&mut transport).err(), Some(Error::FeaturesNotAccepted));
+        assert!(device.status().contains(DeviceStatus::FAILED));
+    }
+
+    #[test]
+    fn missing_queue() {
+        let (mut transport, device, _) = disk(BlkFeature::empty());
+        device.inject(Fault::NoQueue(0));
+        assert_eq!(VirtIoBlk::new(&mut transport).err(), Some(Error::InvalidParam));
+    }
+
+    #[test]
+    fn wrong_device_type() {
+        let (mut transport, device) = MockTransport::new(DeviceType::Network, 0);
+        assert_eq!(VirtIoBlk::new(&mut transport).err(), Some(Error::InvalidParam));
+        assert!(device.status().is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use volatile_register::{RO, WO};
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 16;
+const QUEUE_RECEIVE: usize = 0;
+const QUEUE_TRANSMIT: usize = 1;
+const QUEUE_CONTROL_RECEIVE: usize = 2;
+const QUEUE_CONTROL_TRANSMIT: usize = 3;
+
+/// The size of the buffer the device writes input into.
+const RX_BUFFER_SIZE: usize = 256;
+
+/// How long the device may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the device may take to announce port 0 after DEVICE_READY.
+const PORT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The virtio console device is a simple device for data input and output.
+///
+/// The driver only uses port 0. With VIRTIO_CONSOLE_F_MULTIPORT, it walks
+/// the control queue handshake until port 0 is open, and turns down all
+/// other ports.
+///
+/// Ref: 5.3 Console Device
+pub struct VirtIoConsole<'a, T: Transport> {
+    header: &'a mut T,
+    features: ConsoleFeature,
+    receiveq: VirtQueue,
+    transmitq: VirtQueue,
+    /// Only set up with VIRTIO_CONSOLE_F_MULTIPORT
+    control: Option<Control>,
+    /// The buffer given to the receive queue, or holding unread input
+    rx_buffer: Box<[u8; RX_BUFFER_SIZE]>,
+    /// Whether `rx_buffer` is owned by the device
+    rx_posted: bool,
+    /// Unread input is `rx_buffer[rx_cursor..rx_len]`
+    rx_cursor: usize,
+    rx_len: usize,
+}
+
+impl<'a, T: Transport> VirtIoConsole<'a, T> {
+    /// Create a new virtio-console driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Console {
+            return Err(Error::InvalidParam);
+        }
+        let features = header.begin_init(|features| {
+            let features = ConsoleFeature::from_bits_truncate(features);
+            let supported = ConsoleFeature::SIZE | ConsoleFeature::MULTIPORT | ConsoleFeature::EMERG_WRITE;
+            (features & supported).bits()
+        })?;
+        let features = ConsoleFeature::from_bits_truncate(features);
+
+        let receiveq = VirtQueue::new(header, QUEUE_RECEIVE, QUEUE_SIZE)?;
+        let transmitq = VirtQueue::new(header, QUEUE_TRANSMIT, QUEUE_SIZE)?;
+        let control = if features.contains(ConsoleFeature::MULTIPORT) {
+            let receiveq = VirtQueue::new(header, QUEUE_CONTROL_RECEIVE, QUEUE_SIZE)?;
+            let transmitq = VirtQueue::new(header, QUEUE_CONTROL_TRANSMIT, QUEUE_SIZE)?;
+            let mut buffers = Vec::new();
+            buffers.resize_with(QUEUE_SIZE as usize, || None);
+            Some(Control {
+                receiveq,
+                transmitq,
+                buffers,
+            })
+        } else {
+            None
+        };
+        let mut console = VirtIoConsole {
+            header,
+            features,
+            receiveq,
+            transmitq,
+            control,
+            rx_buffer: Box::new([0; RX_BUFFER_SIZE]),
+            rx_posted: false,
+            rx_cursor: 0,
+            rx_len: 0,
+        };
+        if let Some(control) = console.control.as_mut() {
+            while control.receiveq.available_desc() > 0 {
+                control.post(Box::new(ControlMsg::default()))?;
+            }
+        }
+        console.post_rx()?;
+        console.header.finish_init();
+        console.receiveq.notify(console.header);
+        if console.control.is_some() {
+            console.open_port()?;
+        }
+        Ok(console)
+    }
+
+    /// The size of the console in (columns, rows), if the device knows.
+    pub fn size(&self) -> Option<(u16, u16)> {
+        if !self.features.contains(ConsoleFeature::SIZE) {
+            return None;
+        }
+        let config = unsafe { self.header.config::<ConsoleConfig>() };
+        Some((config.cols.read(), config.rows.read()))
+    }
+
+    /// Write one byte through the config space, without any queue.
+    ///
+    /// Useful when the queues can't be trusted anymore, e.g. on panic.
+    pub fn emergency_write(&mut self, byte: u8) -> Result {
+        if !self.features.contains(ConsoleFeature::EMERG_WRITE) {
+            return Err(Error::Unsupported);
+        }
+        let config = unsafe { self.header.config::<ConsoleConfig>() };
+        unsafe { config.emerg_wr.write(byte as u32) };
+        Ok(())
+    }
+
+    /// Send `bytes` and wait until the device has taken them.
+    pub fn send(&mut self, bytes: &[u8]) -> Result {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        request(self.header, &mut self.transmitq, &[bytes], &[])
+    }
+
+    /// Whether there is input to read.
+    pub fn can_recv(&self) -> bool {
+        self.rx_cursor < self.rx_len || self.receiveq.can_pop()
+    }
+
+    /// Read the input that has arrived into `buf`, without waiting.
+    ///
+    /// Returns 0 if there is nothing to read.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.rx_cursor == self.rx_len && self.receiveq.can_pop() {
+            let (_token, len) = self.receiveq.pop_used()?;
+            self.rx_posted = false;
+            self.rx_cursor = 0;
+            self.rx_len = (len as usize).min(RX_BUFFER_SIZE);
+        }
+        let len = buf.len().min(self.rx_len - self.rx_cursor);
+        buf[..len].copy_from_slice(&self.rx_buffer[self.rx_cursor..self.rx_cursor + len]);
+        self.rx_cursor += len;
+        if self.rx_cursor == self.rx_len && !self.rx_posted {
+            self.post_rx()?;
+            self.receiveq.notify(self.header);
+        }
+        Ok(len)
+    }
+
+    /// Give `rx_buffer` to the receive queue. The caller notifies the device.
+    fn post_rx(&mut self) -> Result {
+        self.receiveq.add(&[], &[&mut self.rx_buffer[..]])?;
+        self.rx_posted = true;
+        self.rx_cursor = 0;
+        self.rx_len = 0;
+        Ok(())
+    }
+
+    /// Tell the device the driver is ready, and serve control messages
+    /// until port 0 is open.
+    ///
+    /// Ref: 5.3.6.2 Multiport Device Operation
+    fn open_port(&mut self) -> Result {
+        let mut control = self.control.take().ok_or(Error::Unsupported)?;
+        control.receiveq.notify(self.header);
+        control.send(self.header, ControlMsg::new(0, VIRTIO_CONSOLE_DEVICE_READY, 1))?;
+        let deadline = hal::now() + PORT_TIMEOUT;
+        let mut opened = false;
+        while !opened && hal::now() < deadline {
+            let (token, _len) = match control.receiveq.pop_used() {
+                Ok(used) => used,
+                Err(_) => continue,
+            };
+            let msg = control.buffers[token as usize].take().ok_or(Error::IoError)?;
+            match (msg.event, msg.id) {
+                (VIRTIO_CONSOLE_DEVICE_ADD, 0) => {
+                    control.send(self.header, ControlMsg::new(0, VIRTIO_CONSOLE_PORT_READY, 1))?;
+                    control.send(self.header, ControlMsg::new(0, VIRTIO_CONSOLE_PORT_OPEN, 1))?;
+                    opened = true;
+                }
+                (VIRTIO_CONSOLE_DEVICE_ADD, id) => {
+                    // 只驱动 0 号端口，其余端口告诉设备初始化失败
+                    control.send(self.header, ControlMsg::new(id, VIRTIO_CONSOLE_PORT_READY, 0))?;
+                }
+                _ => {}
+            }
+            control.post(msg)?;
+            control.receiveq.notify(self.header);
+        }
+        self.control = Some(control);
+        if opened {
+            Ok(())
+        } else {
+            Err(Error::NotReady)
+        }
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoConsole<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+/// The control queues of a multiport device.
+struct Control {
+    receiveq: VirtQueue,
+    transmitq: VirtQueue,
+    /// Buffers owned by the control receive queue, indexed by token
+    buffers: Vec<Option<Box<ControlMsg>>>,
+}
+
+impl Control {
+    /// Give a buffer to the control receive queue.
+    fn post(&mut self, mut msg: Box<ControlMsg>) -> Result {
+        let token = self.receiveq.add(&[], &[msg.as_buf_mut()])?;
+        self.buffers[token as usize] = Some(msg);
+        Ok(())
+    }
+
+    fn send<T: Transport>(&mut self, header: &mut T, msg: ControlMsg) -> Result {
+        request(header, &mut self.transmitq, &[msg.as_buf()], &[])
+    }
+}
+
+/// Submit one request on `queue` and wait until the device has served it.
+///
+/// If the device hangs, it is reset so it stops touching the buffers,
+/// and the driver can't be used anymore.
+fn request<T: Transport>(header: &mut T, queue: &mut VirtQueue, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result {
+    let token = queue.add(inputs, outputs)?;
+    queue.notify(header);
+    let waiting = &*queue;
+    if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || waiting.can_pop()) {
+        header.reset();
+        return Err(e);
+    }
+    let (used, _len) = queue.pop_used()?;
+    // 一次只提交一个请求，设备返回的一定是它
+    assert_eq!(used, token);
+    Ok(())
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ConsoleConfig {
+    cols: RO<u16>,
+    rows: RO<u16>,
+    max_nr_ports: RO<u32>,
+    emerg_wr: WO<u32>,
+}
+
+/// A message on the control queues, `struct virtio_console_control`.
+#[repr(C)]
+#[derive(Default)]
+struct ControlMsg {
+    /// Port number
+    id: u32,
+    /// The kind of control event
+    event: u16,
+    /// Extra information for the event
+    value: u16,
+}
+
+impl ControlMsg {
+    fn new(id: u32, event: u16, value: u16) -> Self {
+        ControlMsg { id, event, value }
+    }
+}
+
+unsafe impl AsBuf for ControlMsg {}
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
+bitflags! {
+    struct ConsoleFeature: u64 {
+        /// Configuration cols and rows are valid.
+        const SIZE          = 1 << 0;
+        /// Device has support for multiple ports.
+        const MULTIPORT     = 1 << 1;
+        /// Device has support for emergency write.
+        const EMERG_WRITE   = 1 << 2;
+    }
+}
@@ -0,0 +1,336 @@
+use core::time::Duration;
+
+use alloc::vec::Vec;
+use volatile_register::RO;
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 4;
+
+/// The most data queues the driver sets up.
+const MAX_DATA_QUEUES: u32 = 4;
+
+/// How long the device may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The virtio crypto device, driven for symmetric ciphers only.
+///
+/// Sessions are created and destroyed on the control queue, which comes
+/// after the data queues. Cipher requests go to the data queues in turn.
+/// Every request waits for its result, so each queue has at most one
+/// request in flight.
+///
+/// Ref: 5.9 Crypto Device
+pub struct VirtIoCrypto<'a, T: Transport> {
+    header: &'a mut T,
+    control_queue: VirtQueue,
+    data_queues: Vec<VirtQueue>,
+    /// The data queue of the next cipher request
+    next_queue: usize,
+    cipher_algos: u64,
+    max_key_len: u32,
+}
+
+impl<'a, T: Transport> VirtIoCrypto<'a, T> {
+    /// Create a new virtio-crypto driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Crypto {
+            return Err(Error::InvalidParam);
+        }
+        // 不协商 REVISION_1，请求都是定长的
+        header.begin_init(|_| 0)?;
+        let config = unsafe { header.config::<CryptoConfig>() };
+        if config.status.read() & VIRTIO_CRYPTO_S_HW_READY == 0 {
+            return Err(Error::NotReady);
+        }
+        let max_dataqueues = config.max_dataqueues.read();
+        let cipher_algos = if config.crypto_services.read() & (1 << VIRTIO_CRYPTO_SERVICE_CIPHER) != 0 {
+            config.cipher_algo_l.read() as u64 | (config.cipher_algo_h.read() as u64) << 32
+        } else {
+            0
+        };
+        let max_key_len = config.max_cipher_key_len.read();
+        // 控制队列的编号在所有数据队列之后
+        let control_queue = VirtQueue::new(header, max_dataqueues as usize, QUEUE_SIZE)?;
+        let mut data_queues = Vec::new();
+        for i in 0..max_dataqueues.min(MAX_DATA_QUEUES) {
+            data_queues.push(VirtQueue::new(header, i as usize, QUEUE_SIZE)?);
+        }
+        if data_queues.is_empty() {
+            return Err(Error::Unsupported);
+        }
+        header.finish_init();
+        Ok(VirtIoCrypto {
+            header,
+            control_queue,
+            data_queues,
+            next_queue: 0,
+            cipher_algos,
+            max_key_len,
+        })
+    }
+
+    /// The number of data queues in use.
+    pub fn num_data_queues(&self) -> usize {
+        self.data_queues.len()
+    }
+
+    /// Whether the device has AES in CBC mode.
+    pub fn supports_aes_cbc(&self) -> bool {
+        self.cipher_algos & (1 << VIRTIO_CRYPTO_CIPHER_AES_CBC) != 0
+    }
+
+    /// Create an AES-CBC session with `key` of 16, 24 or 32 bytes, which
+    /// encrypts or decrypts as `op` says.
+    pub fn create_session(&mut self, key: &[u8], op: CipherOp) -> Result<Session> {
+        if !self.supports_aes_cbc() {
+            return Err(Error::Unsupported);
+        }
+        if key.len() > self.max_key_len as usize {
+            return Err(Error::InvalidParam);
+        }
+        let req = CreateSessionReq {
+            header: CtrlHeader {
+                opcode: VIRTIO_CRYPTO_CIPHER_CREATE_SESSION,
+                algo: VIRTIO_CRYPTO_CIPHER_AES_CBC,
+                ..CtrlHeader::default()
+            },
+            algo: VIRTIO_CRYPTO_CIPHER_AES_CBC,
+            keylen: key.len() as u32,
+            op: op as u32,
+            op_type: VIRTIO_CRYPTO_SYM_OP_CIPHER,
+            ..CreateSessionReq::default()
+        };
+        let mut input = SessionInput::default();
+        let token = self.control_queue.add(&[req.as_buf(), key], &[input.as_buf_mut()])?;
+        wait_request(self.header, &mut self.control_queue, token)?;
+        check_status(input.status as u8)?;
+        Ok(Session { id: input.session_id, op })
+    }
+
+    /// Destroy `session`.
+    pub fn destroy_session(&mut self, session: Session) -> Result {
+        let req = DestroySessionReq {
+            header: CtrlHeader {
+                opcode: VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION,
+                algo: VIRTIO_CRYPTO_CIPHER_AES_CBC,
+                ..CtrlHeader::default()
+            },
+            session_id: session.id,
+            ..DestroySessionReq::default()
+        };
+        let mut status = [0u8];
+        let token = self.control_queue.add(&[req.as_buf()], &[&mut status])?;
+        wait_request(self.header, &mut self.control_queue, token)?;
+        check_status(status[0])
+    }
+
+    /// Run `src` through the cipher of `session` with `iv` into `dst` of the
+    /// same length, on the next data queue.
+    ///
+    /// Return the index of the data queue used.
+    pub fn cipher(&mut self, session: &Session, iv: &[u8], src: &[u8], dst: &mut [u8]) -> Result<usize> {
+        if src.len() != dst.len() || src.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let opcode = match session.op {
+            CipherOp::Encrypt => VIRTIO_CRYPTO_CIPHER_ENCRYPT,
+            CipherOp::Decrypt => VIRTIO_CRYPTO_CIPHER_DECRYPT,
+        };
+        let req = CipherDataReq {
+            header: OpHeader {
+                opcode,
+                algo: VIRTIO_CRYPTO_CIPHER_AES_CBC,
+                session_id: session.id,
+                ..OpHeader::default()
+            },
+            iv_len: iv.len() as u32,
+            src_data_len: src.len() as u32,
+            dst_data_len: dst.len() as u32,
+            op_type: VIRTIO_CRYPTO_SYM_OP_CIPHER,
+            ..CipherDataReq::default()
+        };
+        let index = self.next_queue;
+        self.next_queue = (index + 1) % self.data_queues.len();
+        let queue = &mut self.data_queues[index];
+        // 设备先写结果，最后写一个字节的状态
+        let mut status = [0u8];
+        let token = queue.add(&[req.as_buf(), iv, src], &[dst, &mut status])?;
+        wait_request(self.header, queue, token)?;
+        check_status(status[0])?;
+        Ok(index)
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoCrypto<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+/// Notify the device of the request `token` on `queue`, and wait until it
+/// is served.
+///
+/// If the device hangs, it is reset so it stops touching the buffers, and
+/// the driver can't be used anymore.
+fn wait_request<T: Transport>(header: &mut T, queue: &mut VirtQueue, token: u16) -> Result {
+    queue.notify(header);
+    let queue_ref = &*queue;
+    if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue_ref.can_pop()) {
+        header.reset();
+        return Err(e);
+    }
+    let (used, _len) = queue.pop_used()?;
+    // 每个队列一次只提交一个请求，设备返回的一定是它
+    assert_eq!(used, token);
+    Ok(())
+}
+
+fn check_status(status: u8) -> Result {
+    match status {
+        VIRTIO_CRYPTO_OK => Ok(()),
+        VIRTIO_CRYPTO_NOTSUPP => Err(Error::Unsupported),
+        VIRTIO_CRYPTO_BADMSG | VIRTIO_CRYPTO_INVSESS | VIRTIO_CRYPTO_KEY_REJECTED => Err(Error::InvalidParam),
+        VIRTIO_CRYPTO_NOSPC => Err(Error::BufferTooSmall),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// What a cipher session does to the data.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CipherOp {
+    Encrypt = 1,
+    Decrypt = 2,
+}
+
+/// A cipher session on the device.
+#[derive(Debug)]
+pub struct Session {
+    id: u64,
+    op: CipherOp,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct CryptoConfig {
+    status: RO<u32>,
+    max_dataqueues: RO<u32>,
+    crypto_services: RO<u32>,
+    cipher_algo_l: RO<u32>,
+    cipher_algo_h: RO<u32>,
+    hash_algo: RO<u32>,
+    mac_algo_l: RO<u32>,
+    mac_algo_h: RO<u32>,
+    aead_algo: RO<u32>,
+    max_cipher_key_len: RO<u32>,
+    max_auth_key_len: RO<u32>,
+    akcipher_algo: RO<u32>,
+    max_size: RO<u64>,
+}
+
+/// `struct virtio_crypto_ctrl_header`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CtrlHeader {
+    opcode: u32,
+    algo: u32,
+    flag: u32,
+    queue_id: u32,
+}
+
+/// `struct virtio_crypto_op_ctrl_req` for a cipher session, followed by
+/// the key.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CreateSessionReq {
+    header: CtrlHeader,
+    // struct virtio_crypto_cipher_session_para
+    algo: u32,
+    keylen: u32,
+    op: u32,
+    padding: u32,
+    _union_padding: [u8; 32],
+    op_type: u32,
+    padding2: u32,
+}
+
+unsafe impl AsBuf for CreateSessionReq {}
+
+/// `struct virtio_crypto_session_input`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SessionInput {
+    session_id: u64,
+    status: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for SessionInput {}
+
+/// `struct virtio_crypto_op_ctrl_req` to destroy a session.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct DestroySessionReq {
+    header: CtrlHeader,
+    session_id: u64,
+    padding: [u64; 6],
+}
+
+unsafe impl AsBuf for DestroySessionReq {}
+
+/// `struct virtio_crypto_op_header`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct OpHeader {
+    opcode: u32,
+    algo: u32,
+    session_id: u64,
+    flag: u32,
+    padding: u32,
+}
+
+/// `struct virtio_crypto_op_data_req` for a cipher, followed by the iv and
+/// the source data.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CipherDataReq {
+    header: OpHeader,
+    // struct virtio_crypto_cipher_para
+    iv_len: u32,
+    src_data_len: u32,
+    dst_data_len: u32,
+    padding: u32,
+    _union_padding: [u8; 24],
+    op_type: u32,
+    padding2: u32,
+}
+
+unsafe impl AsBuf for CipherDataReq {}
+
+const VIRTIO_CRYPTO_S_HW_READY: u32 = 1 << 0;
+
+const VIRTIO_CRYPTO_SERVICE_CIPHER: u32 = 0;
+
+const VIRTIO_CRYPTO_CIPHER_AES_CBC: u32 = 3;
+
+const VIRTIO_CRYPTO_SYM_OP_CIPHER: u32 = 1;
+
+// opcodes of the cipher service
+const VIRTIO_CRYPTO_CIPHER_ENCRYPT: u32 = 0x00;
+const VIRTIO_CRYPTO_CIPHER_DECRYPT: u32 = 0x01;
+const VIRTIO_CRYPTO_CIPHER_CREATE_SESSION: u32 = 0x02;
+const VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION: u32 = 0x03;
+
+// status of requests
+const VIRTIO_CRYPTO_OK: u8 = 0;
+const VIRTIO_CRYPTO_BADMSG: u8 = 2;
+const VIRTIO_CRYPTO_NOTSUPP: u8 = 3;
+const VIRTIO_CRYPTO_INVSESS: u8 = 4;
+const VIRTIO_CRYPTO_NOSPC: u8 = 5;
+const VIRTIO_CRYPTO_KEY_REJECTED: u8 = 6;
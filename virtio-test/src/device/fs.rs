@@ -0,0 +1,507 @@
+use core::mem::size_of;
+use core::time::Duration;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use volatile_register::RO;
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 8;
+const QUEUE_HIPRIO: usize = 0;
+/// The first request queue, the only one the driver uses
+const QUEUE_REQUEST: usize = 1;
+
+/// How long the file server may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The FUSE version the driver speaks, 7.31 is the first with virtio-fs.
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+/// The most bytes one `read` or `readdirplus` asks for.
+const MAX_READ: usize = 8192;
+
+/// The node of the root directory.
+pub const ROOT_ID: u64 = 1;
+
+/// The `kind` of a directory entry which is a directory.
+pub const DT_DIR: u32 = 4;
+
+/// Open flags, as in linux.
+pub const O_RDONLY: u32 = 0o0;
+
+/// The virtio file system device is a FUSE server in the host.
+///
+/// Every request is a FUSE message: `fuse_in_header` and the arguments for
+/// the device to read, and buffers for `fuse_out_header` and the reply.
+/// Requests go to the first request queue, one at a time. `FORGET` has no
+/// reply and goes to the hiprio queue.
+///
+/// Ref: 5.11 File System Device
+pub struct VirtIoFs<'a, T: Transport> {
+    header: &'a mut T,
+    hiprio_queue: VirtQueue,
+    request_queue: VirtQueue,
+    /// The id of the next request
+    unique: u64,
+}
+
+impl<'a, T: Transport> VirtIoFs<'a, T> {
+    /// Create a new virtio-fs driver, and start a FUSE session.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::FileSystem {
+            return Err(Error::InvalidParam);
+        }
+        // 不支持 DAX 窗口和通知队列
+        header.begin_init(|_| 0)?;
+        let config = unsafe { header.config::<FsConfig>() };
+        if config.num_request_queues.read() == 0 {
+            return Err(Error::Unsupported);
+        }
+        let hiprio_queue = VirtQueue::new(header, QUEUE_HIPRIO, QUEUE_SIZE)?;
+        let request_queue = VirtQueue::new(header, QUEUE_REQUEST, QUEUE_SIZE)?;
+        header.finish_init();
+        let mut fs = VirtIoFs {
+            header,
+            hiprio_queue,
+            request_queue,
+            unique: 1,
+        };
+        fs.init()?;
+        Ok(fs)
+    }
+
+    /// The tag the host exports the file system under.
+    pub fn tag(&self) -> String {
+        let config = unsafe { self.header.config::<FsConfig>() };
+        // 标签不足 36 字节时以 NUL 结尾
+        let tag: Vec<u8> = config.tag.iter().map(|b| b.read()).take_while(|&b| b != 0).collect();
+        String::from_utf8_lossy(&tag).into()
+    }
+
+    /// Look up `name` in the directory `parent`.
+    ///
+    /// The server counts the lookup, send `forget` when done with the node.
+    pub fn lookup(&mut self, parent: u64, name: &str) -> Result<Entry> {
+        // 名字要以 NUL 结尾
+        let mut entry = EntryOut::default();
+        self.request(FUSE_LOOKUP, parent, &[name.as_bytes(), &[0]], entry.as_buf_mut())?;
+        Ok(entry.into())
+    }
+
+    /// Tell the server `nlookup` lookups of `nodeid` are done with.
+    pub fn forget(&mut self, nodeid: u64, nlookup: u64) -> Result {
+        let forget = ForgetIn { nlookup };
+        let hdr = self.in_header(FUSE_FORGET, nodeid, size_of::<ForgetIn>());
+        let token = self.hiprio_queue.add(&[hdr.as_buf(), forget.as_buf()], &[])?;
+        self.hiprio_queue.notify(self.header);
+        let queue = &self.hiprio_queue;
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.hiprio_queue.pop_used()?;
+        assert_eq!(used, token);
+        Ok(())
+    }
+
+    /// Get the attributes of `nodeid`.
+    pub fn getattr(&mut self, nodeid: u64) -> Result<Attr> {
+        let getattr = GetattrIn::default();
+        let mut out = AttrOut::default();
+        self.request(FUSE_GETATTR, nodeid, &[getattr.as_buf()], out.as_buf_mut())?;
+        Ok(out.attr)
+    }
+
+    /// Open the file `nodeid` with linux open `flags`, return the handle.
+    pub fn open(&mut self, nodeid: u64, flags: u32) -> Result<u64> {
+        self.do_open(FUSE_OPEN, nodeid, flags)
+    }
+
+    /// Open the directory `nodeid`, return the handle.
+    pub fn opendir(&mut self, nodeid: u64) -> Result<u64> {
+        self.do_open(FUSE_OPENDIR, nodeid, O_RDONLY)
+    }
+
+    /// Read from the open file `nodeid` at `offset` into `buf`, return the
+    /// number of bytes read. 0 means end of file.
+    pub fn read(&mut self, nodeid: u64, fh: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(MAX_READ);
+        let read = ReadIn {
+            fh,
+            offset,
+            size: len as u32,
+            ..ReadIn::default()
+        };
+        self.request(FUSE_READ, nodeid, &[read.as_buf()], &mut buf[..len])
+    }
+
+    /// Read the entries of the open directory `nodeid` starting from
+    /// `offset`, with their attributes. An empty result means the end of
+    /// the directory.
+    ///
+    /// The server counts a lookup for every entry with a node other than 0.
+    pub fn readdirplus(&mut self, nodeid: u64, fh: u64, offset: u64) -> Result<Vec<DirEntry>> {
+        let read = ReadIn {
+            fh,
+            offset,
+            size: MAX_READ as u32,
+            ..ReadIn::default()
+        };
+        let mut buf = vec![0u8; MAX_READ];
+        let len = self.request(FUSE_READDIRPLUS, nodeid, &[read.as_buf()], &mut buf)?;
+        let mut entries = Vec::new();
+        let mut rest = &buf[..len];
+        // 每一项是 fuse_direntplus 加上名字，按 8 字节对齐
+        while rest.len() >= size_of::<EntryOut>() + size_of::<Dirent>() {
+            let mut entry = EntryOut::default();
+            let mut dirent = Dirent::default();
+            let (head, tail) = rest.split_at(size_of::<EntryOut>());
+            entry.as_buf_mut().copy_from_slice(head);
+            let (head, tail) = tail.split_at(size_of::<Dirent>());
+            dirent.as_buf_mut().copy_from_slice(head);
+            let namelen = dirent.namelen as usize;
+            if tail.len() < namelen {
+                return Err(Error::IoError);
+            }
+            entries.push(DirEntry {
+                entry: entry.into(),
+                offset: dirent.off,
+                kind: dirent.kind,
+                name: String::from_utf8_lossy(&tail[..namelen]).into(),
+            });
+            let size = size_of::<EntryOut>() + size_of::<Dirent>() + namelen;
+            rest = &rest[((size + 7) & !7).min(rest.len())..];
+        }
+        Ok(entries)
+    }
+
+    /// Close the handle `fh` of the file `nodeid`.
+    pub fn release(&mut self, nodeid: u64, fh: u64) -> Result {
+        self.do_release(FUSE_RELEASE, nodeid, fh)
+    }
+
+    /// Close the handle `fh` of the directory `nodeid`.
+    pub fn releasedir(&mut self, nodeid: u64, fh: u64) -> Result {
+        self.do_release(FUSE_RELEASEDIR, nodeid, fh)
+    }
+
+    /// Agree on the protocol version with the server.
+    fn init(&mut self) -> Result {
+        let init = InitIn {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 0,
+            flags: FUSE_DO_READDIRPLUS,
+        };
+        let mut out = InitOut::default();
+        // 旧的服务器回复得短一些，只要有版本号就行
+        let len = self.request(FUSE_INIT, 0, &[init.as_buf()], out.as_buf_mut())?;
+        if len < 8 || out.major != FUSE_KERNEL_VERSION || out.minor < FUSE_KERNEL_MINOR_VERSION {
+            return Err(Error::Unsupported);
+        }
+        Ok(())
+    }
+
+    fn do_open(&mut self, opcode: u32, nodeid: u64, flags: u32) -> Result<u64> {
+        let open = OpenIn { flags, open_flags: 0 };
+        let mut out = OpenOut::default();
+        self.request(opcode, nodeid, &[open.as_buf()], out.as_buf_mut())?;
+        Ok(out.fh)
+    }
+
+    fn do_release(&mut self, opcode: u32, nodeid: u64, fh: u64) -> Result {
+        let release = ReleaseIn {
+            fh,
+            ..ReleaseIn::default()
+        };
+        self.request(opcode, nodeid, &[release.as_buf()], &mut [])?;
+        Ok(())
+    }
+
+    fn in_header(&mut self, opcode: u32, nodeid: u64, args_len: usize) -> InHeader {
+        let unique = self.unique;
+        self.unique += 1;
+        InHeader {
+            len: (size_of::<InHeader>() + args_len) as u32,
+            opcode,
+            unique,
+            nodeid,
+            ..InHeader::default()
+        }
+    }
+
+    /// Send a request on the request queue and wait for the reply, return
+    /// the length of the reply in `out`.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    fn request(&mut self, opcode: u32, nodeid: u64, args: &[&[u8]], out: &mut [u8]) -> Result<usize> {
+        let hdr = self.in_header(opcode, nodeid, args.iter().map(|arg| arg.len()).sum());
+        let mut inputs = vec![hdr.as_buf()];
+        inputs.extend(args.iter().filter(|arg| !arg.is_empty()));
+        let mut out_hdr = OutHeader::default();
+        let token = if out.is_empty() {
+            self.request_queue.add(&inputs, &[out_hdr.as_buf_mut()])?
+        } else {
+            self.request_queue.add(&inputs, &[out_hdr.as_buf_mut(), out])?
+        };
+        self.request_queue.notify(self.header);
+        let queue = &self.request_queue;
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.request_queue.pop_used()?;
+        // 一次只提交一个请求，设备返回的一定是它
+        assert_eq!(used, token);
+        if out_hdr.unique != hdr.unique {
+            return Err(Error::IoError);
+        }
+        if out_hdr.error < 0 {
+            return Err(Error::Errno(-out_hdr.error as u32));
+        }
+        Ok((out_hdr.len as usize).saturating_sub(size_of::<OutHeader>()).min(out.len()))
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoFs<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+/// A node found by `lookup` or `readdirplus`.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// 0 for an entry the server did not look up
+    pub nodeid: u64,
+    pub attr: Attr,
+}
+
+impl From<EntryOut> for Entry {
+    fn from(out: EntryOut) -> Self {
+        Entry {
+            nodeid: out.nodeid,
+            attr: out.attr,
+        }
+    }
+}
+
+/// An entry returned by `readdirplus`.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub entry: Entry,
+    /// Pass to `readdirplus` to continue after this entry
+    pub offset: u64,
+    /// `DT_DIR`, `DT_REG` and so on
+    pub kind: u32,
+    pub name: String,
+}
+
+/// The attributes of a node, `struct fuse_attr`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    pub atimensec: u32,
+    pub mtimensec: u32,
+    pub ctimensec: u32,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub flags: u32,
+}
+
+#[repr(C)]
+struct FsConfig {
+    /// NUL-padded
+    tag: [RO<u8>; 36],
+    num_request_queues: RO<u32>,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct InHeader {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for InHeader {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct OutHeader {
+    len: u32,
+    /// Negative linux errno
+    error: i32,
+    unique: u64,
+}
+
+unsafe impl AsBuf for OutHeader {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct InitIn {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+unsafe impl AsBuf for InitIn {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct InitOut {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+    time_gran: u32,
+    max_pages: u16,
+    map_alignment: u16,
+    flags2: u32,
+    unused: [u32; 7],
+}
+
+unsafe impl AsBuf for InitOut {}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct EntryOut {
+    nodeid: u64,
+    generation: u64,
+    entry_valid: u64,
+    attr_valid: u64,
+    entry_valid_nsec: u32,
+    attr_valid_nsec: u32,
+    attr: Attr,
+}
+
+unsafe impl AsBuf for EntryOut {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ForgetIn {
+    nlookup: u64,
+}
+
+unsafe impl AsBuf for ForgetIn {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct GetattrIn {
+    getattr_flags: u32,
+    dummy: u32,
+    fh: u64,
+}
+
+unsafe impl AsBuf for GetattrIn {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct AttrOut {
+    attr_valid: u64,
+    attr_valid_nsec: u32,
+    dummy: u32,
+    attr: Attr,
+}
+
+unsafe impl AsBuf for AttrOut {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct OpenIn {
+    flags: u32,
+    open_flags: u32,
+}
+
+unsafe impl AsBuf for OpenIn {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct OpenOut {
+    fh: u64,
+    open_flags: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for OpenOut {}
+
+/// Also the argument of `FUSE_READDIRPLUS`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ReadIn {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    read_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for ReadIn {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ReleaseIn {
+    fh: u64,
+    flags: u32,
+    release_flags: u32,
+    lock_owner: u64,
+}
+
+unsafe impl AsBuf for ReleaseIn {}
+
+/// `struct fuse_dirent` without the name
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Dirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    kind: u32,
+}
+
+unsafe impl AsBuf for Dirent {}
+
+// opcodes of FUSE
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_READDIRPLUS: u32 = 44;
+
+/// `FUSE_INIT` flag: the driver asks for `READDIRPLUS`
+const FUSE_DO_READDIRPLUS: u32 = 1 << 13;
@@ -0,0 +1,457 @@
+use core::time::Duration;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use volatile_register::{RO, WO};
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 4;
+const QUEUE_TRANSMIT: usize = 0;
+const QUEUE_CURSOR: usize = 1;
+
+/// How long the device may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The cursor image is always 64x64 pixels.
+pub const CURSOR_SIZE: u32 = 64;
+
+/// Bytes per pixel in both the framebuffer and the cursor, see `FORMAT`.
+pub const BYTES_PER_PIXEL: usize = 4;
+
+/// Pixels are stored as bytes B, G, R, A.
+const FORMAT: u32 = VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM;
+
+const RESOURCE_ID_FB: u32 = 0xbabe;
+const RESOURCE_ID_CURSOR: u32 = 0xdade;
+const SCANOUT_ID: u32 = 0;
+
+/// The virtio GPU device, driven in 2D mode.
+///
+/// The driver shows one framebuffer on scanout 0. Drawing happens in guest
+/// memory, then `flush` copies it to the host resource and updates the
+/// display. The cursor is a separate 64x64 image on the cursor queue.
+///
+/// Ref: 5.7 GPU Device
+pub struct VirtIoGpu<'a, T: Transport> {
+    header: &'a mut T,
+    /// The size of scanout 0
+    rect: Rect,
+    /// Backing memory of the framebuffer resource, empty until set up
+    frame_buffer: Vec<u8>,
+    /// Backing memory of the cursor resource, empty until set up
+    cursor_buffer: Vec<u8>,
+    control_queue: VirtQueue,
+    cursor_queue: VirtQueue,
+}
+
+impl<'a, T: Transport> VirtIoGpu<'a, T> {
+    /// Create a new virtio-gpu driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Gpu {
+            return Err(Error::InvalidParam);
+        }
+        // 只用 2D 命令，VIRGL 和 EDID 都不需要
+        header.begin_init(|_| 0)?;
+        let control_queue = VirtQueue::new(header, QUEUE_TRANSMIT, QUEUE_SIZE)?;
+        let cursor_queue = VirtQueue::new(header, QUEUE_CURSOR, QUEUE_SIZE)?;
+        header.finish_init();
+
+        let mut gpu = VirtIoGpu {
+            header,
+            rect: Rect::default(),
+            frame_buffer: Vec::new(),
+            cursor_buffer: Vec::new(),
+            control_queue,
+            cursor_queue,
+        };
+        let display_info = gpu.get_display_info()?;
+        let display = &display_info.pmodes[SCANOUT_ID as usize];
+        if display.enabled == 0 || display.rect.width == 0 || display.rect.height == 0 {
+            return Err(Error::NotReady);
+        }
+        gpu.rect = display.rect;
+        Ok(gpu)
+    }
+
+    /// The number of scanouts the device supports.
+    pub fn num_scanouts(&self) -> u32 {
+        let config = unsafe { self.header.config::<GpuConfig>() };
+        config.num_scanouts.read()
+    }
+
+    /// The (width, height) of the display in pixels.
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.rect.width, self.rect.height)
+    }
+
+    /// Create the framebuffer and show it on the display.
+    ///
+    /// Rows are `width * BYTES_PER_PIXEL` bytes long. Changes show up after
+    /// `flush`.
+    pub fn setup_framebuffer(&mut self) -> Result<&mut [u8]> {
+        if self.frame_buffer.is_empty() {
+            let (width, height) = self.resolution();
+            self.resource_create_2d(RESOURCE_ID_FB, width, height)?;
+            self.frame_buffer = vec![0u8; width as usize * height as usize * BYTES_PER_PIXEL];
+            let (addr, len) = (self.frame_buffer.as_ptr() as u64, self.frame_buffer.len() as u32);
+            self.resource_attach_backing(RESOURCE_ID_FB, addr, len)?;
+            self.set_scanout(self.rect, SCANOUT_ID, RESOURCE_ID_FB)?;
+        }
+        Ok(&mut self.frame_buffer)
+    }
+
+    /// Show the current content of the framebuffer on the display.
+    pub fn flush(&mut self) -> Result {
+        if self.frame_buffer.is_empty() {
+            return Err(Error::NotReady);
+        }
+        self.transfer_to_host_2d(self.rect, 0, RESOURCE_ID_FB)?;
+        self.resource_flush(self.rect, RESOURCE_ID_FB)
+    }
+
+    /// Set the cursor image and show it at (`x`, `y`).
+    ///
+    /// `image` holds 64x64 pixels, (`hot_x`, `hot_y`) is the pixel in it
+    /// that points at (`x`, `y`).
+    pub fn setup_cursor(&mut self, image: &[u8], x: u32, y: u32, hot_x: u32, hot_y: u32) -> Result {
+        let size = (CURSOR_SIZE * CURSOR_SIZE) as usize * BYTES_PER_PIXEL;
+        if image.len() != size {
+            return Err(Error::InvalidParam);
+        }
+        if self.cursor_buffer.is_empty() {
+            self.resource_create_2d(RESOURCE_ID_CURSOR, CURSOR_SIZE, CURSOR_SIZE)?;
+            self.cursor_buffer = vec![0u8; size];
+            let (addr, len) = (self.cursor_buffer.as_ptr() as u64, self.cursor_buffer.len() as u32);
+            self.resource_attach_backing(RESOURCE_ID_CURSOR, addr, len)?;
+        }
+        self.cursor_buffer.copy_from_slice(image);
+        let rect = Rect::new(0, 0, CURSOR_SIZE, CURSOR_SIZE);
+        self.transfer_to_host_2d(rect, 0, RESOURCE_ID_CURSOR)?;
+        self.cursor_request(UpdateCursor::new(VIRTIO_GPU_CMD_UPDATE_CURSOR, x, y, RESOURCE_ID_CURSOR, hot_x, hot_y))
+    }
+
+    /// Move the cursor to (`x`, `y`).
+    pub fn move_cursor(&mut self, x: u32, y: u32) -> Result {
+        self.cursor_request(UpdateCursor::new(VIRTIO_GPU_CMD_MOVE_CURSOR, x, y, 0, 0, 0))
+    }
+
+    fn get_display_info(&mut self) -> Result<RespDisplayInfo> {
+        let req = CtrlHeader::with_type(VIRTIO_GPU_CMD_GET_DISPLAY_INFO);
+        let mut resp = RespDisplayInfo::default();
+        self.request(&[req.as_buf()], resp.as_buf_mut())?;
+        resp.header.check(VIRTIO_GPU_RESP_OK_DISPLAY_INFO)?;
+        Ok(resp)
+    }
+
+    fn resource_create_2d(&mut self, resource_id: u32, width: u32, height: u32) -> Result {
+        let req = ResourceCreate2D {
+            header: CtrlHeader::with_type(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+            resource_id,
+            format: FORMAT,
+            width,
+            height,
+        };
+        self.request_nodata(&[req.as_buf()])
+    }
+
+    fn resource_attach_backing(&mut self, resource_id: u32, addr: u64, length: u32) -> Result {
+        // 内核里物理地址和虚拟地址相同，整块缓冲区只要一个内存项
+        let req = ResourceAttachBacking {
+            header: CtrlHeader::with_type(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+            resource_id,
+            nr_entries: 1,
+        };
+        let entry = MemEntry {
+            addr,
+            length,
+            padding: 0,
+        };
+        self.request_nodata(&[req.as_buf(), entry.as_buf()])
+    }
+
+    fn set_scanout(&mut self, rect: Rect, scanout_id: u32, resource_id: u32) -> Result {
+        let req = SetScanout {
+            header: CtrlHeader::with_type(VIRTIO_GPU_CMD_SET_SCANOUT),
+            rect,
+            scanout_id,
+            resource_id,
+        };
+        self.request_nodata(&[req.as_buf()])
+    }
+
+    fn transfer_to_host_2d(&mut self, rect: Rect, offset: u64, resource_id: u32) -> Result {
+        let req = TransferToHost2D {
+            header: CtrlHeader::with_type(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+            rect,
+            offset,
+            resource_id,
+            padding: 0,
+        };
+        self.request_nodata(&[req.as_buf()])
+    }
+
+    fn resource_flush(&mut self, rect: Rect, resource_id: u32) -> Result {
+        let req = ResourceFlush {
+            header: CtrlHeader::with_type(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+            rect,
+            resource_id,
+            padding: 0,
+        };
+        self.request_nodata(&[req.as_buf()])
+    }
+
+    /// Send a control command whose response carries no data.
+    fn request_nodata(&mut self, inputs: &[&[u8]]) -> Result {
+        let mut resp = CtrlHeader::default();
+        self.request(inputs, resp.as_buf_mut())?;
+        resp.check(VIRTIO_GPU_RESP_OK_NODATA)
+    }
+
+    /// Send a control command and wait until the device has served it.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    fn request(&mut self, inputs: &[&[u8]], resp: &mut [u8]) -> Result {
+        let token = self.control_queue.add(inputs, &[resp])?;
+        self.control_queue.notify(self.header);
+        let queue = &self.control_queue;
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.control_queue.pop_used()?;
+        // 一次只提交一个请求，设备返回的一定是它
+        assert_eq!(used, token);
+        Ok(())
+    }
+
+    /// Send a command on the cursor queue, which has no response.
+    fn cursor_request(&mut self, req: UpdateCursor) -> Result {
+        let token = self.cursor_queue.add(&[req.as_buf()], &[])?;
+        self.cursor_queue.notify(self.header);
+        let queue = &self.cursor_queue;
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.cursor_queue.pop_used()?;
+        assert_eq!(used, token);
+        Ok(())
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoGpu<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct GpuConfig {
+    /// Signals pending events to the driver
+    events_read: RO<u32>,
+    /// Clears pending events in the device
+    events_clear: WO<u32>,
+    /// Specifies the maximum number of scanouts supported by the device
+    num_scanouts: RO<u32>,
+    reserved: RO<u32>,
+}
+
+/// The header of every request and response on the control queue.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CtrlHeader {
+    hdr_type: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHeader {
+    fn with_type(hdr_type: u32) -> Self {
+        CtrlHeader {
+            hdr_type,
+            ..CtrlHeader::default()
+        }
+    }
+
+    /// Check that the response is of type `expected`.
+    fn check(&self, expected: u32) -> Result {
+        match self.hdr_type {
+            t if t == expected => Ok(()),
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY => Err(Error::DmaError),
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID
+            | VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+            | VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER => Err(Error::InvalidParam),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+unsafe impl AsBuf for CtrlHeader {}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect { x, y, width, height }
+    }
+}
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct RespDisplayInfo {
+    header: CtrlHeader,
+    pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+unsafe impl AsBuf for RespDisplayInfo {}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct ResourceCreate2D {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+unsafe impl AsBuf for ResourceCreate2D {}
+
+/// Followed by `nr_entries` of `MemEntry`.
+#[repr(C)]
+struct ResourceAttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+unsafe impl AsBuf for ResourceAttachBacking {}
+
+#[repr(C)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for MemEntry {}
+
+#[repr(C)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+unsafe impl AsBuf for SetScanout {}
+
+#[repr(C)]
+struct TransferToHost2D {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for TransferToHost2D {}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+unsafe impl AsBuf for ResourceFlush {}
+
+#[repr(C)]
+struct CursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    padding: u32,
+}
+
+/// Both UPDATE_CURSOR and MOVE_CURSOR, the latter only looks at `pos`.
+#[repr(C)]
+struct UpdateCursor {
+    header: CtrlHeader,
+    pos: CursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    padding: u32,
+}
+
+impl UpdateCursor {
+    fn new(hdr_type: u32, x: u32, y: u32, resource_id: u32, hot_x: u32, hot_y: u32) -> Self {
+        UpdateCursor {
+            header: CtrlHeader::with_type(hdr_type),
+            pos: CursorPos {
+                scanout_id: SCANOUT_ID,
+                x,
+                y,
+                padding: 0,
+            },
+            resource_id,
+            hot_x,
+            hot_y,
+            padding: 0,
+        }
+    }
+}
+
+unsafe impl AsBuf for UpdateCursor {}
+
+// 2D commands
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x101;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x106;
+
+// cursor commands
+const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x300;
+const VIRTIO_GPU_CMD_MOVE_CURSOR: u32 = 0x301;
+
+// success responses
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+// error responses
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
@@ -0,0 +1,247 @@
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use volatile_register::{RO, RW};
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: usize = 32;
+const QUEUE_EVENT: usize = 0;
+const QUEUE_STATUS: usize = 1;
+
+/// Event types, as in linux input-event-codes.h
+pub const EV_SYN: u8 = 0x00;
+pub const EV_KEY: u8 = 0x01;
+pub const EV_REL: u8 = 0x02;
+pub const EV_ABS: u8 = 0x03;
+
+/// The virtio input device carries linux evdev events from the host.
+///
+/// The device is described through config selects, and sends events on
+/// the event queue. Every event buffer is one descriptor, so a buffer goes
+/// back into the queue under the same token it was popped with.
+///
+/// Ref: 5.8 Input Device
+pub struct VirtIoInput<'a, T: Transport> {
+    header: &'a mut T,
+    event_queue: VirtQueue,
+    /// Set up for the device, but the driver sends no LED updates
+    _status_queue: VirtQueue,
+    /// Buffers owned by the event queue, indexed by token
+    event_buf: Box<[RawEvent; QUEUE_SIZE]>,
+    /// The interrupt line taken by the driver, if any
+    irq: Option<u32>,
+    /// The hart receiving the interrupts of the device, if any
+    irq_hart: Option<usize>,
+}
+
+impl<'a, T: Transport> VirtIoInput<'a, T> {
+    /// Create a new virtio-input driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Input {
+            return Err(Error::InvalidParam);
+        }
+        // 输入设备没有设备相关的特性位
+        header.begin_init(|_| 0)?;
+        let mut event_queue = VirtQueue::new(header, QUEUE_EVENT, QUEUE_SIZE as u16)?;
+        let status_queue = VirtQueue::new(header, QUEUE_STATUS, QUEUE_SIZE as u16)?;
+        let mut event_buf = Box::new([RawEvent::default(); QUEUE_SIZE]);
+        for (i, event) in event_buf.iter_mut().enumerate() {
+            let token = event_queue.add(&[], &[event.as_buf_mut()])?;
+            assert_eq!(token as usize, i);
+        }
+        header.finish_init();
+        event_queue.notify(header);
+        Ok(VirtIoInput {
+            header,
+            event_queue,
+            _status_queue: status_queue,
+            event_buf,
+            irq: None,
+            irq_hart: None,
+        })
+    }
+
+    /// Wait for events on interrupt `irq` instead of busy-polling.
+    ///
+    /// The interrupt goes to the calling hart, other harts still poll.
+    /// Does nothing if external interrupts are not set up.
+    pub fn use_interrupt(&mut self, irq: u32)
+    where
+        T: 'static,
+    {
+        if hal::interrupt_enabled() {
+            // 驱动析构时注销，在此之前 transport 一直有效
+            unsafe { hal::register_interrupt(irq, self.header) };
+            self.irq = Some(irq);
+            self.irq_hart = Some(hal::hart_id());
+        }
+    }
+
+    /// The name of the device.
+    pub fn name(&mut self) -> String {
+        self.query_string(ConfigSelect::IdName)
+    }
+
+    /// The serial number of the device.
+    pub fn serial(&mut self) -> String {
+        self.query_string(ConfigSelect::IdSerial)
+    }
+
+    /// Get the bitmap of codes the device supports for `event_type`, return
+    /// its length in bytes. Bit `code` of the bitmap stands for `code`.
+    pub fn ev_bits(&mut self, event_type: u8, bitmap: &mut [u8]) -> usize {
+        self.query_config(ConfigSelect::EvBits, event_type, bitmap)
+    }
+
+    /// Whether the device sends events of `event_type`.
+    pub fn supports(&mut self, event_type: u8) -> bool {
+        self.ev_bits(event_type, &mut []) != 0
+    }
+
+    /// Get the range of absolute axis `axis`, if the device has it.
+    pub fn abs_info(&mut self, axis: u8) -> Option<AbsInfo> {
+        let mut info = AbsInfo::default();
+        match self.query_config(ConfigSelect::AbsInfo, axis, info.as_buf_mut()) {
+            0 => None,
+            _ => Some(info),
+        }
+    }
+
+    /// Wait until an event arrives, at most for `timeout`.
+    pub fn wait_event(&self, timeout: Duration) -> Result {
+        let queue = &self.event_queue;
+        let sleep = self.irq_hart == Some(hal::hart_id());
+        hal::wait_for(timeout, sleep, || queue.can_pop())
+    }
+
+    /// Take the next event sent by the device, if any.
+    pub fn pop_event(&mut self) -> Option<InputEvent> {
+        let (token, _len) = self.event_queue.pop_used().ok()?;
+        let event = self.event_buf[token as usize];
+        // 单个描述符回收后就在空闲链表头上，重新加入得到同一个令牌
+        let new_token = self.event_queue.add(&[], &[self.event_buf[token as usize].as_buf_mut()]).ok()?;
+        assert_eq!(new_token, token);
+        self.event_queue.notify(self.header);
+        Some(event.into())
+    }
+
+    /// Select `select` and `subsel`, copy the selected data into `out`,
+    /// return the size of the data.
+    fn query_config(&mut self, select: ConfigSelect, subsel: u8, out: &mut [u8]) -> usize {
+        let config = unsafe { self.header.config::<InputConfig>() };
+        unsafe {
+            config.select.write(select as u8);
+            config.subsel.write(subsel);
+        }
+        let size = config.size.read() as usize;
+        for (byte, reg) in out.iter_mut().zip(config.data.iter().take(size)) {
+            *byte = reg.read();
+        }
+        size
+    }
+
+    fn query_string(&mut self, select: ConfigSelect) -> String {
+        let mut buf = [0u8; 128];
+        let size = self.query_config(select, 0, &mut buf);
+        String::from_utf8_lossy(&buf[..size]).into()
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoInput<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+        if let Some(irq) = self.irq {
+            hal::unregister_interrupt(irq);
+        }
+    }
+}
+
+#[repr(C)]
+struct InputConfig {
+    select: RW<u8>,
+    subsel: RW<u8>,
+    size: RO<u8>,
+    _reserved: [RO<u8>; 5],
+    data: [RO<u8>; 128],
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+enum ConfigSelect {
+    Unset = 0x00,
+    IdName = 0x01,
+    IdSerial = 0x02,
+    IdDevids = 0x03,
+    PropBits = 0x10,
+    EvBits = 0x11,
+    AbsInfo = 0x12,
+}
+
+/// The range of an absolute axis.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AbsInfo {
+    pub min: u32,
+    pub max: u32,
+    pub fuzz: u32,
+    pub flat: u32,
+    pub res: u32,
+}
+
+unsafe impl AsBuf for AbsInfo {}
+
+/// An event as sent by the device, `struct virtio_input_event`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct RawEvent {
+    event_type: u16,
+    code: u16,
+    value: u32,
+}
+
+unsafe impl AsBuf for RawEvent {}
+
+/// An input event.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InputEvent {
+    /// The end of a group of events that happened together
+    Syn,
+    /// A key or button changed its state
+    Key { code: u16, state: KeyState },
+    /// A relative axis moved, e.g. a mouse
+    Rel { axis: u16, delta: i32 },
+    /// An absolute axis moved, e.g. a tablet
+    Abs { axis: u16, value: u32 },
+    /// Anything else
+    Other { event_type: u16, code: u16, value: u32 },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    Repeated,
+}
+
+impl From<RawEvent> for InputEvent {
+    fn from(event: RawEvent) -> Self {
+        let RawEvent { event_type, code, value } = event;
+        match (event_type, value) {
+            (t, _) if t == EV_SYN as u16 => InputEvent::Syn,
+            (t, 0) if t == EV_KEY as u16 => InputEvent::Key { code, state: KeyState::Released },
+            (t, 1) if t == EV_KEY as u16 => InputEvent::Key { code, state: KeyState::Pressed },
+            (t, 2) if t == EV_KEY as u16 => InputEvent::Key { code, state: KeyState::Repeated },
+            (t, _) if t == EV_REL as u16 => InputEvent::Rel { axis: code, delta: value as i32 },
+            (t, _) if t == EV_ABS as u16 => InputEvent::Abs { axis: code, value },
+            _ => InputEvent::Other { event_type, code, value },
+        }
+    }
+}
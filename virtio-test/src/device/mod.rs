@@ -0,0 +1,32 @@
+//! Drivers of virtio devices on top of a `Transport` and `VirtQueue`.
+
+pub mod balloon;
+pub mod blk;
+pub mod console;
+pub mod crypto;
+pub mod fs;
+pub mod gpu;
+pub mod input;
+pub mod net;
+pub mod p9;
+pub mod phy;
+pub mod rng;
+pub mod sound;
+pub mod vsock;
+
+use core::mem::size_of;
+use core::slice;
+
+/// Plain old data that can be handed to the device as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)` without padding bytes or pointers.
+pub(crate) unsafe trait AsBuf: Sized {
+    fn as_buf(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as _, size_of::<Self>()) }
+    }
+    fn as_buf_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self as *mut _ as _, size_of::<Self>()) }
+    }
+}
@@ -0,0 +1,548 @@
+use core::mem::size_of;
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use volatile_register::RO;
+
+use super::phy::{self, DeviceCapabilities};
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::{DeviceType, VIRTIO_F_VERSION_1};
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+/// The largest ethernet frame the driver sends or receives, without the
+/// frame check sequence.
+pub const MAX_FRAME_LEN: usize = 1514;
+
+const QUEUE_SIZE: u16 = 16;
+const QUEUE_RECEIVE: usize = 0;
+const QUEUE_TRANSMIT: usize = 1;
+
+/// How long the device may take to send one frame.
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// MAC address used when the device doesn't provide one.
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// The virtio network device is a virtual ethernet card.
+///
+/// Every receive buffer takes two descriptors, one for the `virtio_net_hdr`
+/// and one for the frame, so that legacy devices without
+/// VIRTIO_F_ANY_LAYOUT accept them. Received buffers are put back into the
+/// receive queue right away.
+///
+/// Ref: 5.1 Network Device
+pub struct VirtIoNet<'a, T: Transport> {
+    header: &'a mut T,
+    mac: [u8; 6],
+    features: NetFeature,
+    /// The length of `virtio_net_hdr` in use
+    hdr_len: usize,
+    recv_queue: VirtQueue,
+    send_queue: VirtQueue,
+    /// Buffers owned by the receive queue, indexed by token
+    rx_buffers: Vec<Option<Box<RxBuffer>>>,
+    /// The interrupt line taken by the driver, if any
+    irq: Option<u32>,
+    /// The hart receiving the interrupts of the device, if any
+    irq_hart: Option<usize>,
+}
+
+impl<'a, T: Transport> VirtIoNet<'a, T> {
+    /// Create a new virtio-net driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Network {
+            return Err(Error::InvalidParam);
+        }
+        let features = header.begin_init(|features| {
+            let features = NetFeature::from_bits_truncate(features);
+            let supported = NetFeature::MAC | NetFeature::STATUS;
+            (features & supported).bits()
+        })?;
+        // 不协商 MRG_RXBUF 时，只有现代设备的头部带 num_buffers 字段
+        let hdr_len = if features & VIRTIO_F_VERSION_1 != 0 {
+            size_of::<NetHdr>()
+        } else {
+            size_of::<NetHdr>() - size_of::<u16>()
+        };
+        let features = NetFeature::from_bits_truncate(features);
+
+        let mut mac = DEFAULT_MAC;
+        if features.contains(NetFeature::MAC) {
+            let config = unsafe { header.config::<NetConfig>() };
+            for (byte, reg) in mac.iter_mut().zip(config.mac.iter()) {
+                *byte = reg.read();
+            }
+        }
+
+        let recv_queue = VirtQueue::new(header, QUEUE_RECEIVE, QUEUE_SIZE)?;
+        let send_queue = VirtQueue::new(header, QUEUE_TRANSMIT, QUEUE_SIZE)?;
+        let mut rx_buffers = Vec::new();
+        rx_buffers.resize_with(QUEUE_SIZE as usize, || None);
+        let mut net = VirtIoNet {
+            header,
+            mac,
+            features,
+            hdr_len,
+            recv_queue,
+            send_queue,
+            rx_buffers,
+            irq: None,
+            irq_hart: None,
+        };
+        // 在 DRIVER_OK 之前填满接收队列，但设置 DRIVER_OK 之后才能通知设备
+        while net.recv_queue.available_desc() >= 2 {
+            net.post_rx(Box::new(RxBuffer::new()))?;
+        }
+        net.header.finish_init();
+        net.recv_queue.notify(net.header);
+        Ok(net)
+    }
+
+    /// Wait for frames on interrupt `irq` instead of busy-polling.
+    ///
+    /// The interrupt goes to the calling hart, other harts still poll.
+    /// Does nothing if external interrupts are not set up.
+    pub fn use_interrupt(&mut self, irq: u32)
+    where
+        T: 'static,
+    {
+        if hal::interrupt_enabled() {
+            // 驱动析构时注销，在此之前 transport 一直有效
+            unsafe { hal::register_interrupt(irq, self.header) };
+            self.irq = Some(irq);
+            self.irq_hart = Some(hal::hart_id());
+        }
+    }
+
+    /// The MAC address of the device.
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Whether the link is up. Always true if the device doesn't report it.
+    pub fn link_up(&self) -> bool {
+        if !self.features.contains(NetFeature::STATUS) {
+            return true;
+        }
+        let config = unsafe { self.header.config::<NetConfig>() };
+        config.status.read() & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    /// Whether a received frame is waiting.
+    pub fn can_recv(&self) -> bool {
+        self.recv_queue.can_pop()
+    }
+
+    /// Wait until a frame is received, at most for `timeout`.
+    pub fn wait_recv(&self, timeout: Duration) -> Result {
+        let queue = &self.recv_queue;
+        let sleep = self.irq_hart == Some(hal::hart_id());
+        hal::wait_for(timeout, sleep, || queue.can_pop())
+    }
+
+    /// Receive a frame into `buf`, return its length.
+    ///
+    /// If `buf` is too small, the frame is dropped.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let (rx, len) = self.pop_rx()?;
+        let result = if len <= buf.len() {
+            buf[..len].copy_from_slice(&rx.frame[..len]);
+            Ok(len)
+        } else {
+            Err(Error::BufferTooSmall)
+        };
+        self.post_rx(rx)?;
+        self.recv_queue.notify(self.header);
+        result
+    }
+
+    /// Send a frame and wait until the device has taken it.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    pub fn send(&mut self, frame: &[u8]) -> Result {
+        if frame.is_empty() || frame.len() > MAX_FRAME_LEN {
+            return Err(Error::InvalidParam);
+        }
+        // 不请求任何校验和或分段卸载，头部全为零
+        let hdr = NetHdr::default();
+        let token = self.send_queue.add(&[&hdr.as_buf()[..self.hdr_len], frame], &[])?;
+        self.send_queue.notify(self.header);
+        let queue = &self.send_queue;
+        let sleep = self.irq_hart == Some(hal::hart_id());
+        if let Err(e) = hal::wait_for(SEND_TIMEOUT, sleep, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.send_queue.pop_used()?;
+        // 一次只发送一帧，设备返回的一定是它
+        assert_eq!(used, token);
+        Ok(())
+    }
+
+    /// Take a used buffer from the receive queue, return it with the length
+    /// of the frame inside.
+    fn pop_rx(&mut self) -> Result<(Box<RxBuffer>, usize)> {
+        let (token, len) = self.recv_queue.pop_used()?;
+        let rx = self.rx_buffers[token as usize].take().ok_or(Error::IoError)?;
+        // 设备写入的长度包含头部
+        let len = (len as usize).saturating_sub(self.hdr_len).min(MAX_FRAME_LEN);
+        Ok((rx, len))
+    }
+
+    /// Give a buffer to the receive queue. The caller notifies the device.
+    fn post_rx(&mut self, mut rx: Box<RxBuffer>) -> Result {
+        let RxBuffer { hdr, frame } = &mut *rx;
+        let token = self
+            .recv_queue
+            .add(&[], &[&mut hdr.as_buf_mut()[..self.hdr_len], &mut frame[..]])?;
+        self.rx_buffers[token as usize] = Some(rx);
+        Ok(())
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoNet<'a, T> {
+    fn drop(&mut self) {
+        // 接收缓冲区一直挂在设备上，不复位的话之后到达的包会写进已释放的内存
+        self.header.reset();
+        if let Some(irq) = self.irq {
+            hal::unregister_interrupt(irq);
+        }
+    }
+}
+
+impl<'a, 'h: 'a, T: Transport> phy::Device<'a> for VirtIoNet<'h, T> {
+    type RxToken = NetRxToken;
+    type TxToken = NetTxToken<'a, 'h, T>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let (rx, len) = self.pop_rx().ok()?;
+        // 收到的缓冲区交给令牌，换一块新的放回接收队列
+        if self.post_rx(Box::new(RxBuffer::new())).is_ok() {
+            self.recv_queue.notify(self.header);
+        }
+        Some((NetRxToken { rx, len }, NetTxToken { net: self }))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if self.send_queue.available_desc() < 2 {
+            return None;
+        }
+        Some(NetTxToken { net: self })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            max_transmission_unit: MAX_FRAME_LEN,
+            max_burst_size: Some(1),
+        }
+    }
+}
+
+/// A frame received by `VirtIoNet`.
+pub struct NetRxToken {
+    rx: Box<RxBuffer>,
+    len: usize,
+}
+
+impl phy::RxToken for NetRxToken {
+    fn consume<R, F>(mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.rx.frame[..self.len])
+    }
+}
+
+/// Room for one frame to send through `VirtIoNet`.
+pub struct NetTxToken<'a, 'h, T: Transport> {
+    net: &'a mut VirtIoNet<'h, T>,
+}
+
+impl<'a, 'h, T: Transport> phy::TxToken for NetTxToken<'a, 'h, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        if len > MAX_FRAME_LEN {
+            return Err(Error::InvalidParam);
+        }
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let result = f(&mut frame[..len])?;
+        self.net.send(&frame[..len])?;
+        Ok(result)
+    }
+}
+
+#[repr(C)]
+struct NetConfig {
+    mac: [RO<u8>; 6],
+    status: RO<u16>,
+}
+
+/// The header in front of every frame, `struct virtio_net_hdr`.
+///
+/// Legacy devices don't have `num_buffers` unless VIRTIO_NET_F_MRG_RXBUF is
+/// negotiated.
+#[repr(C)]
+#[derive(Default)]
+struct NetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+unsafe impl AsBuf for NetHdr {}
+
+/// A receive buffer, written by the device.
+#[repr(C)]
+struct RxBuffer {
+    hdr: NetHdr,
+    frame: [u8; MAX_FRAME_LEN],
+}
+
+impl RxBuffer {
+    fn new() -> Self {
+        RxBuffer {
+            hdr: NetHdr::default(),
+            frame: [0; MAX_FRAME_LEN],
+        }
+    }
+}
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+bitflags! {
+    struct NetFeature: u64 {
+        /// Device handles packets with partial checksum.
+        const CSUM                  = 1 << 0;
+        /// Driver handles packets with partial checksum.
+        const GUEST_CSUM            = 1 << 1;
+        /// Control channel offloads reconfiguration support.
+        const CTRL_GUEST_OFFLOADS   = 1 << 2;
+        /// Device maximum MTU reporting is supported.
+        const MTU                   = 1 << 3;
+        /// Device has given MAC address.
+        const MAC                   = 1 << 5;
+        /// Device handles packets with any GSO type. (legacy)
+        const GSO                   = 1 << 6;
+        /// Driver can receive TSOv4.
+        const GUEST_TSO4            = 1 << 7;
+        /// Driver can receive TSOv6.
+        const GUEST_TSO6            = 1 << 8;
+        /// Driver can receive TSO with ECN.
+        const GUEST_ECN             = 1 << 9;
+        /// Driver can receive UFO.
+        const GUEST_UFO             = 1 << 10;
+        /// Device can receive TSOv4.
+        const HOST_TSO4             = 1 << 11;
+        /// Device can receive TSOv6.
+        const HOST_TSO6             = 1 << 12;
+        /// Device can receive TSO with ECN.
+        const HOST_ECN              = 1 << 13;
+        /// Device can receive UFO.
+        const HOST_UFO              = 1 << 14;
+        /// Driver can merge receive buffers.
+        const MRG_RXBUF             = 1 << 15;
+        /// Configuration status field is available.
+        const STATUS                = 1 << 16;
+        /// Control channel is available.
+        const CTRL_VQ               = 1 << 17;
+        /// Control channel RX mode support.
+        const CTRL_RX               = 1 << 18;
+        /// Control channel VLAN filtering.
+        const CTRL_VLAN             = 1 << 19;
+        /// Driver can send gratuitous packets.
+        const GUEST_ANNOUNCE        = 1 << 21;
+        /// Device supports multiqueue with automatic receive steering.
+        const MQ                    = 1 << 22;
+        /// Set MAC address through control channel.
+        const CTRL_MAC_ADDR         = 1 << 23;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::mock::{Fault, MockDevice, MockTransport};
+
+    const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x42];
+
+    type Frames = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+    /// A network card whose wire is two frame queues, return the frames
+    /// sent by the driver and the frames waiting to be received.
+    fn card(transport: MockTransport, device: &MockDevice, hdr_len: usize) -> (MockTransport, Frames, Frames) {
+        device.set_config(0, &MAC);
+        device.set_config(6, &VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        let sent = Frames::default();
+        let incoming = Frames::default();
+        let wire = sent.clone();
+        device.on_queue(QUEUE_TRANSMIT as u32, move |chain| {
+            wire.borrow_mut().push_back(chain.input[hdr_len..].to_vec());
+            Some(0)
+        });
+        let wire = incoming.clone();
+        device.on_queue(QUEUE_RECEIVE as u32, move |chain| {
+            // 没有帧要收时，接收缓冲区留在设备里
+            let frame = wire.borrow_mut().pop_front()?;
+            chain.write(hdr_len, &frame);
+            Some((hdr_len + frame.len()) as u32)
+        });
+        (transport, sent, incoming)
+    }
+
+    fn modern(features: NetFeature) -> (MockTransport, MockDevice, Frames, Frames) {
+        let (transport, device) = MockTransport::new(DeviceType::Network, features.bits());
+        let (transport, sent, incoming) = card(transport, &device, size_of::<NetHdr>());
+        (transport, device, sent, incoming)
+    }
+
+    #[test]
+    fn mac_and_link_from_config() {
+        let (mut transport, _device, _, _) = modern(NetFeature::MAC | NetFeature::STATUS);
+        let net = VirtIoNet::new(&mut transport).unwrap();
+        assert_eq!(net.mac(), MAC);
+        assert!(net.link_up());
+    }
+
+    #[test]
+    fn defaults_without_features() {
+        let (mut transport, device, _, _) = modern(NetFeature::empty());
+        device.set_config(6, &0u16.to_le_bytes());
+        let net = VirtIoNet::new(&mut transport).unwrap();
+        assert_eq!(net.mac(), DEFAULT_MAC);
+        assert!(net.link_up());
+    }
+
+    #[test]
+    fn receive_queue_filled_at_init() {
+        let (mut transport, device, _, _) = modern(NetFeature::empty());
+        let net = VirtIoNet::new(&mut transport).unwrap();
+        assert_eq!(device.pending(QUEUE_RECEIVE as u32), QUEUE_SIZE as usize / 2);
+        assert!(!net.can_recv());
+    }
+
+    #[test]
+    fn drop_takes_back_receive_buffers() {
+        let (mut transport, device, _, _) = modern(NetFeature::empty());
+        drop(VirtIoNet::new(&mut transport).unwrap());
+        assert!(device.status().is_empty());
+        assert_eq!(device.pending(QUEUE_RECEIVE as u32), 0);
+    }
+
+    #[test]
+    fn send_frames() {
+        let (mut transport, _device, sent, _) = modern(NetFeature::empty());
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        for i in 0..(QUEUE_SIZE as usize * 3) {
+            net.send(&[i as u8; 60]).unwrap();
+        }
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), QUEUE_SIZE as usize * 3);
+        assert!(sent.iter().enumerate().all(|(i, frame)| frame[..] == [i as u8; 60][..]));
+    }
+
+    #[test]
+    fn send_rejects_bad_frames() {
+        let (mut transport, device, _, _) = modern(NetFeature::empty());
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        assert_eq!(net.send(&[]), Err(Error::InvalidParam));
+        assert_eq!(net.send(&[0; MAX_FRAME_LEN + 1]), Err(Error::InvalidParam));
+        assert_eq!(device.notifications(QUEUE_TRANSMIT as u32), 0);
+    }
+
+    #[test]
+    fn legacy_header_is_shorter() {
+        let (transport, device) = MockTransport::new_legacy(DeviceType::Network, 0);
+        let hdr_len = size_of::<NetHdr>() - size_of::<u16>();
+        let (mut transport, sent, incoming) = card(transport, &device, hdr_len);
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        net.send(b"legacy frame").unwrap();
+        assert_eq!(sent.borrow()[0], b"legacy frame");
+        incoming.borrow_mut().push_back(b"legacy reply".to_vec());
+        device.process(QUEUE_RECEIVE as u32);
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = net.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"legacy reply");
+    }
+
+    #[test]
+    fn receive_frames() {
+        let (mut transport, device, _, incoming) = modern(NetFeature::empty());
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        assert_eq!(net.recv(&mut buf), Err(Error::NotReady));
+        for i in 0..(QUEUE_SIZE as usize * 3) {
+            incoming.borrow_mut().push_back(vec![i as u8; 64 + i]);
+            device.process(QUEUE_RECEIVE as u32);
+            net.wait_recv(Duration::from_secs(1)).unwrap();
+            let len = net.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &vec![i as u8; 64 + i][..]);
+        }
+        // 收过的缓冲区都放回了接收队列
+        assert_eq!(device.pending(QUEUE_RECEIVE as u32), QUEUE_SIZE as usize / 2);
+    }
+
+    #[test]
+    fn receive_into_small_buffer_drops_frame() {
+        let (mut transport, device, _, incoming) = modern(NetFeature::empty());
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        incoming.borrow_mut().push_back(vec![1; 100]);
+        incoming.borrow_mut().push_back(vec![2; 10]);
+        device.process(QUEUE_RECEIVE as u32);
+        let mut buf = [0u8; 50];
+        assert_eq!(net.recv(&mut buf), Err(Error::BufferTooSmall));
+        assert_eq!(net.recv(&mut buf), Ok(10));
+        assert_eq!(device.pending(QUEUE_RECEIVE as u32), QUEUE_SIZE as usize / 2);
+    }
+
+    #[test]
+    fn phy_tokens() {
+        let (mut transport, device, sent, incoming) = modern(NetFeature::empty());
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        let tx = phy::Device::transmit(&mut net).unwrap();
+        phy::TxToken::consume(tx, 4, |frame| {
+            frame.copy_from_slice(b"ping");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent.borrow()[0], b"ping");
+
+        assert!(phy::Device::receive(&mut net).is_none());
+        incoming.borrow_mut().push_back(b"pong".to_vec());
+        device.process(QUEUE_RECEIVE as u32);
+        let (rx, _tx) = phy::Device::receive(&mut net).unwrap();
+        let frame = phy::RxToken::consume(rx, |frame| Ok(frame.to_vec())).unwrap();
+        assert_eq!(frame, b"pong");
+    }
+
+    #[test]
+    fn hung_device_times_out_and_is_reset() {
+        let (mut transport, device, _, _) = modern(NetFeature::empty());
+        device.inject(Fault::Hang(QUEUE_TRANSMIT as u32));
+        let mut net = VirtIoNet::new(&mut transport).unwrap();
+        assert_eq!(net.send(&[0; 60]), Err(Error::Timeout));
+        assert!(device.status().is_empty());
+    }
+
+    #[test]
+    fn missing_transmit_queue() {
+        let (mut transport, device, _, _) = modern(NetFeature::empty());
+        device.inject(Fault::NoQueue(QUEUE_TRANSMIT as u32));
+        assert_eq!(VirtIoNet::new(&mut transport).err(), Some(Error::InvalidParam));
+    }
+}
@@ -0,0 +1,428 @@
+use core::time::Duration;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use volatile_register::RO;
+
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 4;
+
+/// How long the file server may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The largest message the client sends or receives.
+const MSIZE: u32 = 8192;
+/// The smallest message size the client accepts from the server, leaving
+/// room for the headers of `read` and `write`.
+const MIN_MSIZE: u32 = 4096;
+
+const VERSION_9P2000_L: &str = "9P2000.L";
+
+/// The tag of every request but `Tversion`, only one is in flight.
+const TAG: u16 = 1;
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+
+/// Open flags of `lopen`, as in linux.
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_TRUNC: u32 = 0o1000;
+
+/// The `kind` of a directory entry which is a directory or a regular file.
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+
+/// The virtio 9P transport carries 9P messages to a file server in the host.
+///
+/// Every request is one T-message for the device to read, and a buffer
+/// for the R-message. The protocol itself lives in `P9Client`.
+///
+/// Ref: Virtio PCI Card Specification v0.9.5, Appendix I
+pub struct VirtIo9p<'a, T: Transport> {
+    header: &'a mut T,
+    queue: VirtQueue,
+    features: P9Feature,
+}
+
+impl<'a, T: Transport> VirtIo9p<'a, T> {
+    /// Create a new virtio-9p driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::_9P {
+            return Err(Error::InvalidParam);
+        }
+        let features = header.begin_init(|features| {
+            let features = P9Feature::from_bits_truncate(features);
+            (features & P9Feature::MOUNT_TAG).bits()
+        })?;
+        let features = P9Feature::from_bits_truncate(features);
+        let queue = VirtQueue::new(header, 0, QUEUE_SIZE)?;
+        header.finish_init();
+        Ok(VirtIo9p {
+            header,
+            queue,
+            features,
+        })
+    }
+
+    /// The tag the host exports the file system under.
+    pub fn mount_tag(&self) -> Option<String> {
+        if !self.features.contains(P9Feature::MOUNT_TAG) {
+            return None;
+        }
+        let config = unsafe { self.header.config::<P9Config>() };
+        let len = config.tag_len.read() as usize;
+        let tag: Vec<u8> = config.tag.iter().take(len).map(|b| b.read()).collect();
+        Some(String::from_utf8_lossy(&tag).into())
+    }
+
+    /// Send the T-message `req`, receive the R-message into `resp`, return
+    /// its length.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    pub fn request(&mut self, req: &[u8], resp: &mut [u8]) -> Result<usize> {
+        let token = self.queue.add(&[req], &[resp])?;
+        self.queue.notify(self.header);
+        let queue = &self.queue;
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, len) = self.queue.pop_used()?;
+        // 一次只提交一个请求，设备返回的一定是它
+        assert_eq!(used, token);
+        Ok(len as usize)
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIo9p<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+/// A file on the server, named by the client.
+pub type Fid = u32;
+
+/// The identity of a file on the server.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+/// An entry returned by `readdir`.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub qid: Qid,
+    /// Pass to `readdir` to continue after this entry
+    pub offset: u64,
+    /// `DT_DIR`, `DT_REG` and so on
+    pub kind: u8,
+    pub name: String,
+}
+
+/// A minimal 9P2000.L client, one request at a time.
+///
+/// Ref: https://github.com/chaos/diod/blob/master/protocol.md
+pub struct P9Client<'a, T: Transport> {
+    transport: VirtIo9p<'a, T>,
+    msize: u32,
+    next_fid: Fid,
+    resp: Vec<u8>,
+}
+
+impl<'a, T: Transport> P9Client<'a, T> {
+    /// Agree on the protocol version and message size with the server.
+    pub fn new(transport: VirtIo9p<'a, T>) -> Result<Self> {
+        let mut client = P9Client {
+            transport,
+            msize: MSIZE,
+            next_fid: 0,
+            resp: vec![0; MSIZE as usize],
+        };
+        let mut req = Message::new(TVERSION, NOTAG);
+        req.put_u32(MSIZE).put_str(VERSION_9P2000_L);
+        let mut resp = client.call(req, RVERSION)?;
+        let msize = resp.get_u32()?;
+        if msize < MIN_MSIZE || resp.get_str()? != VERSION_9P2000_L {
+            return Err(Error::Unsupported);
+        }
+        client.msize = msize.min(MSIZE);
+        Ok(client)
+    }
+
+    /// Attach to the file tree `aname` as `uname`, return the fid of its root.
+    pub fn attach(&mut self, uname: &str, aname: &str) -> Result<Fid> {
+        let fid = self.alloc_fid();
+        let mut req = Message::new(TATTACH, TAG);
+        req.put_u32(fid).put_u32(NOFID).put_str(uname).put_str(aname).put_u32(NONUNAME);
+        self.call(req, RATTACH)?.get_qid()?;
+        Ok(fid)
+    }
+
+    /// Walk from `fid` through `names`, return a new fid for the result.
+    ///
+    /// Walking no names clones `fid`.
+    pub fn walk(&mut self, fid: Fid, names: &[&str]) -> Result<Fid> {
+        let newfid = self.alloc_fid();
+        let mut req = Message::new(TWALK, TAG);
+        req.put_u32(fid).put_u32(newfid).put_u16(names.len() as u16);
+        for name in names {
+            req.put_str(name);
+        }
+        let nwqid = self.call(req, RWALK)?.get_u16()?;
+        // 只走了一部分时服务器不会创建 newfid
+        if nwqid as usize != names.len() {
+            return Err(Error::Errno(ENOENT));
+        }
+        Ok(newfid)
+    }
+
+    /// Open the file of `fid` with linux open `flags`, return its qid.
+    pub fn lopen(&mut self, fid: Fid, flags: u32) -> Result<Qid> {
+        let mut req = Message::new(TLOPEN, TAG);
+        req.put_u32(fid).put_u32(flags);
+        self.call(req, RLOPEN)?.get_qid()
+    }
+
+    /// Read from the open file of `fid` at `offset` into `buf`, return the
+    /// number of bytes read. 0 means end of file.
+    pub fn read(&mut self, fid: Fid, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        // size[4] type[1] tag[2] count[4]
+        let count = buf.len().min(self.msize as usize - 11);
+        let mut req = Message::new(TREAD, TAG);
+        req.put_u32(fid).put_u64(offset).put_u32(count as u32);
+        let mut resp = self.call(req, RREAD)?;
+        let data = resp.get_data()?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    /// Write `data` to the open file of `fid` at `offset`, return the number
+    /// of bytes written.
+    pub fn write(&mut self, fid: Fid, offset: u64, data: &[u8]) -> Result<usize> {
+        // size[4] type[1] tag[2] fid[4] offset[8] count[4]
+        let count = data.len().min(self.msize as usize - 23);
+        let mut req = Message::new(TWRITE, TAG);
+        req.put_u32(fid).put_u64(offset).put_u32(count as u32).put_bytes(&data[..count]);
+        Ok(self.call(req, RWRITE)?.get_u32()? as usize)
+    }
+
+    /// Read the entries of the open directory of `fid` starting from
+    /// `offset`. An empty result means the end of the directory.
+    pub fn readdir(&mut self, fid: Fid, offset: u64) -> Result<Vec<DirEntry>> {
+        let count = self.msize - 11;
+        let mut req = Message::new(TREADDIR, TAG);
+        req.put_u32(fid).put_u64(offset).put_u32(count);
+        let mut resp = self.call(req, RREADDIR)?;
+        let mut data = Reader::new(resp.get_data()?);
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            entries.push(DirEntry {
+                qid: data.get_qid()?,
+                offset: data.get_u64()?,
+                kind: data.get_u8()?,
+                name: data.get_str()?.into(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Forget `fid`, closing its file if open.
+    pub fn clunk(&mut self, fid: Fid) -> Result {
+        let mut req = Message::new(TCLUNK, TAG);
+        req.put_u32(fid);
+        self.call(req, RCLUNK)?;
+        Ok(())
+    }
+
+    fn alloc_fid(&mut self) -> Fid {
+        let fid = self.next_fid;
+        self.next_fid += 1;
+        fid
+    }
+
+    /// Send `req`, check the reply is of type `expected`, return a reader
+    /// positioned after the header.
+    fn call(&mut self, req: Message, expected: u8) -> Result<Reader<'_>> {
+        let req = req.finish();
+        if req.len() > self.msize as usize {
+            return Err(Error::BufferTooSmall);
+        }
+        let len = self.transport.request(&req, &mut self.resp)?;
+        let mut resp = Reader::new(&self.resp[..len.min(self.resp.len())]);
+        let size = resp.get_u32()? as usize;
+        let kind = resp.get_u8()?;
+        let _tag = resp.get_u16()?;
+        if size > len {
+            return Err(Error::IoError);
+        }
+        match kind {
+            RLERROR => Err(Error::Errno(resp.get_u32()?)),
+            kind if kind == expected => Ok(resp),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+/// A T-message being built.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    /// Start a message, leaving room for its size.
+    fn new(kind: u8, tag: u16) -> Self {
+        let mut msg = Message { buf: Vec::new() };
+        msg.put_u32(0).put_u8(kind).put_u16(tag);
+        msg
+    }
+
+    fn put_u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    fn put_u16(&mut self, value: u16) -> &mut Self {
+        self.put_bytes(&value.to_le_bytes())
+    }
+
+    fn put_u32(&mut self, value: u32) -> &mut Self {
+        self.put_bytes(&value.to_le_bytes())
+    }
+
+    fn put_u64(&mut self, value: u64) -> &mut Self {
+        self.put_bytes(&value.to_le_bytes())
+    }
+
+    /// A string is its length in 2 bytes, then the bytes without NUL.
+    fn put_str(&mut self, value: &str) -> &mut Self {
+        self.put_u16(value.len() as u16).put_bytes(value.as_bytes())
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    /// Fill in the size, return the whole message.
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Takes fields out of an R-message.
+struct Reader<'b> {
+    buf: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn new(buf: &'b [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn get_bytes(&mut self, len: usize) -> Result<&'b [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::IoError);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn get_u8(&mut self) -> Result<u8> {
+        Ok(self.get_bytes(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> Result<u16> {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(self.get_bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn get_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.get_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn get_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.get_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn get_str(&mut self) -> Result<&'b str> {
+        let len = self.get_u16()? as usize;
+        core::str::from_utf8(self.get_bytes(len)?).map_err(|_| Error::IoError)
+    }
+
+    /// Data is its length in 4 bytes, then the bytes.
+    fn get_data(&mut self) -> Result<&'b [u8]> {
+        let len = self.get_u32()? as usize;
+        self.get_bytes(len)
+    }
+
+    fn get_qid(&mut self) -> Result<Qid> {
+        Ok(Qid {
+            kind: self.get_u8()?,
+            version: self.get_u32()?,
+            path: self.get_u64()?,
+        })
+    }
+}
+
+#[repr(C)]
+struct P9Config {
+    /// Length of the tag name
+    tag_len: RO<u16>,
+    /// Not NUL-terminated
+    tag: [RO<u8>; 64],
+}
+
+// message types of 9P2000.L
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const RLOPEN: u8 = 13;
+const TREADDIR: u8 = 40;
+const RREADDIR: u8 = 41;
+const TVERSION: u8 = 100;
+const RVERSION: u8 = 101;
+const TATTACH: u8 = 104;
+const RATTACH: u8 = 105;
+const TWALK: u8 = 110;
+const RWALK: u8 = 111;
+const TREAD: u8 = 116;
+const RREAD: u8 = 117;
+const TWRITE: u8 = 118;
+const RWRITE: u8 = 119;
+const TCLUNK: u8 = 120;
+const RCLUNK: u8 = 121;
+
+/// `n_uname` of `Tattach` when the user is given by name.
+const NONUNAME: u32 = !0;
+
+const ENOENT: u32 = 2;
+
+bitflags! {
+    struct P9Feature: u64 {
+        /// The mount tag is in the config space.
+        const MOUNT_TAG = 1 << 0;
+    }
+}
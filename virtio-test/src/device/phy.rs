@@ -0,0 +1,49 @@
+//! Network device interface in the shape of `smoltcp::phy`.
+//!
+//! A network driver hands out tokens instead of buffers: a receive token
+//! owns one received frame, a transmit token reserves room for one frame
+//! to send. A no_std TCP/IP stack only needs these three traits to sit on
+//! top of a driver.
+
+use crate::Result;
+
+/// What a network device can do.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceCapabilities {
+    /// Maximum size of an ethernet frame, without the frame check sequence
+    pub max_transmission_unit: usize,
+    /// How many frames can be sent or received in a burst, if limited
+    pub max_burst_size: Option<usize>,
+}
+
+/// An interface for sending and receiving raw network frames.
+pub trait Device<'a> {
+    type RxToken: RxToken + 'a;
+    type TxToken: TxToken + 'a;
+
+    /// Get a received frame, together with a token to answer it.
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)>;
+
+    /// Get a token to send a frame, if there is room for one.
+    fn transmit(&'a mut self) -> Option<Self::TxToken>;
+
+    /// Get the capabilities of the device.
+    fn capabilities(&self) -> DeviceCapabilities;
+}
+
+/// A token to receive a single network frame.
+pub trait RxToken {
+    /// Consume the token, passing the frame to `f`.
+    fn consume<R, F>(self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>;
+}
+
+/// A token to send a single network frame.
+pub trait TxToken {
+    /// Consume the token, letting `f` fill a frame of `len` bytes, then
+    /// send it.
+    fn consume<R, F>(self, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>;
+}
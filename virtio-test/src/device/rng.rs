@@ -0,0 +1,75 @@
+use core::time::Duration;
+
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 8;
+
+/// How long the device may take to serve one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The virtio entropy device supplies high-quality randomness.
+///
+/// The driver gives a buffer to the single request queue, and the device
+/// fills it with as many random bytes as it has.
+///
+/// Ref: 5.4 Entropy Device
+pub struct VirtIoRng<'a, T: Transport> {
+    header: &'a mut T,
+    queue: VirtQueue,
+}
+
+impl<'a, T: Transport> VirtIoRng<'a, T> {
+    /// Create a new virtio-rng driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::EntropySource {
+            return Err(Error::InvalidParam);
+        }
+        // 熵设备没有设备相关的特性位
+        header.begin_init(|_| 0)?;
+        let queue = VirtQueue::new(header, 0, QUEUE_SIZE)?;
+        header.finish_init();
+        Ok(VirtIoRng { header, queue })
+    }
+
+    /// Fill the front of `buf` with random bytes, return how many.
+    ///
+    /// The device may return fewer bytes than asked for, but never none.
+    pub fn request(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let token = self.queue.add(&[], &[buf])?;
+        self.queue.notify(self.header);
+        let queue = &self.queue;
+        if let Err(e) = hal::wait_for(REQUEST_TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, len) = self.queue.pop_used()?;
+        // 一次只提交一个请求，设备返回的一定是它
+        assert_eq!(used, token);
+        match len as usize {
+            0 => Err(Error::IoError),
+            len => Ok(len.min(buf.len())),
+        }
+    }
+
+    /// Fill all of `buf` with random bytes.
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result {
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += self.request(&mut buf[filled..])?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoRng<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
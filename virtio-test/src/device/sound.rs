@@ -0,0 +1,473 @@
+use core::mem::size_of;
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use volatile_register::RO;
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 16;
+const EVENT_QUEUE_SIZE: usize = 8;
+const QUEUE_CONTROL: usize = 0;
+const QUEUE_EVENT: usize = 1;
+const QUEUE_TX: usize = 2;
+const QUEUE_RX: usize = 3;
+
+/// How long the device may take to serve a control request, or to play
+/// one buffer.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Directions of streams, jacks and channel maps
+pub const DIRECTION_OUTPUT: u8 = 0;
+
+/// Signed 16-bit samples, the index in `PcmInfo::formats`
+pub const PCM_FMT_S16: u8 = 5;
+
+/// 44100 Hz, the index in `PcmInfo::rates`
+pub const PCM_RATE_44100: u8 = 6;
+
+/// The virtio sound device, driven for PCM playback.
+///
+/// Streams are set up on the control queue. Playback buffers go to the TX
+/// queue, and the device gives them back once it has played them, so the
+/// queue paces the driver. Every buffer takes three descriptors: the
+/// stream id, the frames and the status.
+///
+/// Ref: 5.14 Sound Device
+pub struct VirtIoSound<'a, T: Transport> {
+    header: &'a mut T,
+    control_queue: VirtQueue,
+    event_queue: VirtQueue,
+    tx_queue: VirtQueue,
+    /// Set up for the device, but the driver does no capture
+    _rx_queue: VirtQueue,
+    /// Buffers owned by the event queue, indexed by token
+    event_buf: Box<[Event; EVENT_QUEUE_SIZE]>,
+    /// Buffers owned by the TX queue, indexed by token
+    tx_buffers: Vec<Option<Box<TxBuffer>>>,
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+}
+
+impl<'a, T: Transport> VirtIoSound<'a, T> {
+    /// Create a new virtio-sound driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Sound {
+            return Err(Error::InvalidParam);
+        }
+        // 不需要控制元素特性
+        header.begin_init(|_| 0)?;
+        let config = unsafe { header.config::<SoundConfig>() };
+        let (jacks, streams, chmaps) = (config.jacks.read(), config.streams.read(), config.chmaps.read());
+        let control_queue = VirtQueue::new(header, QUEUE_CONTROL, QUEUE_SIZE)?;
+        let mut event_queue = VirtQueue::new(header, QUEUE_EVENT, EVENT_QUEUE_SIZE as u16)?;
+        let tx_queue = VirtQueue::new(header, QUEUE_TX, QUEUE_SIZE)?;
+        let rx_queue = VirtQueue::new(header, QUEUE_RX, QUEUE_SIZE)?;
+        let mut event_buf = Box::new([Event::default(); EVENT_QUEUE_SIZE]);
+        for (i, event) in event_buf.iter_mut().enumerate() {
+            let token = event_queue.add(&[], &[event.as_buf_mut()])?;
+            assert_eq!(token as usize, i);
+        }
+        let mut tx_buffers = Vec::new();
+        tx_buffers.resize_with(QUEUE_SIZE as usize, || None);
+        header.finish_init();
+        event_queue.notify(header);
+        Ok(VirtIoSound {
+            header,
+            control_queue,
+            event_queue,
+            tx_queue,
+            _rx_queue: rx_queue,
+            event_buf,
+            tx_buffers,
+            jacks,
+            streams,
+            chmaps,
+        })
+    }
+
+    /// The numbers of jacks, streams and channel maps.
+    pub fn counts(&self) -> (u32, u32, u32) {
+        (self.jacks, self.streams, self.chmaps)
+    }
+
+    /// Describe all jacks.
+    pub fn jack_info(&mut self) -> Result<Vec<JackInfo>> {
+        self.query_info(VIRTIO_SND_R_JACK_INFO, self.jacks)
+    }
+
+    /// Describe all PCM streams.
+    pub fn pcm_info(&mut self) -> Result<Vec<PcmInfo>> {
+        self.query_info(VIRTIO_SND_R_PCM_INFO, self.streams)
+    }
+
+    /// Describe all channel maps.
+    pub fn chmap_info(&mut self) -> Result<Vec<ChmapInfo>> {
+        self.query_info(VIRTIO_SND_R_CHMAP_INFO, self.chmaps)
+    }
+
+    /// Set the format of stream `stream_id`.
+    pub fn pcm_set_params(&mut self, stream_id: u32, params: PcmParams) -> Result {
+        let req = PcmSetParams {
+            hdr: PcmHdr {
+                code: VIRTIO_SND_R_PCM_SET_PARAMS,
+                stream_id,
+            },
+            buffer_bytes: params.buffer_bytes,
+            period_bytes: params.period_bytes,
+            features: 0,
+            channels: params.channels,
+            format: params.format,
+            rate: params.rate,
+            padding: 0,
+        };
+        self.request(req.as_buf(), &mut [])
+    }
+
+    /// Get stream `stream_id` ready to play with its parameters.
+    pub fn pcm_prepare(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_PREPARE, stream_id)
+    }
+
+    /// Start playing stream `stream_id`.
+    pub fn pcm_start(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_START, stream_id)
+    }
+
+    /// Stop playing stream `stream_id`.
+    pub fn pcm_stop(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_STOP, stream_id)
+    }
+
+    /// Free the resources of stream `stream_id`.
+    pub fn pcm_release(&mut self, stream_id: u32) -> Result {
+        self.pcm_request(VIRTIO_SND_R_PCM_RELEASE, stream_id)
+    }
+
+    /// Queue `frames` for playback on stream `stream_id`, waiting for the
+    /// device to give back a buffer if the queue is full.
+    ///
+    /// One call should carry one period.
+    pub fn pcm_write(&mut self, stream_id: u32, frames: &[u8]) -> Result {
+        if frames.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        while self.tx_queue.available_desc() < 3 {
+            self.pop_tx()?;
+        }
+        let mut tx = Box::new(TxBuffer {
+            xfer: PcmXfer { stream_id },
+            frames: frames.to_vec(),
+            status: PcmStatus::default(),
+        });
+        let TxBuffer { xfer, frames, status } = &mut *tx;
+        let token = self.tx_queue.add(&[xfer.as_buf(), frames], &[status.as_buf_mut()])?;
+        self.tx_buffers[token as usize] = Some(tx);
+        self.tx_queue.notify(self.header);
+        Ok(())
+    }
+
+    /// Wait until the device has played all queued frames.
+    pub fn pcm_drain(&mut self) -> Result {
+        while self.tx_buffers.iter().any(Option::is_some) {
+            self.pop_tx()?;
+        }
+        Ok(())
+    }
+
+    /// Take the next event sent by the device, if any.
+    pub fn pop_event(&mut self) -> Option<SoundEvent> {
+        let (token, _len) = self.event_queue.pop_used().ok()?;
+        let event = self.event_buf[token as usize];
+        // 单个描述符回收后就在空闲链表头上，重新加入得到同一个令牌
+        let new_token = self.event_queue.add(&[], &[self.event_buf[token as usize].as_buf_mut()]).ok()?;
+        assert_eq!(new_token, token);
+        self.event_queue.notify(self.header);
+        Some(event.into())
+    }
+
+    /// Wait for a played buffer and check its status.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    fn pop_tx(&mut self) -> Result {
+        let queue = &self.tx_queue;
+        if let Err(e) = hal::wait_for(TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (token, _len) = self.tx_queue.pop_used()?;
+        let tx = self.tx_buffers[token as usize].take().ok_or(Error::IoError)?;
+        check_status(tx.status.status)
+    }
+
+    fn pcm_request(&mut self, code: u32, stream_id: u32) -> Result {
+        let req = PcmHdr { code, stream_id };
+        self.request(req.as_buf(), &mut [])
+    }
+
+    /// Query `count` items of information, starting from the first one.
+    fn query_info<I: AsBuf + Default>(&mut self, code: u32, count: u32) -> Result<Vec<I>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let req = QueryInfo {
+            code,
+            start_id: 0,
+            count,
+            size: size_of::<I>() as u32,
+        };
+        let mut buf = vec![0u8; size_of::<I>() * count as usize];
+        self.request(req.as_buf(), &mut buf)?;
+        let infos = buf
+            .chunks_exact(size_of::<I>())
+            .map(|chunk| {
+                let mut info = I::default();
+                info.as_buf_mut().copy_from_slice(chunk);
+                info
+            })
+            .collect();
+        Ok(infos)
+    }
+
+    /// Send a control request and wait until the device has served it.
+    /// The device writes the status, then `out`.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    fn request(&mut self, req: &[u8], out: &mut [u8]) -> Result {
+        let mut status = Hdr::default();
+        let token = if out.is_empty() {
+            self.control_queue.add(&[req], &[status.as_buf_mut()])?
+        } else {
+            self.control_queue.add(&[req], &[status.as_buf_mut(), out])?
+        };
+        self.control_queue.notify(self.header);
+        let queue = &self.control_queue;
+        if let Err(e) = hal::wait_for(TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.control_queue.pop_used()?;
+        // 一次只提交一个请求，设备返回的一定是它
+        assert_eq!(used, token);
+        check_status(status.code)
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoSound<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+fn check_status(code: u32) -> Result {
+    match code {
+        VIRTIO_SND_S_OK => Ok(()),
+        VIRTIO_SND_S_BAD_MSG => Err(Error::InvalidParam),
+        VIRTIO_SND_S_NOT_SUPP => Err(Error::Unsupported),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// The format of a PCM stream.
+#[derive(Debug, Clone, Copy)]
+pub struct PcmParams {
+    /// The size of the whole buffer, a multiple of `period_bytes`
+    pub buffer_bytes: u32,
+    /// How often the device reports progress
+    pub period_bytes: u32,
+    pub channels: u8,
+    /// `PCM_FMT_*`
+    pub format: u8,
+    /// `PCM_RATE_*`
+    pub rate: u8,
+}
+
+/// A jack, `struct virtio_snd_jack_info`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JackInfo {
+    pub hda_fn_nid: u32,
+    pub features: u32,
+    pub hda_reg_defconf: u32,
+    pub hda_reg_caps: u32,
+    pub connected: u8,
+    _padding: [u8; 7],
+}
+
+unsafe impl AsBuf for JackInfo {}
+
+/// A PCM stream, `struct virtio_snd_pcm_info`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PcmInfo {
+    pub hda_fn_nid: u32,
+    pub features: u32,
+    /// Bit `PCM_FMT_*` set for every supported format
+    pub formats: u64,
+    /// Bit `PCM_RATE_*` set for every supported rate
+    pub rates: u64,
+    pub direction: u8,
+    pub channels_min: u8,
+    pub channels_max: u8,
+    _padding: [u8; 5],
+}
+
+unsafe impl AsBuf for PcmInfo {}
+
+/// A channel map, `struct virtio_snd_chmap_info`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChmapInfo {
+    pub hda_fn_nid: u32,
+    pub direction: u8,
+    pub channels: u8,
+    /// `VIRTIO_SND_CHMAP_*` of each channel
+    pub positions: [u8; 18],
+}
+
+unsafe impl AsBuf for ChmapInfo {}
+
+/// An event from the device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SoundEvent {
+    JackConnected { jack_id: u32 },
+    JackDisconnected { jack_id: u32 },
+    /// A period of the stream was played
+    PcmPeriodElapsed { stream_id: u32 },
+    /// The stream ran out of frames
+    PcmXrun { stream_id: u32 },
+    Other { code: u32, data: u32 },
+}
+
+impl From<Event> for SoundEvent {
+    fn from(event: Event) -> Self {
+        let Event { code, data } = event;
+        match code {
+            VIRTIO_SND_EVT_JACK_CONNECTED => SoundEvent::JackConnected { jack_id: data },
+            VIRTIO_SND_EVT_JACK_DISCONNECTED => SoundEvent::JackDisconnected { jack_id: data },
+            VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED => SoundEvent::PcmPeriodElapsed { stream_id: data },
+            VIRTIO_SND_EVT_PCM_XRUN => SoundEvent::PcmXrun { stream_id: data },
+            _ => SoundEvent::Other { code, data },
+        }
+    }
+}
+
+#[repr(C)]
+struct SoundConfig {
+    jacks: RO<u32>,
+    streams: RO<u32>,
+    chmaps: RO<u32>,
+}
+
+/// `struct virtio_snd_hdr`, also the status of replies
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Hdr {
+    code: u32,
+}
+
+unsafe impl AsBuf for Hdr {}
+
+/// `struct virtio_snd_query_info`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct QueryInfo {
+    code: u32,
+    start_id: u32,
+    count: u32,
+    size: u32,
+}
+
+unsafe impl AsBuf for QueryInfo {}
+
+/// `struct virtio_snd_pcm_hdr`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PcmHdr {
+    code: u32,
+    stream_id: u32,
+}
+
+unsafe impl AsBuf for PcmHdr {}
+
+/// `struct virtio_snd_pcm_set_params`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PcmSetParams {
+    hdr: PcmHdr,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+unsafe impl AsBuf for PcmSetParams {}
+
+/// `struct virtio_snd_pcm_xfer`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PcmXfer {
+    stream_id: u32,
+}
+
+unsafe impl AsBuf for PcmXfer {}
+
+/// `struct virtio_snd_pcm_status`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PcmStatus {
+    status: u32,
+    latency_bytes: u32,
+}
+
+unsafe impl AsBuf for PcmStatus {}
+
+struct TxBuffer {
+    xfer: PcmXfer,
+    frames: Vec<u8>,
+    status: PcmStatus,
+}
+
+/// `struct virtio_snd_event`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Event {
+    code: u32,
+    data: u32,
+}
+
+unsafe impl AsBuf for Event {}
+
+// control request codes
+const VIRTIO_SND_R_JACK_INFO: u32 = 0x0001;
+const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+
+// event codes
+const VIRTIO_SND_EVT_JACK_CONNECTED: u32 = 0x1000;
+const VIRTIO_SND_EVT_JACK_DISCONNECTED: u32 = 0x1001;
+const VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED: u32 = 0x1100;
+const VIRTIO_SND_EVT_PCM_XRUN: u32 = 0x1101;
+
+// status codes
+const VIRTIO_SND_S_OK: u32 = 0x8000;
+const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
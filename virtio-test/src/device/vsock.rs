@@ -0,0 +1,552 @@
+use core::hint::spin_loop;
+use core::mem::size_of;
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use volatile_register::RO;
+
+use super::AsBuf;
+use crate::hal;
+use crate::mmio::DeviceType;
+use crate::queue::VirtQueue;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+const QUEUE_SIZE: u16 = 16;
+const EVENT_QUEUE_SIZE: usize = 4;
+const QUEUE_RX: usize = 0;
+const QUEUE_TX: usize = 1;
+const QUEUE_EVENT: usize = 2;
+
+/// The payload one receive buffer holds.
+const RX_DATA_LEN: usize = 1024;
+
+/// How many received bytes a connection buffers before the application
+/// reads them. Peers never send more than this ahead.
+const BUF_ALLOC: u32 = 4096;
+
+/// How long the device may take to send one packet, and the peer to
+/// answer a connection request or give credit.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The CID of the host.
+pub const HOST_CID: u64 = 2;
+
+/// The first port `connect` binds to.
+const FIRST_LOCAL_PORT: u32 = 1024;
+
+/// A connection of `VirtIoVsock`.
+pub type SocketId = usize;
+
+/// The virtio socket device carries stream connections between the guest
+/// and the host.
+///
+/// Every packet is a `virtio_vsock_hdr` followed by the payload. As with
+/// virtio-net, receive buffers take two descriptors so that legacy
+/// devices accept them. The driver polls: nothing happens on a connection
+/// unless one of the methods below is called.
+///
+/// Ref: 5.10 Socket Device
+pub struct VirtIoVsock<'a, T: Transport> {
+    header: &'a mut T,
+    guest_cid: u64,
+    rx_queue: VirtQueue,
+    tx_queue: VirtQueue,
+    event_queue: VirtQueue,
+    /// Buffers owned by the receive queue, indexed by token
+    rx_buffers: Vec<Option<Box<RxBuffer>>>,
+    /// Buffers owned by the event queue, indexed by token
+    event_buf: Box<[Event; EVENT_QUEUE_SIZE]>,
+    /// Indexed by `SocketId`, `None` once closed
+    connections: Vec<Option<Connection>>,
+    /// Ports accepting connections from peers
+    listening: Vec<u32>,
+    /// Connections from peers not accepted yet
+    pending: VecDeque<SocketId>,
+    next_port: u32,
+}
+
+impl<'a, T: Transport> VirtIoVsock<'a, T> {
+    /// Create a new virtio-vsock driver.
+    pub fn new(header: &'a mut T) -> Result<Self> {
+        if header.device_type() != DeviceType::Socket {
+            return Err(Error::InvalidParam);
+        }
+        // 只支持流式连接，不需要任何特性位
+        header.begin_init(|_| 0)?;
+        let rx_queue = VirtQueue::new(header, QUEUE_RX, QUEUE_SIZE)?;
+        let tx_queue = VirtQueue::new(header, QUEUE_TX, QUEUE_SIZE)?;
+        let mut event_queue = VirtQueue::new(header, QUEUE_EVENT, EVENT_QUEUE_SIZE as u16)?;
+        let mut event_buf = Box::new([Event::default(); EVENT_QUEUE_SIZE]);
+        for (i, event) in event_buf.iter_mut().enumerate() {
+            let token = event_queue.add(&[], &[event.as_buf_mut()])?;
+            assert_eq!(token as usize, i);
+        }
+        let mut rx_buffers = Vec::new();
+        rx_buffers.resize_with(QUEUE_SIZE as usize, || None);
+        let mut vsock = VirtIoVsock {
+            guest_cid: read_guest_cid(header),
+            header,
+            rx_queue,
+            tx_queue,
+            event_queue,
+            rx_buffers,
+            event_buf,
+            connections: Vec::new(),
+            listening: Vec::new(),
+            pending: VecDeque::new(),
+            next_port: FIRST_LOCAL_PORT,
+        };
+        while vsock.rx_queue.available_desc() >= 2 {
+            vsock.post_rx(Box::new(RxBuffer::new()))?;
+        }
+        vsock.header.finish_init();
+        vsock.rx_queue.notify(vsock.header);
+        vsock.event_queue.notify(vsock.header);
+        Ok(vsock)
+    }
+
+    /// The CID of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Accept connections from peers to `port`.
+    pub fn listen(&mut self, port: u32) {
+        if !self.listening.contains(&port) {
+            self.listening.push(port);
+        }
+    }
+
+    /// Wait for a peer to connect to the listening `port`, at most for
+    /// `timeout`.
+    pub fn accept(&mut self, port: u32, timeout: Duration) -> Result<SocketId> {
+        if !self.listening.contains(&port) {
+            return Err(Error::InvalidParam);
+        }
+        self.poll_until(timeout, |vsock| vsock.find_pending(port).is_some())?;
+        let index = self.find_pending(port).unwrap();
+        Ok(self.pending.remove(index).unwrap())
+    }
+
+    /// Connect to `port` of `cid`.
+    pub fn connect(&mut self, cid: u64, port: u32) -> Result<SocketId> {
+        let local_port = self.next_port;
+        self.next_port += 1;
+        let id = self.insert(Connection::new(local_port, cid, port, State::Connecting));
+        self.send_control(id, OP_REQUEST, 0)?;
+        let result = self.poll_until(TIMEOUT, |vsock| vsock.connections[id].as_ref().unwrap().state != State::Connecting);
+        let state = self.connections[id].as_ref().unwrap().state;
+        match (result, state) {
+            (_, State::Connected) => Ok(id),
+            (Err(e), _) => {
+                self.connections[id] = None;
+                Err(e)
+            }
+            // 对方用 RST 拒绝了连接
+            _ => {
+                self.connections[id] = None;
+                Err(Error::IoError)
+            }
+        }
+    }
+
+    /// Send all of `data` on connection `id`, waiting for the peer to make
+    /// room for it.
+    pub fn send(&mut self, id: SocketId, mut data: &[u8]) -> Result {
+        while !data.is_empty() {
+            self.poll_until(TIMEOUT, |vsock| match vsock.connection(id) {
+                Ok(conn) => conn.state != State::Connected || conn.peer_credit() > 0,
+                Err(_) => true,
+            })?;
+            let conn = self.connection(id)?;
+            if conn.state != State::Connected {
+                return Err(Error::NotReady);
+            }
+            let len = data.len().min(conn.peer_credit() as usize).min(RX_DATA_LEN);
+            let hdr = self.packet_header(id, OP_RW, len as u32);
+            self.send_packet(&hdr, &data[..len])?;
+            let conn = self.connection_mut(id)?;
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Receive what the peer sent on connection `id` into `buf`, return its
+    /// length. Returns 0 if nothing arrived yet, or the peer closed the
+    /// connection, see `peer_closed`.
+    pub fn recv(&mut self, id: SocketId, buf: &mut [u8]) -> Result<usize> {
+        self.poll()?;
+        let conn = self.connection_mut(id)?;
+        let len = buf.len().min(conn.rx.len());
+        for (byte, data) in buf.iter_mut().zip(conn.rx.drain(..len)) {
+            *byte = data;
+        }
+        conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+        // 对方看到的空间少于一半时主动告诉它新的额度
+        let state = conn.state;
+        if state == State::Connected && conn.fwd_cnt.wrapping_sub(conn.fwd_cnt_sent) >= BUF_ALLOC / 2 {
+            self.send_control(id, OP_CREDIT_UPDATE, 0)?;
+        }
+        Ok(len)
+    }
+
+    /// Wait until something arrives on connection `id` or the peer closes
+    /// it, at most for `timeout`.
+    pub fn wait_recv(&mut self, id: SocketId, timeout: Duration) -> Result {
+        self.connection(id)?;
+        self.poll_until(timeout, |vsock| {
+            let conn = vsock.connections[id].as_ref().unwrap();
+            !conn.rx.is_empty() || conn.peer_closed()
+        })
+    }
+
+    /// Whether the peer will send nothing more on connection `id`.
+    pub fn peer_closed(&self, id: SocketId) -> Result<bool> {
+        Ok(self.connection(id)?.peer_closed())
+    }
+
+    /// Close connection `id`, dropping what was not received.
+    pub fn close(&mut self, id: SocketId) -> Result {
+        if self.connection(id)?.state == State::Connected {
+            self.send_control(id, OP_SHUTDOWN, SHUTDOWN_RECV | SHUTDOWN_SEND)?;
+        }
+        // 对方随后的 RST 找不到连接，会被忽略
+        self.connections[id] = None;
+        Ok(())
+    }
+
+    /// Handle the packets and events from the device.
+    fn poll(&mut self) -> Result {
+        let mut posted = false;
+        while self.rx_queue.can_pop() {
+            let (token, len) = self.rx_queue.pop_used()?;
+            let rx = self.rx_buffers[token as usize].take().ok_or(Error::IoError)?;
+            // 设备写入的长度包含头部
+            let len = (len as usize).saturating_sub(size_of::<PacketHeader>()).min(rx.hdr.len as usize).min(RX_DATA_LEN);
+            let result = self.handle_packet(rx.hdr, &rx.data[..len]);
+            self.post_rx(rx)?;
+            posted = true;
+            result?;
+        }
+        if posted {
+            self.rx_queue.notify(self.header);
+        }
+        while let Ok((token, _len)) = self.event_queue.pop_used() {
+            if self.event_buf[token as usize].id == EVENT_TRANSPORT_RESET {
+                // 迁移之后 CID 可能变了，已有的连接都失效
+                self.guest_cid = read_guest_cid(self.header);
+                for conn in self.connections.iter_mut().flatten() {
+                    conn.state = State::Closed;
+                }
+            }
+            let new_token = self.event_queue.add(&[], &[self.event_buf[token as usize].as_buf_mut()])?;
+            assert_eq!(new_token, token);
+            self.event_queue.notify(self.header);
+        }
+        Ok(())
+    }
+
+    /// Poll until `condition` holds, or fail with `Error::Timeout` after
+    /// `timeout`.
+    fn poll_until(&mut self, timeout: Duration, condition: impl Fn(&Self) -> bool) -> Result {
+        let deadline = hal::now() + timeout;
+        loop {
+            self.poll()?;
+            if condition(self) {
+                return Ok(());
+            }
+            if hal::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            spin_loop();
+        }
+    }
+
+    fn handle_packet(&mut self, hdr: PacketHeader, payload: &[u8]) -> Result {
+        if hdr.type_ != TYPE_STREAM || hdr.dst_cid != self.guest_cid {
+            return Ok(());
+        }
+        let (op, port) = (hdr.op, hdr.dst_port);
+        let found = self.connections.iter().position(|conn| match conn {
+            Some(conn) => {
+                conn.local_port == port && conn.peer_cid == hdr.src_cid && conn.peer_port == hdr.src_port
+            }
+            None => false,
+        });
+        let id = match found {
+            Some(id) => id,
+            None if op == OP_REQUEST && self.listening.contains(&port) => {
+                let mut conn = Connection::new(port, hdr.src_cid, hdr.src_port, State::Connected);
+                conn.update_credit(&hdr);
+                let id = self.insert(conn);
+                self.pending.push_back(id);
+                return self.send_control(id, OP_RESPONSE, 0);
+            }
+            // 不认识的连接一律用 RST 回绝，但不回应 RST 本身
+            None if op != OP_RST => return self.send_reset(&hdr),
+            None => return Ok(()),
+        };
+        let conn = self.connections[id].as_mut().unwrap();
+        conn.update_credit(&hdr);
+        match op {
+            OP_RESPONSE if conn.state == State::Connecting => conn.state = State::Connected,
+            OP_RW => {
+                let room = BUF_ALLOC as usize - conn.rx.len();
+                conn.rx.extend(payload.iter().take(room));
+            }
+            OP_CREDIT_REQUEST => self.send_control(id, OP_CREDIT_UPDATE, 0)?,
+            OP_SHUTDOWN => {
+                let flags = hdr.flags;
+                if flags & SHUTDOWN_SEND != 0 {
+                    conn.peer_shutdown = true;
+                }
+                if flags & (SHUTDOWN_RECV | SHUTDOWN_SEND) == SHUTDOWN_RECV | SHUTDOWN_SEND {
+                    conn.state = State::Closed;
+                    self.send_control(id, OP_RST, 0)?;
+                }
+            }
+            OP_RST => conn.state = State::Closed,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Send a packet without payload on connection `id`.
+    fn send_control(&mut self, id: SocketId, op: u16, flags: u32) -> Result {
+        let mut hdr = self.packet_header(id, op, 0);
+        hdr.flags = flags;
+        self.send_packet(&hdr, &[])
+    }
+
+    /// Answer a packet of an unknown connection with RST.
+    fn send_reset(&mut self, to: &PacketHeader) -> Result {
+        let hdr = PacketHeader {
+            src_cid: self.guest_cid,
+            dst_cid: to.src_cid,
+            src_port: to.dst_port,
+            dst_port: to.src_port,
+            type_: TYPE_STREAM,
+            op: OP_RST,
+            ..PacketHeader::default()
+        };
+        self.send_packet(&hdr, &[])
+    }
+
+    /// The header of a packet on connection `id`, giving our credit.
+    fn packet_header(&mut self, id: SocketId, op: u16, len: u32) -> PacketHeader {
+        let guest_cid = self.guest_cid;
+        let conn = self.connections[id].as_mut().unwrap();
+        conn.fwd_cnt_sent = conn.fwd_cnt;
+        PacketHeader {
+            src_cid: guest_cid,
+            dst_cid: conn.peer_cid,
+            src_port: conn.local_port,
+            dst_port: conn.peer_port,
+            len,
+            type_: TYPE_STREAM,
+            op,
+            flags: 0,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt: conn.fwd_cnt,
+        }
+    }
+
+    /// Send a packet and wait until the device has taken it.
+    ///
+    /// If the device hangs, it is reset so it stops touching the buffers,
+    /// and the driver can't be used anymore.
+    fn send_packet(&mut self, hdr: &PacketHeader, payload: &[u8]) -> Result {
+        let token = if payload.is_empty() {
+            self.tx_queue.add(&[hdr.as_buf()], &[])?
+        } else {
+            self.tx_queue.add(&[hdr.as_buf(), payload], &[])?
+        };
+        self.tx_queue.notify(self.header);
+        let queue = &self.tx_queue;
+        if let Err(e) = hal::wait_for(TIMEOUT, false, || queue.can_pop()) {
+            self.header.reset();
+            return Err(e);
+        }
+        let (used, _len) = self.tx_queue.pop_used()?;
+        // 一次只发送一个包，设备返回的一定是它
+        assert_eq!(used, token);
+        Ok(())
+    }
+
+    /// Give a buffer to the receive queue. The caller notifies the device.
+    fn post_rx(&mut self, mut rx: Box<RxBuffer>) -> Result {
+        let RxBuffer { hdr, data } = &mut *rx;
+        let token = self.rx_queue.add(&[], &[hdr.as_buf_mut(), &mut data[..]])?;
+        self.rx_buffers[token as usize] = Some(rx);
+        Ok(())
+    }
+
+    fn insert(&mut self, conn: Connection) -> SocketId {
+        match self.connections.iter().position(Option::is_none) {
+            Some(id) => {
+                self.connections[id] = Some(conn);
+                id
+            }
+            None => {
+                self.connections.push(Some(conn));
+                self.connections.len() - 1
+            }
+        }
+    }
+
+    fn find_pending(&self, port: u32) -> Option<usize> {
+        self.pending.iter().position(|&id| match &self.connections[id] {
+            Some(conn) => conn.local_port == port,
+            None => false,
+        })
+    }
+
+    fn connection(&self, id: SocketId) -> Result<&Connection> {
+        self.connections.get(id).and_then(Option::as_ref).ok_or(Error::InvalidParam)
+    }
+
+    fn connection_mut(&mut self, id: SocketId) -> Result<&mut Connection> {
+        self.connections.get_mut(id).and_then(Option::as_mut).ok_or(Error::InvalidParam)
+    }
+}
+
+impl<'a, T: Transport> Drop for VirtIoVsock<'a, T> {
+    fn drop(&mut self) {
+        self.header.reset();
+    }
+}
+
+fn read_guest_cid<T: Transport>(header: &T) -> u64 {
+    let config = unsafe { header.config::<VsockConfig>() };
+    // 64 位配置分两次读，低位在前
+    config.guest_cid_low.read() as u64 | (config.guest_cid_high.read() as u64) << 32
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State {
+    /// Waiting for the response of the peer
+    Connecting,
+    Connected,
+    /// Reset by the peer or the device
+    Closed,
+}
+
+struct Connection {
+    local_port: u32,
+    peer_cid: u64,
+    peer_port: u32,
+    state: State,
+    /// The peer shut down its sending side
+    peer_shutdown: bool,
+    /// Received but not read by the application
+    rx: VecDeque<u8>,
+    /// Bytes read by the application
+    fwd_cnt: u32,
+    /// `fwd_cnt` in the last packet sent
+    fwd_cnt_sent: u32,
+    /// Bytes sent to the peer
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(local_port: u32, peer_cid: u64, peer_port: u32, state: State) -> Self {
+        Connection {
+            local_port,
+            peer_cid,
+            peer_port,
+            state,
+            peer_shutdown: false,
+            rx: VecDeque::new(),
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Every packet of the peer tells its buffer space.
+    fn update_credit(&mut self, hdr: &PacketHeader) {
+        self.peer_buf_alloc = hdr.buf_alloc;
+        self.peer_fwd_cnt = hdr.fwd_cnt;
+    }
+
+    /// How many bytes the peer can take now.
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    fn peer_closed(&self) -> bool {
+        self.peer_shutdown || self.state == State::Closed
+    }
+}
+
+#[repr(C)]
+struct VsockConfig {
+    guest_cid_low: RO<u32>,
+    guest_cid_high: RO<u32>,
+}
+
+/// `struct virtio_vsock_hdr`
+#[repr(C, packed)]
+#[derive(Debug, Default, Clone, Copy)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+unsafe impl AsBuf for PacketHeader {}
+
+struct RxBuffer {
+    hdr: PacketHeader,
+    data: [u8; RX_DATA_LEN],
+}
+
+impl RxBuffer {
+    fn new() -> Self {
+        RxBuffer {
+            hdr: PacketHeader::default(),
+            data: [0; RX_DATA_LEN],
+        }
+    }
+}
+
+/// `struct virtio_vsock_event`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Event {
+    id: u32,
+}
+
+unsafe impl AsBuf for Event {}
+
+const TYPE_STREAM: u16 = 1;
+
+const OP_REQUEST: u16 = 1;
+const OP_RESPONSE: u16 = 2;
+const OP_RST: u16 = 3;
+const OP_SHUTDOWN: u16 = 4;
+const OP_RW: u16 = 5;
+const OP_CREDIT_UPDATE: u16 = 6;
+const OP_CREDIT_REQUEST: u16 = 7;
+
+/// Flags of `OP_SHUTDOWN`
+const SHUTDOWN_RECV: u32 = 1;
+const SHUTDOWN_SEND: u32 = 2;
+
+const EVENT_TRANSPORT_RESET: u32 = 0;
@@ -0,0 +1,150 @@
+//! Read the board layout from the flattened device tree passed by SBI.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use device_tree::{DeviceTree, Node};
+
+use crate::mmio::{self, Slot};
+
+const DEVICE_TREE_MAGIC: u32 = 0xD00DFEED;
+
+/// What the kernel needs to know about the board.
+#[derive(Debug)]
+pub struct BoardInfo {
+    /// Physical memory region
+    pub memory: Range<usize>,
+    /// Number of harts
+    pub cpu_count: usize,
+    /// Frequency of the `time` CSR in Hz
+    pub timebase_frequency: u64,
+    /// Base address of the PLIC
+    pub plic_base: Option<usize>,
+    /// Base address of the `sifive_test` finisher
+    pub sifive_test_base: Option<usize>,
+    /// Register interfaces and interrupt lines of virtio-mmio nodes
+    pub virtio_slots: Vec<Slot>,
+}
+
+impl BoardInfo {
+    /// The layout of QEMU virt with one hart and 128 MiB memory, for when
+    /// there is no device tree.
+    pub fn qemu_virt() -> Self {
+        BoardInfo {
+            memory: 0x8000_0000..0x8800_0000,
+            cpu_count: 1,
+            timebase_frequency: 10_000_000,
+            plic_base: Some(0x0c00_0000),
+            sifive_test_base: Some(0x10_0000),
+            virtio_slots: mmio::default_slots(),
+        }
+    }
+}
+
+/// Parse the device tree at `dtb_pa`.
+///
+/// Returns `None` if there is no valid device tree there.
+pub unsafe fn parse(dtb_pa: usize) -> Option<BoardInfo> {
+    #[repr(C)]
+    struct DtbHeader { magic: u32, size: u32 }
+    let header = &*(dtb_pa as *const DtbHeader);
+    if u32::from_be(header.magic) != DEVICE_TREE_MAGIC {
+        return None;
+    }
+    let size = u32::from_be(header.size);
+    let data = core::slice::from_raw_parts(dtb_pa as *const u8, size as usize);
+    let dt = DeviceTree::load(data).ok()?;
+
+    let root = &dt.root;
+    let cells = Cells {
+        address: prop_u32(root, "#address-cells").unwrap_or(2),
+        size: prop_u32(root, "#size-cells").unwrap_or(1),
+    };
+    let mut info = BoardInfo {
+        memory: 0..0,
+        cpu_count: 0,
+        timebase_frequency: 10_000_000,
+        plic_base: None,
+        sifive_test_base: None,
+        virtio_slots: Vec::new(),
+    };
+    walk(root, cells, &mut info);
+    Some(info)
+}
+
+/// Numbers of u32 cells in `reg` addresses and sizes of child nodes.
+#[derive(Clone, Copy)]
+struct Cells {
+    address: u32,
+    size: u32,
+}
+
+fn walk(node: &Node, cells: Cells, info: &mut BoardInfo) {
+    if prop_str_eq(node, "device_type", "memory") {
+        if let Some((base, size)) = reg(node, cells) {
+            info.memory = base..base + size;
+        }
+    } else if prop_str_eq(node, "device_type", "cpu") {
+        info.cpu_count += 1;
+    } else if node.name == "cpus" {
+        if let Some(frequency) = prop_u32(node, "timebase-frequency") {
+            info.timebase_frequency = frequency as u64;
+        }
+    } else if compatible_with(node, "virtio,mmio") {
+        if let (Some((base, _)), Some(irq)) = (reg(node, cells), prop_u32(node, "interrupts")) {
+            info.virtio_slots.push(Slot { base, irq });
+        }
+    } else if compatible_with(node, "riscv,plic0") || compatible_with(node, "sifive,plic-1.0.0") {
+        if let Some((base, _)) = reg(node, cells) {
+            info.plic_base = Some(base);
+        }
+    } else if compatible_with(node, "sifive,test0") {
+        if let Some((base, _)) = reg(node, cells) {
+            info.sifive_test_base = Some(base);
+        }
+    }
+    // 子节点的 reg 格式由本节点的 #address-cells 和 #size-cells 决定
+    let child_cells = Cells {
+        address: prop_u32(node, "#address-cells").unwrap_or(cells.address),
+        size: prop_u32(node, "#size-cells").unwrap_or(cells.size),
+    };
+    for child in node.children.iter() {
+        walk(child, child_cells, info);
+    }
+}
+
+/// Read the first (address, size) pair of the `reg` property.
+fn reg(node: &Node, cells: Cells) -> Option<(usize, usize)> {
+    let raw = node.prop_raw("reg")?;
+    let address = read_cells(raw, 0, cells.address)?;
+    let size = read_cells(raw, cells.address as usize, cells.size)?;
+    Some((address as usize, size as usize))
+}
+
+fn prop_u32(node: &Node, name: &str) -> Option<u32> {
+    read_cells(node.prop_raw(name)?, 0, 1).map(|value| value as u32)
+}
+
+/// Whether one of the strings in the property equals `value`.
+fn prop_str_eq(node: &Node, name: &str, value: &str) -> bool {
+    match node.prop_raw(name) {
+        Some(raw) => raw
+            .split(|&b| b == 0)
+            .any(|s| s == value.as_bytes()),
+        None => false,
+    }
+}
+
+fn compatible_with(node: &Node, value: &str) -> bool {
+    prop_str_eq(node, "compatible", value)
+}
+
+/// Read a big-endian number of `count` u32 cells, starting at cell `index`.
+fn read_cells(raw: &[u8], index: usize, count: u32) -> Option<u64> {
+    let mut value = 0u64;
+    for i in index..index + count as usize {
+        let bytes = raw.get(i * 4..i * 4 + 4)?;
+        value = (value << 32) | u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64;
+    }
+    Some(value)
+}
@@ -0,0 +1,53 @@
+//! Exit the test kernel, telling the host whether the test passed.
+//!
+//! The QEMU virt `sifive_test` finisher carries an exit code to the QEMU
+//! process; without it, SBI SRST can still report a failure reason.
+
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sbi::{self, ResetReason, ResetType};
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+static SIFIVE_TEST_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Why the test failed, also the exit code of QEMU.
+///
+/// The codes start above the exit statuses of QEMU itself, which exits
+/// with 1 on its own errors. Keep in sync with `xtask`.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    Panic = 0x21,
+    OutOfMemory = 0x22,
+    Exception = 0x23,
+}
+
+/// Use the `sifive_test` device at `base` to exit.
+pub fn init(sifive_test_base: Option<usize>) {
+    SIFIVE_TEST_BASE.store(sifive_test_base.unwrap_or(0), Ordering::Release);
+}
+
+/// Exit telling the test passed.
+pub fn success() -> ! {
+    finish(FINISHER_PASS);
+    let _ = sbi::system_reset(ResetType::Shutdown, ResetReason::NoReason);
+    sbi::shutdown()
+}
+
+/// Exit telling the test failed.
+pub fn failure(failure: Failure) -> ! {
+    finish(FINISHER_FAIL | (failure as u32) << 16);
+    let _ = sbi::system_reset(ResetType::Shutdown, ResetReason::SystemFailure);
+    sbi::shutdown()
+}
+
+/// Write to the `sifive_test` device, which never returns if it exists.
+fn finish(value: u32) {
+    let base = SIFIVE_TEST_BASE.load(Ordering::Acquire);
+    if base != 0 {
+        unsafe { write_volatile(base as *mut u32, value) };
+    }
+}
@@ -0,0 +1,118 @@
+//! Hooks into the kernel running the drivers.
+//!
+//! Drivers need a clock, a way to wait, and interrupt routing, which only
+//! the kernel can give. The kernel installs its `Hal` with `init` before
+//! creating any driver; host tests install one of their own.
+
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::transport::Transport;
+use crate::Result;
+
+/// What the drivers need from the kernel.
+pub trait Hal: Sync {
+    /// Time since the hart is powered on.
+    fn now(&self) -> Duration;
+
+    /// Wait until `condition` holds, or fail with `Error::Timeout` after
+    /// `timeout`.
+    ///
+    /// With `sleep` the hart may sleep between interrupts, otherwise it spins.
+    fn wait_for(&self, timeout: Duration, sleep: bool, condition: &dyn Fn() -> bool) -> Result;
+
+    /// The ID of the calling hart.
+    fn hart_id(&self) -> usize;
+
+    /// Whether external interrupts are set up.
+    fn interrupt_enabled(&self) -> bool {
+        false
+    }
+
+    /// Deliver interrupt `irq` to the calling hart, and acknowledge it on
+    /// `transport`.
+    ///
+    /// # Safety
+    ///
+    /// `transport` must stay valid and in place until
+    /// `unregister_interrupt(irq)`.
+    unsafe fn register_interrupt(&self, _irq: u32, _transport: &mut (dyn Transport + 'static)) {}
+
+    /// Stop taking interrupt `irq`.
+    fn unregister_interrupt(&self, _irq: u32) {}
+
+    /// The interrupt status bits seen on `irq` since they were last taken.
+    fn interrupt_status(&self, _irq: u32) -> u32 {
+        0
+    }
+
+    /// Take the interrupt status bits `mask` seen on `irq`, return them.
+    fn take_interrupt_status(&self, _irq: u32, _mask: u32) -> u32 {
+        0
+    }
+}
+
+static HAL: Mutex<Option<&'static dyn Hal>> = Mutex::new(None);
+
+/// Install the hooks of the kernel. Only the first call takes effect.
+pub fn init(hal: &'static dyn Hal) {
+    let mut current = HAL.lock();
+    if current.is_none() {
+        *current = Some(hal);
+    }
+}
+
+fn hal() -> &'static dyn Hal {
+    // 只在锁里取出引用，等待时不持有锁
+    let hal = *HAL.lock();
+    hal.expect("hal::init not called before using a driver")
+}
+
+/// Time since the hart is powered on.
+pub fn now() -> Duration {
+    hal().now()
+}
+
+/// Wait until `condition` holds, or fail with `Error::Timeout` after `timeout`.
+///
+/// With `sleep` the hart may sleep between interrupts, otherwise it spins.
+pub fn wait_for(timeout: Duration, sleep: bool, condition: impl Fn() -> bool) -> Result {
+    hal().wait_for(timeout, sleep, &condition)
+}
+
+/// The ID of the calling hart.
+pub fn hart_id() -> usize {
+    hal().hart_id()
+}
+
+/// Whether external interrupts are set up.
+pub fn interrupt_enabled() -> bool {
+    hal().interrupt_enabled()
+}
+
+/// Deliver interrupt `irq` to the calling hart, and acknowledge it on
+/// `transport`.
+///
+/// # Safety
+///
+/// `transport` must stay valid and in place until `unregister_interrupt(irq)`.
+/// Drivers make sure of it by unregistering when they are dropped.
+pub unsafe fn register_interrupt(irq: u32, transport: &mut (dyn Transport + 'static)) {
+    hal().register_interrupt(irq, transport)
+}
+
+/// Stop taking interrupt `irq`.
+pub fn unregister_interrupt(irq: u32) {
+    hal().unregister_interrupt(irq)
+}
+
+/// The interrupt status bits seen on `irq` since they were last taken.
+pub fn interrupt_status(irq: u32) -> u32 {
+    hal().interrupt_status(irq)
+}
+
+/// Take the interrupt status bits `mask` seen on `irq`, return them.
+pub fn take_interrupt_status(irq: u32, mask: u32) -> u32 {
+    hal().take_interrupt_status(irq, mask)
+}
@@ -0,0 +1,174 @@
+//! Route external interrupts from the PLIC to the virtio devices owning them.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register::{sie, sstatus};
+use spin::Mutex;
+
+use crate::plic::{self, Plic};
+use crate::transport::Transport;
+
+static PLIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// The devices taking interrupts.
+static DEVICES: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+/// A device taking interrupts on one line.
+struct Registered {
+    irq: u32,
+    /// The hart the interrupt is delivered to
+    hart: usize,
+    /// The transport of the device, valid until `unregister`
+    transport: *mut dyn Transport,
+    /// Interrupt status bits acknowledged but not yet taken by the driver
+    status: u32,
+}
+
+// transport 只在持有 DEVICES 锁时访问，register 的调用者保证它在 unregister 之前有效
+unsafe impl Send for Registered {}
+
+/// Set up the PLIC for this hart and enable external interrupts.
+pub fn init(plic_base: usize) {
+    PLIC_BASE.store(plic_base, Ordering::Release);
+    init_hart();
+}
+
+/// Set up the PLIC context of this hart and enable external interrupts.
+///
+/// Interrupt sources are only enabled for the hart that registers them.
+pub fn init_hart() {
+    if let Some(plic) = plic() {
+        plic.set_threshold(plic::supervisor_context(crate::hart_id()), 0);
+        unsafe {
+            sie::set_sext();
+            sstatus::set_sie();
+        }
+    }
+}
+
+/// Whether external interrupts are set up.
+pub fn enabled() -> bool {
+    PLIC_BASE.load(Ordering::Acquire) != 0
+}
+
+/// Deliver interrupt `irq` to this hart, and acknowledge it on `transport`.
+///
+/// A line already registered is taken over by `transport`.
+///
+/// # Safety
+///
+/// `transport` must stay valid and in place until `unregister(irq)`.
+pub unsafe fn register(irq: u32, transport: &mut (dyn Transport + 'static)) {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let hart = crate::hart_id();
+    let previous = riscv::interrupt::free(|_| {
+        let mut devices = DEVICES.lock();
+        let previous = take(&mut devices, irq);
+        devices.push(Registered {
+            irq,
+            hart,
+            transport: transport as *mut dyn Transport,
+            status: 0,
+        });
+        previous
+    });
+    if let Some(previous) = previous {
+        plic.disable(plic::supervisor_context(previous.hart), irq);
+    }
+    plic.set_priority(irq, 1);
+    plic.enable(plic::supervisor_context(hart), irq);
+}
+
+/// Stop taking interrupt `irq`, and forget the transport it was acknowledged on.
+pub fn unregister(irq: u32) {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let registered = riscv::interrupt::free(|_| take(&mut DEVICES.lock(), irq));
+    if let Some(registered) = registered {
+        plic.disable(plic::supervisor_context(registered.hart), irq);
+    }
+}
+
+fn take(devices: &mut Vec<Registered>, irq: u32) -> Option<Registered> {
+    let index = devices.iter().position(|device| device.irq == irq)?;
+    Some(devices.swap_remove(index))
+}
+
+/// Handle a supervisor external interrupt.
+pub fn handle_external() {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let context = plic::supervisor_context(crate::hart_id());
+    let irq = plic.claim(context);
+    if irq == 0 {
+        // 其它 hart 已经认领了这个中断
+        return;
+    }
+    let mut devices = DEVICES.lock();
+    match devices.iter_mut().find(|device| device.irq == irq) {
+        Some(device) => {
+            let transport = unsafe { &mut *device.transport };
+            device.status |= transport.ack_interrupt();
+        }
+        None => {
+            println!("!! Kernel: unexpected external interrupt {}", irq);
+        }
+    }
+    drop(devices);
+    plic.complete(context, irq);
+}
+
+/// The interrupt status bits seen on `irq` since they were last taken.
+pub fn status(irq: u32) -> u32 {
+    riscv::interrupt::free(|_| {
+        DEVICES.lock().iter().find(|device| device.irq == irq).map_or(0, |device| device.status)
+    })
+}
+
+/// Take the interrupt status bits `mask` seen on `irq`, return them.
+pub fn take_status(irq: u32, mask: u32) -> u32 {
+    riscv::interrupt::free(|_| {
+        let mut devices = DEVICES.lock();
+        match devices.iter_mut().find(|device| device.irq == irq) {
+            Some(device) => {
+                let taken = device.status & mask;
+                device.status &= !mask;
+                taken
+            }
+            None => 0,
+        }
+    })
+}
+
+/// Sleep until `condition` holds, waking up on every interrupt.
+///
+/// Interrupts stay masked between the check and `wfi`, so one arriving in
+/// between still wakes the hart up instead of being missed.
+pub fn wait_until(condition: impl Fn() -> bool) {
+    loop {
+        unsafe { sstatus::clear_sie() };
+        if condition() {
+            unsafe { sstatus::set_sie() };
+            return;
+        }
+        unsafe {
+            riscv::asm::wfi();
+            sstatus::set_sie();
+        }
+    }
+}
+
+fn plic() -> Option<Plic> {
+    match PLIC_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(Plic::new(base)),
+    }
+}
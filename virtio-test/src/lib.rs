@@ -0,0 +1,49 @@
+//! Virtio drivers over virtio-mmio, shared by the test kernel and host tests.
+//!
+//! Nothing here touches the hardware of the board directly: devices are
+//! reached through a `Transport`, and the kernel services drivers need
+//! through `hal`. So the drivers also build for the host, where `cargo
+//! test` runs them against a mock device.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod device;
+pub mod hal;
+pub mod mmio;
+pub mod queue;
+pub mod transport;
+
+#[cfg(test)]
+mod mock;
+
+/// The error type of virtio drivers.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The buffer is too small.
+    BufferTooSmall,
+    /// The device is not ready.
+    NotReady,
+    /// The queue is already in use.
+    AlreadyUsed,
+    /// Invalid parameter.
+    InvalidParam,
+    /// Failed to alloc DMA memory.
+    DmaError,
+    /// The heap ran out of memory.
+    NoMemory,
+    /// The device refused the features accepted by the driver.
+    FeaturesNotAccepted,
+    /// I/O error reported by the device.
+    IoError,
+    /// The request is not supported by the device.
+    Unsupported,
+    /// The device did not respond in time.
+    Timeout,
+    /// The file server failed with a linux errno.
+    Errno(u32),
+}
+
+/// The result type of virtio drivers.
+pub type Result<T = ()> = core::result::Result<T, Error>;
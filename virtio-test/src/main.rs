@@ -3,12 +3,168 @@
 #![no_std]
 #![no_main]
 
-mod mmio;
+extern crate alloc;
+
+#[macro_use]
+mod console {
+    use super::sbi::*;
+    use crate::clock;
+    use crate::device::console::VirtIoConsole;
+    use crate::mmio::VirtIoHeader;
+    use crate::{Error, Result};
+    use core::fmt::{self, Write};
+    use core::hint::spin_loop;
+    use core::time::Duration;
+    use spin::Mutex;
+
+    struct Stdout {
+        /// Once set, input and output go through it instead of SBI
+        virtio: Option<VirtIoConsole<'static, VirtIoHeader>>,
+    }
+
+    /// The SBI console, which xtask watches.
+    struct Sbi;
+
+    impl Write for Sbi {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let mut buffer = [0u8; 4];
+            for c in s.chars() {
+                for code_point in c.encode_utf8(&mut buffer).as_bytes().iter() {
+                    console_putchar(*code_point as usize);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Write for Stdout {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            if let Some(console) = self.virtio.as_mut() {
+                if console.send(s.as_bytes()).is_ok() {
+                    return Ok(());
+                }
+                // 设备出错后退回到 SBI，免得错误信息也打印不出来
+                self.virtio = None;
+            }
+            Sbi.write_str(s)
+        }
+    }
+
+    #[allow(unused)]
+    pub fn print(args: fmt::Arguments) {
+        STDOUT.lock().write_fmt(args).unwrap();
+    }
+
+    /// Print on the SBI console even after `use_virtio`, as xtask only
+    /// watches that one for markers.
+    pub fn print_host(args: fmt::Arguments) {
+        let _stdout = STDOUT.lock();
+        Sbi.write_fmt(args).unwrap();
+    }
+
+    /// Print on the SBI console without taking the lock, which the panicking
+    /// code may hold, e.g. in the middle of a virtio console send.
+    pub fn print_panic(args: fmt::Arguments) {
+        let _ = Sbi.write_fmt(args);
+    }
+
+    /// Switch `print!` and `read_line` over to a virtio console.
+    pub fn use_virtio(console: VirtIoConsole<'static, VirtIoHeader>) {
+        STDOUT.lock().virtio = Some(console);
+    }
+
+    /// Read a line into `buf` without the line break, return its length.
+    ///
+    /// Fails with `Error::Timeout` if no line break arrives in `timeout`.
+    pub fn read_line(buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        read_line_with(buf, timeout, getchar)
+    }
+
+    /// Read a line from the SBI console like `read_line`, for the answers
+    /// of xtask.
+    pub fn read_host_line(buf: &mut [u8], timeout: Duration) -> Result<usize> {
+        read_line_with(buf, timeout, sbi_getchar)
+    }
+
+    fn read_line_with(buf: &mut [u8], timeout: Duration, getchar: fn() -> Option<u8>) -> Result<usize> {
+        let deadline = clock::now() + timeout;
+        let mut len = 0;
+        while clock::now() < deadline {
+            let byte = match getchar() {
+                Some(byte) => byte,
+                None => {
+                    spin_loop();
+                    continue;
+                }
+            };
+            if byte == b'\n' || byte == b'\r' {
+                return Ok(len);
+            }
+            if len == buf.len() {
+                return Err(Error::BufferTooSmall);
+            }
+            buf[len] = byte;
+            len += 1;
+        }
+        Err(Error::Timeout)
+    }
+
+    fn getchar() -> Option<u8> {
+        let mut stdout = STDOUT.lock();
+        match stdout.virtio.as_mut() {
+            Some(console) => {
+                let mut byte = [0u8];
+                match console.recv(&mut byte) {
+                    Ok(1) => Some(byte[0]),
+                    _ => None,
+                }
+            }
+            None => sbi_getchar(),
+        }
+    }
+
+    fn sbi_getchar() -> Option<u8> {
+        // 没有输入时返回 -1
+        match console_getchar() {
+            c if c <= 0xff => Some(c as u8),
+            _ => None,
+        }
+    }
+
+    lazy_static::lazy_static! {
+        static ref STDOUT: Mutex<Stdout> = Mutex::new(Stdout { virtio: None });
+    }
+
+    #[macro_export]
+    macro_rules! print {
+        ($fmt: literal $(, $($arg: tt)+)?) => {
+            $crate::console::print(format_args!($fmt $(, $($arg)+)?));
+        }
+    }
+
+    #[macro_export]
+    macro_rules! println {
+        ($fmt: literal $(, $($arg: tt)+)?) => {
+            $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
+        }
+    }
+}
+
+mod clock;
+mod dtb;
+mod exit;
+mod interrupt;
+mod plic;
+mod random;
+mod trap;
+
+use virtio_test::{device, hal, mmio, transport, Error, Result};
 
 use linked_list_allocator::LockedHeap;
 
 use core::mem::MaybeUninit;
-const KERNEL_HEAP_SIZE: usize = 64 * 1024;
+// virtio-gpu 默认 1280x800 的帧缓冲就要 4 MB
+const KERNEL_HEAP_SIZE: usize = 8 * 1024 * 1024;
 static mut HEAP_SPACE: MaybeUninit<[u8; KERNEL_HEAP_SIZE]> = MaybeUninit::uninit();
 
 #[global_allocator]
@@ -20,53 +176,900 @@ unsafe fn init_heap() {
     )
 }
 
+/// Memory statistics of the kernel heap.
+fn heap_stats() -> MemStats {
+    let heap = ALLOCATOR.lock();
+    MemStats {
+        total: heap.size() as u64,
+        free: heap.free() as u64,
+        available: heap.free() as u64,
+    }
+}
+
 #[allow(unused)]
 #[cfg_attr(not(test), alloc_error_handler)]
 fn oom(layout: core::alloc::Layout) -> ! {
     println!("!! Out of memory: {:?}", layout);
     println!("!! Kernel: Test failed due to out of memory");
-    sbi::shutdown()
+    exit::failure(exit::Failure::OutOfMemory)
 }
 
-use riscv::register::stvec::{self, TrapMode};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use device::balloon::{MemStats, VirtIoBalloon};
+use device::blk::{VirtIoBlk, SECTOR_SIZE};
+use device::console::VirtIoConsole;
+use device::crypto::VirtIoCrypto;
+use device::fs::VirtIoFs;
+use device::gpu::VirtIoGpu;
+use device::input::{InputEvent, VirtIoInput};
+use device::net::VirtIoNet;
+use device::p9::{P9Client, VirtIo9p};
+use device::rng::VirtIoRng;
+use device::sound::VirtIoSound;
+use device::vsock::VirtIoVsock;
+use mmio::VirtIoHeader;
+
+/// Set by the first hart entering `rust_main`. Lives in `.data` so that
+/// clearing BSS doesn't reset it.
+#[link_section = ".data"]
+static BOOT_HART_ELECTED: AtomicBool = AtomicBool::new(false);
+
+/// Number of harts that have finished `hart_main`
+static FINISHED_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// virtio-blk driver shared by the per-hart tests
+static BLK: spin::Mutex<Option<VirtIoBlk<'static, VirtIoHeader>>> = spin::Mutex::new(None);
 
 pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
+    if BOOT_HART_ELECTED.swap(true, Ordering::AcqRel) {
+        // 老的 SBI 实现会让所有 hart 都从 _start 进来，只留第一个，
+        // 其余的停下来，稍后由启动 hart 通过 HSM 唤醒
+        park()
+    }
     extern "C" { fn sbss(); fn ebss();/* fn ekernel(); */}
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
     println!("<< Kernel: Hart id = {}, DTB physical address = {:#x}", hartid, dtb_pa);
+    println!(
+        "<< Kernel: SBI spec version = {:#x}, hart status = {:?}",
+        sbi::get_spec_version(), sbi::hart_get_status(hartid)
+    );
     unsafe { init_heap() };
-    unsafe { stvec::write(start_trap as usize, TrapMode::Direct) };
+    trap::init();
     
-    // unsafe { dump_dtb(dtb_pa) };
+    let board = unsafe { dtb::parse(dtb_pa) }.unwrap_or_else(|| {
+        println!("<< Kernel: no valid device tree, assume QEMU virt layout");
+        dtb::BoardInfo::qemu_virt()
+    });
+    println!(
+        "<< Kernel: memory = {:#x?}, {} cpu(s), PLIC at {:#x?}",
+        board.memory, board.cpu_count, board.plic_base
+    );
+    exit::init(board.sifive_test_base);
+    clock::init(board.timebase_frequency);
+    if let Some(plic_base) = board.plic_base {
+        interrupt::init(plic_base);
+    }
+    hal::init(&KernelHal);
+    let start = clock::now();
+    clock::sleep(Duration::from_millis(10));
+    println!("<< Kernel: slept {:?}", clock::now() - start);
+    let devices = mmio::probe(&board.virtio_slots);
+    for device in &devices {
+        println!(
+            "<< Kernel: virtio-mmio@{:#x}: {:?}, vendor = {:#x}, version = {}, irq = {}",
+            device.base, device.device_type, device.vendor_id, device.version, device.irq
+        );
+    }
+    for device in &devices {
+        match device.device_type {
+            mmio::DeviceType::Block => test_blk(device),
+            mmio::DeviceType::Network => test_net(device),
+            mmio::DeviceType::Console => test_console(device),
+            mmio::DeviceType::EntropySource => test_rng(device),
+            mmio::DeviceType::Gpu => test_gpu(device),
+            mmio::DeviceType::Input => test_input(device),
+            mmio::DeviceType::MemoryBallooning => test_balloon(device),
+            mmio::DeviceType::_9P => test_9p(device),
+            mmio::DeviceType::Socket => test_vsock(device),
+            mmio::DeviceType::FileSystem => test_fs(device),
+            mmio::DeviceType::Crypto => test_crypto(device),
+            mmio::DeviceType::Sound => test_sound(device),
+            _ => {}
+        }
+    }
 
-    
-    let header = unsafe {
-        &mut *(0x10001000 as *mut mmio::VirtIoHeader)
-    };
-    println!("Verify = {}", header.verify());
+    let started = start_secondary_harts(hartid, board.cpu_count);
+    hart_main(hartid);
+    clock::wait_for(Duration::from_secs(10), false, || {
+        FINISHED_HARTS.load(Ordering::Acquire) == started + 1
+    })
+    .expect("wait for secondary harts");
 
     println!("<< Kernel: test SUCCESS, shutdown");
-    sbi::shutdown()
+    exit::success()
+}
+
+/// The most harts `BOOT_STACK` has room for.
+const MAX_HARTS: usize = 8;
+
+/// Start every other hart through SBI HSM, return how many have started.
+fn start_secondary_harts(boot_hartid: usize, cpu_count: usize) -> usize {
+    let mut started = 0;
+    for hartid in (0..cpu_count.min(MAX_HARTS)).filter(|&id| id != boot_hartid) {
+        match sbi::hart_start(hartid, secondary_entry as usize, 0) {
+            Ok(()) => started += 1,
+            Err(e) => {
+                println!("<< Kernel: can't start hart {}: {:?}", hartid, e);
+            }
+        }
+    }
+    started
+}
+
+extern "C" fn rust_main_secondary(hartid: usize, _opaque: usize) -> ! {
+    trap::init();
+    clock::init_hart();
+    interrupt::init_hart();
+    hart_main(hartid);
+    park()
+}
+
+/// The test every hart runs after boot.
+///
+/// All harts submit requests to the same virtio-blk queue.
+fn hart_main(hartid: usize) {
+    const ROUNDS: usize = 16;
+    let mut first = [0u8; SECTOR_SIZE];
+    let mut buf = [0u8; SECTOR_SIZE];
+    for round in 0..ROUNDS {
+        let mut blk = BLK.lock();
+        let blk = match blk.as_mut() {
+            Some(blk) => blk,
+            None => break,
+        };
+        let sector = hartid as u64 % blk.capacity();
+        let target = if round == 0 { &mut first } else { &mut buf };
+        blk.read_sectors(sector, target).expect("read sector on hart");
+        if round != 0 {
+            assert_eq!(&first[..], &buf[..], "hart {} read back mismatch", hartid);
+        }
+    }
+    println!("<< Kernel: hart {} finished", hartid);
+    FINISHED_HARTS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Stop this hart so that it can be started again through HSM.
+fn park() -> ! {
+    let _ = sbi::hart_stop();
+    loop {
+        unsafe { riscv::asm::wfi() };
+    }
+}
+
+fn test_blk(device: &mmio::DeviceInfo) {
+    use device::blk::ID_BYTES;
+    let mut blk = VirtIoBlk::new(unsafe { device.header() }).expect("create virtio-blk driver");
+    blk.use_interrupt(device.irq);
+    println!(
+        "<< Kernel: virtio-blk capacity = {} sectors, blk_size = {}, seg_max = {}",
+        blk.capacity(), blk.blk_size(), blk.seg_max()
+    );
+    let mut id = [0u8; ID_BYTES];
+    match blk.get_id(&mut id) {
+        Ok(len) => {
+            println!("<< Kernel: virtio-blk id = {:?}", core::str::from_utf8(&id[..len]));
+        }
+        Err(e) => {
+            println!("<< Kernel: virtio-blk get id: {:?}", e);
+        }
+    }
+    // 测试最后一个扇区，测完写回原来的数据，不改变磁盘镜像
+    let sector = blk.capacity() - 1;
+    let mut origin = [0u8; SECTOR_SIZE];
+    blk.read_sectors(sector, &mut origin).expect("read sector");
+    let mut pattern = [0u8; SECTOR_SIZE];
+    for (i, byte) in pattern.iter_mut().enumerate() {
+        *byte = (i as u8) ^ 0x5a;
+    }
+    blk.write_sectors(sector, &pattern).expect("write sector");
+    if let Err(e) = blk.flush() {
+        println!("<< Kernel: virtio-blk flush: {:?}", e);
+    }
+    let mut readback = [0u8; SECTOR_SIZE];
+    blk.read_sectors(sector, &mut readback).expect("read sector back");
+    blk.write_sectors(sector, &origin).expect("restore sector");
+    assert_eq!(&pattern[..], &readback[..], "virtio-blk read back mismatch");
+    println!("<< Kernel: virtio-blk read back sector {} OK", sector);
+    *BLK.lock() = Some(blk);
+}
+
+/// Colors of the four quadrants drawn by `test_gpu`, as R, G, B.
+///
+/// Keep in sync with `QUADRANT_COLORS` in xtask
+const QUADRANT_COLORS: [[u8; 3]; 4] = [[0xff, 0, 0], [0, 0xff, 0], [0, 0, 0xff], [0xff, 0xff, 0xff]];
+
+/// Paint the framebuffer in four colored quadrants and show a cursor.
+///
+/// Top left, top right, bottom left, bottom right follow `QUADRANT_COLORS`.
+fn test_gpu(device: &mmio::DeviceInfo) {
+    use device::gpu::{BYTES_PER_PIXEL, CURSOR_SIZE};
+    let mut gpu = VirtIoGpu::new(unsafe { device.header() }).expect("create virtio-gpu driver");
+    let (width, height) = gpu.resolution();
+    println!("<< Kernel: virtio-gpu {} scanout(s), resolution = {}x{}", gpu.num_scanouts(), width, height);
+    let fb = gpu.setup_framebuffer().expect("set up framebuffer");
+    for (i, pixel) in fb.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let quadrant = (y >= height / 2) as usize * 2 + (x >= width / 2) as usize;
+        let [r, g, b] = QUADRANT_COLORS[quadrant];
+        pixel.copy_from_slice(&[b, g, r, 0xff]);
+    }
+    gpu.flush().expect("flush framebuffer");
+    // 半透明的灰色方块光标，热点在中心
+    let cursor = alloc::vec![0x80u8; (CURSOR_SIZE * CURSOR_SIZE) as usize * BYTES_PER_PIXEL];
+    let hot = CURSOR_SIZE / 2;
+    gpu.setup_cursor(&cursor, width / 2, height / 2, hot, hot).expect("set up cursor");
+    gpu.move_cursor(width / 4, height / 4).expect("move cursor");
+    if !wait_for_host("<< Kernel: virtio-gpu frame ready") {
+        println!("<< Kernel: virtio-gpu frame not checked by host");
+    }
+}
+
+/// Describe the input device, then log the events the host injects.
+fn test_input(device: &mmio::DeviceInfo) {
+    use device::input::{EV_ABS, EV_KEY, EV_REL};
+    /// ABS_X in linux input-event-codes.h
+    const ABS_X: u8 = 0x00;
+    let mut input = VirtIoInput::new(unsafe { device.header() }).expect("create virtio-input driver");
+    input.use_interrupt(device.irq);
+    println!(
+        "<< Kernel: virtio-input {:?} serial {:?}, key = {}, rel = {}, abs = {}",
+        input.name(), input.serial(), input.supports(EV_KEY), input.supports(EV_REL), input.supports(EV_ABS)
+    );
+    if let Some(info) = input.abs_info(ABS_X) {
+        println!("<< Kernel: virtio-input ABS_X = {:?}", info);
+    }
+    // 鼠标和触摸板也报告按键，按最具体的那类事件请主机注入
+    let kind = if input.supports(EV_ABS) {
+        "abs"
+    } else if input.supports(EV_REL) {
+        "rel"
+    } else {
+        "key"
+    };
+    let acked = wait_for_host(&format!("<< Kernel: virtio-input ready for {} events", kind));
+    let mut count = 0;
+    // 主机注入的事件在应答之前就已经到了，等到 100ms 内没有新事件为止
+    while input.wait_event(Duration::from_millis(100)).is_ok() {
+        while let Some(event) = input.pop_event() {
+            if event != InputEvent::Syn {
+                println!("<< Kernel: virtio-input event {:?}", event);
+                count += 1;
+            }
+        }
+    }
+    if acked {
+        assert!(count > 0, "virtio-input: no event from host");
+    }
+}
+
+/// Let the host inflate the balloon with heap pages, then deflate it.
+fn test_balloon(device: &mmio::DeviceInfo) {
+    let mut balloon = VirtIoBalloon::new(unsafe { device.header() }).expect("create virtio-balloon driver");
+    balloon.use_interrupt(device.irq);
+    println!("<< Kernel: virtio-balloon target = {} pages, heap {:?}", balloon.target_pages(), heap_stats());
+    let steps = [
+        ("inflate", "<< Kernel: virtio-balloon ready to inflate"),
+        ("deflate", "<< Kernel: virtio-balloon ready to deflate"),
+    ];
+    for (step, marker) in &steps {
+        if !wait_for_host(marker) {
+            println!("<< Kernel: virtio-balloon not driven by host");
+            return;
+        }
+        // 主机修改目标后会发配置变更中断
+        balloon.wait_config_change(HOST_TIMEOUT).expect("wait for virtio-balloon config change");
+        let actual = balloon.update().expect("update virtio-balloon");
+        assert_eq!(actual, balloon.target_pages(), "virtio-balloon can't {} to target", step);
+        println!("<< Kernel: virtio-balloon {} to {} pages, heap {:?}", step, actual, heap_stats());
+        if let Ok(true) = balloon.serve_stats(&heap_stats()) {
+            println!("<< Kernel: virtio-balloon stats sent");
+        }
+    }
+}
+
+/// The file xtask creates in the shared directory for the kernel to write.
+///
+/// Keep in sync with `OUTPUT_FILE` in xtask
+const P9_OUTPUT_FILE: &str = "virtio-9p-output.txt";
+const P9_OUTPUT: &[u8] = b"hello from virtio-9p\n";
+
+/// List the directory shared by the host, read the first regular file in
+/// it, and write to the output file if xtask created one.
+fn test_9p(device: &mmio::DeviceInfo) {
+    use device::p9::{DT_DIR, DT_REG, O_RDONLY, O_TRUNC, O_WRONLY};
+    let p9 = VirtIo9p::new(unsafe { device.header() }).expect("create virtio-9p driver");
+    println!("<< Kernel: virtio-9p mount tag = {:?}", p9.mount_tag());
+    let mut client = P9Client::new(p9).expect("negotiate 9P2000.L");
+    let root = client.attach("root", "").expect("attach 9P root");
+    let dir = client.walk(root, &[]).expect("clone 9P root");
+    client.lopen(dir, O_RDONLY).expect("open 9P root");
+    let mut file = None;
+    let mut offset = 0;
+    loop {
+        let entries = client.readdir(dir, offset).expect("read 9P directory");
+        let last = match entries.last() {
+            Some(last) => last.offset,
+            None => break,
+        };
+        for entry in &entries {
+            let slash = if entry.kind == DT_DIR { "/" } else { "" };
+            println!("<< Kernel: virtio-9p entry {}{}, qid path = {:#x}", entry.name, slash, entry.qid.path);
+            if file.is_none() && entry.kind == DT_REG && entry.name != P9_OUTPUT_FILE {
+                file = Some(entry.name.clone());
+            }
+        }
+        offset = last;
+    }
+    client.clunk(dir).expect("clunk 9P directory");
+    if let Some(name) = file {
+        let fid = client.walk(root, &[&name]).expect("walk to 9P file");
+        let qid = client.lopen(fid, O_RDONLY).expect("open 9P file");
+        println!("<< Kernel: virtio-9p open {:?}, qid = {:#x}/{}/{}", name, qid.path, qid.version, qid.kind);
+        let mut buf = [0u8; 64];
+        let len = client.read(fid, 0, &mut buf).expect("read 9P file");
+        println!("<< Kernel: virtio-9p {:?} starts with {:?}", name, core::str::from_utf8(&buf[..len]));
+        client.clunk(fid).expect("clunk 9P file");
+    }
+    // 只有 xtask 事先建好输出文件时才写，不在别人的目录里创建文件
+    if let Ok(fid) = client.walk(root, &[P9_OUTPUT_FILE]) {
+        client.lopen(fid, O_WRONLY | O_TRUNC).expect("open 9P output file");
+        let len = client.write(fid, 0, P9_OUTPUT).expect("write 9P output file");
+        assert_eq!(len, P9_OUTPUT.len(), "virtio-9p short write");
+        client.clunk(fid).expect("clunk 9P output file");
+        println!("<< Kernel: virtio-9p wrote {} bytes to {:?}", len, P9_OUTPUT_FILE);
+    }
+    client.clunk(root).expect("clunk 9P root");
+}
+
+/// The tone played over virtio-sound, 100 frames per cycle.
+///
+/// Keep in sync with `TONE_HZ` and `AMPLITUDE` in xtask
+const SOUND_TONE_HZ: u32 = 441;
+const SOUND_RATE_HZ: u32 = 44100;
+const SOUND_AMPLITUDE: f64 = 16383.0;
+
+/// Play one second of a sine tone on the first stereo output stream.
+fn test_sound(device: &mmio::DeviceInfo) {
+    use device::sound::{PcmParams, SoundEvent, DIRECTION_OUTPUT, PCM_FMT_S16, PCM_RATE_44100};
+    /// 10ms per period
+    const PERIOD_FRAMES: usize = 441;
+    const PERIODS: usize = 100;
+    /// Two channels of 16-bit samples
+    const FRAME_BYTES: usize = 4;
+    let mut sound = VirtIoSound::new(unsafe { device.header() }).expect("create virtio-sound driver");
+    let (jacks, streams, chmaps) = sound.counts();
+    println!("<< Kernel: virtio-sound {} jack(s), {} stream(s), {} chmap(s)", jacks, streams, chmaps);
+    for jack in sound.jack_info().expect("query virtio-sound jacks") {
+        println!("<< Kernel: virtio-sound jack {:#x}, connected = {}", jack.hda_reg_defconf, jack.connected);
+    }
+    for chmap in sound.chmap_info().expect("query virtio-sound channel maps") {
+        let positions = &chmap.positions[..(chmap.channels as usize).min(chmap.positions.len())];
+        println!("<< Kernel: virtio-sound chmap direction = {}, positions = {:?}", chmap.direction, positions);
+    }
+    let pcms = sound.pcm_info().expect("query virtio-sound streams");
+    let stream = pcms
+        .iter()
+        .position(|pcm| {
+            pcm.direction == DIRECTION_OUTPUT
+                && pcm.formats & (1 << PCM_FMT_S16) != 0
+                && pcm.rates & (1 << PCM_RATE_44100) != 0
+                && pcm.channels_min <= 2
+                && pcm.channels_max >= 2
+        })
+        .expect("no virtio-sound output stream for 16-bit stereo at 44100 Hz") as u32;
+    let period_bytes = (PERIOD_FRAMES * FRAME_BYTES) as u32;
+    let params = PcmParams {
+        buffer_bytes: period_bytes * 8,
+        period_bytes,
+        channels: 2,
+        format: PCM_FMT_S16,
+        rate: PCM_RATE_44100,
+    };
+    sound.pcm_set_params(stream, params).expect("set virtio-sound params");
+    sound.pcm_prepare(stream).expect("prepare virtio-sound stream");
+    let cycle = sine_cycle((SOUND_RATE_HZ / SOUND_TONE_HZ) as usize);
+    let mut period = [0u8; PERIOD_FRAMES * FRAME_BYTES];
+    for i in 0..PERIODS {
+        for (j, frame) in period.chunks_exact_mut(FRAME_BYTES).enumerate() {
+            let sample = cycle[(i * PERIOD_FRAMES + j) % cycle.len()].to_le_bytes();
+            frame.copy_from_slice(&[sample[0], sample[1], sample[0], sample[1]]);
+        }
+        sound.pcm_write(stream, &period).expect("write virtio-sound frames");
+        // 先填进去几个周期再开始播放，免得一开始就欠载
+        if i == 3 {
+            sound.pcm_start(stream).expect("start virtio-sound stream");
+        }
+    }
+    sound.pcm_drain().expect("drain virtio-sound stream");
+    sound.pcm_stop(stream).expect("stop virtio-sound stream");
+    sound.pcm_release(stream).expect("release virtio-sound stream");
+    let (mut elapsed, mut xruns) = (0, 0);
+    while let Some(event) = sound.pop_event() {
+        match event {
+            SoundEvent::PcmPeriodElapsed { .. } => elapsed += 1,
+            SoundEvent::PcmXrun { .. } => xruns += 1,
+            _ => {
+                println!("<< Kernel: virtio-sound event {:?}", event);
+            }
+        }
+    }
+    println!(
+        "<< Kernel: virtio-sound played {} periods on stream {}, {} elapsed, {} xrun(s)",
+        PERIODS, stream, elapsed, xruns
+    );
+}
+
+/// One cycle of a sine wave in `len` samples of `SOUND_AMPLITUDE`.
+fn sine_cycle(len: usize) -> Vec<i16> {
+    use core::f64::consts::PI;
+    (0..len)
+        .map(|i| {
+            // 先换到 [-π, π]，泰勒级数在这个范围内收敛得快
+            let mut x = 2.0 * PI * i as f64 / len as f64;
+            if x > PI {
+                x -= 2.0 * PI;
+            }
+            let (mut term, mut sum) = (x, x);
+            for k in 1..12 {
+                term *= -x * x / ((2 * k) * (2 * k + 1)) as f64;
+                sum += term;
+            }
+            (sum * SOUND_AMPLITUDE) as i16
+        })
+        .collect()
+}
+
+/// A known answer of AES-CBC: key, iv, plaintext and ciphertext.
+struct CbcVector {
+    name: &'static str,
+    key: &'static [u8],
+    iv: [u8; 16],
+    plaintext: [u8; 64],
+    ciphertext: [u8; 64],
+}
+
+/// The four blocks of plaintext in NIST SP800-38A, F.2
+const SP800_38A_PLAINTEXT: [u8; 64] = [
+    0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a,
+    0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51,
+    0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb, 0xc1, 0x19, 0x1a, 0x0a, 0x52, 0xef,
+    0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17, 0xad, 0x2b, 0x41, 0x7b, 0xe6, 0x6c, 0x37, 0x10,
+];
+
+const SP800_38A_IV: [u8; 16] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+];
+
+/// CBC-AES128 and CBC-AES256 of NIST SP800-38A, F.2.1 and F.2.5
+const CBC_VECTORS: [CbcVector; 2] = [
+    CbcVector {
+        name: "CBC-AES128",
+        key: &[
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+        ],
+        iv: SP800_38A_IV,
+        plaintext: SP800_38A_PLAINTEXT,
+        ciphertext: [
+            0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9, 0x19, 0x7d,
+            0x50, 0x86, 0xcb, 0x9b, 0x50, 0x72, 0x19, 0xee, 0x95, 0xdb, 0x11, 0x3a, 0x91, 0x76, 0x78, 0xb2,
+            0x73, 0xbe, 0xd6, 0xb8, 0xe3, 0xc1, 0x74, 0x3b, 0x71, 0x16, 0xe6, 0x9e, 0x22, 0x22, 0x95, 0x16,
+            0x3f, 0xf1, 0xca, 0xa1, 0x68, 0x1f, 0xac, 0x09, 0x12, 0x0e, 0xca, 0x30, 0x75, 0x86, 0xe1, 0xa7,
+        ],
+    },
+    CbcVector {
+        name: "CBC-AES256",
+        key: &[
+            0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d, 0x77, 0x81,
+            0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3, 0x09, 0x14, 0xdf, 0xf4,
+        ],
+        iv: SP800_38A_IV,
+        plaintext: SP800_38A_PLAINTEXT,
+        ciphertext: [
+            0xf5, 0x8c, 0x4c, 0x04, 0xd6, 0xe5, 0xf1, 0xba, 0x77, 0x9e, 0xab, 0xfb, 0x5f, 0x7b, 0xfb, 0xd6,
+            0x9c, 0xfc, 0x4e, 0x96, 0x7e, 0xdb, 0x80, 0x8d, 0x67, 0x9f, 0x77, 0x7b, 0xc6, 0x70, 0x2c, 0x7d,
+            0x39, 0xf2, 0x33, 0x69, 0xa9, 0xd9, 0xba, 0xcf, 0xa5, 0x30, 0xe2, 0x63, 0x04, 0x23, 0x14, 0x61,
+            0xb2, 0xeb, 0x05, 0xe2, 0xc3, 0x9b, 0xe9, 0xfc, 0xda, 0x6c, 0x19, 0x07, 0x8c, 0x6a, 0x9d, 0x1b,
+        ],
+    },
+];
+
+/// Check the device against the known answers, in both directions and on
+/// every data queue.
+fn test_crypto(device: &mmio::DeviceInfo) {
+    use device::crypto::CipherOp;
+    let mut crypto = VirtIoCrypto::new(unsafe { device.header() }).expect("create virtio-crypto driver");
+    println!(
+        "<< Kernel: virtio-crypto {} data queue(s), aes-cbc = {}",
+        crypto.num_data_queues(), crypto.supports_aes_cbc()
+    );
+    for vector in &CBC_VECTORS {
+        let directions = [
+            (CipherOp::Encrypt, &vector.plaintext, &vector.ciphertext),
+            (CipherOp::Decrypt, &vector.ciphertext, &vector.plaintext),
+        ];
+        for (op, src, expected) in &directions {
+            let session = crypto.create_session(vector.key, *op).expect("create virtio-crypto session");
+            // 轮流提交，每个数据队列都跑一遍
+            for _ in 0..crypto.num_data_queues() {
+                let mut dst = [0u8; 64];
+                let queue = crypto.cipher(&session, &vector.iv, &src[..], &mut dst).expect("virtio-crypto cipher");
+                assert_eq!(&dst[..], &expected[..], "virtio-crypto {} {:?} on data queue {}", vector.name, op, queue);
+            }
+            crypto.destroy_session(session).expect("destroy virtio-crypto session");
+        }
+        println!("<< Kernel: virtio-crypto {} known answers passed", vector.name);
+    }
+}
+
+/// The file xtask puts in the directory shared over virtio-fs.
+///
+/// Keep in sync with `FIXTURE_FILE` in xtask
+const FS_FIXTURE_FILE: &str = "virtio-fs-fixture.txt";
+const FS_FIXTURE: &[u8] = b"hello from virtio-fs\n";
+
+/// List the directory shared by the host, then read the fixture if xtask
+/// put one there.
+fn test_fs(device: &mmio::DeviceInfo) {
+    use device::fs::{DT_DIR, O_RDONLY, ROOT_ID};
+    let mut fs = VirtIoFs::new(unsafe { device.header() }).expect("create virtio-fs driver");
+    println!("<< Kernel: virtio-fs tag = {:?}, root {:?}", fs.tag(), fs.getattr(ROOT_ID));
+    let dir = fs.opendir(ROOT_ID).expect("open virtio-fs root");
+    let mut offset = 0;
+    loop {
+        let entries = fs.readdirplus(ROOT_ID, dir, offset).expect("read virtio-fs directory");
+        let last = match entries.last() {
+            Some(last) => last.offset,
+            None => break,
+        };
+        for entry in &entries {
+            let slash = if entry.kind == DT_DIR { "/" } else { "" };
+            println!("<< Kernel: virtio-fs entry {}{}, size = {}", entry.name, slash, entry.entry.attr.size);
+            // readdirplus 对每个有节点号的项都算了一次 lookup
+            if entry.entry.nodeid != 0 {
+                fs.forget(entry.entry.nodeid, 1).expect("forget virtio-fs node");
+            }
+        }
+        offset = last;
+    }
+    fs.releasedir(ROOT_ID, dir).expect("release virtio-fs root");
+    let entry = match fs.lookup(ROOT_ID, FS_FIXTURE_FILE) {
+        Ok(entry) => entry,
+        Err(_) => {
+            println!("<< Kernel: virtio-fs no fixture from host");
+            return;
+        }
+    };
+    let attr = fs.getattr(entry.nodeid).expect("get virtio-fs fixture attributes");
+    let fh = fs.open(entry.nodeid, O_RDONLY).expect("open virtio-fs fixture");
+    let mut buf = [0u8; 64];
+    let len = fs.read(entry.nodeid, fh, 0, &mut buf).expect("read virtio-fs fixture");
+    fs.release(entry.nodeid, fh).expect("release virtio-fs fixture");
+    fs.forget(entry.nodeid, 1).expect("forget virtio-fs fixture");
+    println!("<< Kernel: virtio-fs read {:?} from {:?}, mode = {:#o}", core::str::from_utf8(&buf[..len]), FS_FIXTURE_FILE, attr.mode);
+    assert_eq!(attr.size as usize, FS_FIXTURE.len(), "virtio-fs fixture size");
+    assert_eq!(&buf[..len], FS_FIXTURE, "virtio-fs fixture content");
 }
 
-// unsafe fn dump_dtb(dtb_pa: usize) {
-//     const DEVICE_TREE_MAGIC: u32 = 0xD00DFEED;
-//     #[repr(C)]
-//     struct DtbHeader { magic: u32, size: u32 }
-//     let header = &*(dtb_pa as *const DtbHeader);
-//     let magic = u32::from_be(header.magic);
-//     if magic == DEVICE_TREE_MAGIC {
-//         let size = u32::from_be(header.size);
-//         // 拷贝数据，加载并遍历
-//         let data = core::slice::from_raw_parts(dtb_pa as *const u8, size as usize);
-//         if let Ok(dt) = device_tree::DeviceTree::load(data) {
-//             println!("{:?}", dt);
-//         }
-//     }
-// }
+/// The vsock port the kernel takes test commands on.
+///
+/// Keep in sync with `CONTROL_PORT` in xtask
+const VSOCK_CONTROL_PORT: u32 = 1234;
 
-pub extern "C" fn rust_trap_exception() {
+/// Serve test commands from the host over vsock, one line each, until the
+/// host says `quit` or goes away.
+fn test_vsock(device: &mmio::DeviceInfo) {
+    let mut vsock = VirtIoVsock::new(unsafe { device.header() }).expect("create virtio-vsock driver");
+    println!("<< Kernel: virtio-vsock guest cid = {}", vsock.guest_cid());
+    vsock.listen(VSOCK_CONTROL_PORT);
+    if !wait_for_host("<< Kernel: virtio-vsock listening on port 1234") {
+        println!("<< Kernel: virtio-vsock not driven by host");
+        return;
+    }
+    let id = vsock.accept(VSOCK_CONTROL_PORT, HOST_TIMEOUT).expect("accept vsock control connection");
+    let mut line = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        if vsock.wait_recv(id, HOST_TIMEOUT).is_err() {
+            println!("<< Kernel: virtio-vsock no command from host");
+            break;
+        }
+        let len = vsock.recv(id, &mut buf).expect("receive from vsock");
+        if len == 0 && vsock.peer_closed(id).unwrap_or(true) {
+            break;
+        }
+        for &byte in &buf[..len] {
+            if byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            let command = String::from_utf8_lossy(&line).into_owned();
+            line.clear();
+            let reply = vsock_command(&mut vsock, &command);
+            println!("<< Kernel: virtio-vsock {:?} -> {:?}", command, reply);
+            vsock.send(id, reply.as_bytes()).expect("send to vsock");
+            vsock.send(id, b"\n").expect("send to vsock");
+            if command == "quit" {
+                vsock.close(id).expect("close vsock connection");
+                return;
+            }
+        }
+    }
+    vsock.close(id).expect("close vsock connection");
+}
+
+/// Run a test command from the host, return the reply.
+///
+/// Replies start with `ok` or `err`, followed by `key=value` results.
+fn vsock_command(vsock: &mut VirtIoVsock<VirtIoHeader>, command: &str) -> String {
+    let mut words = command.split_whitespace();
+    match words.next() {
+        Some("ping") => "ok pong".into(),
+        Some("echo") => format!("ok {}", words.collect::<Vec<_>>().join(" ")),
+        Some("heap") => {
+            let stats = heap_stats();
+            format!("ok total={} free={} available={}", stats.total, stats.free, stats.available)
+        }
+        // 反过来连接主机，验证客户机主动发起的连接
+        Some("connect") => match words.next().map(str::parse) {
+            Some(Ok(port)) => match vsock_hello(vsock, port) {
+                Ok(len) => format!("ok sent={}", len),
+                Err(e) => format!("err error={:?}", e),
+            },
+            _ => "err usage=connect-port".into(),
+        },
+        Some("quit") => "ok bye".into(),
+        _ => format!("err unknown={:?}", command),
+    }
+}
+
+/// Connect to `port` of the host, send a line and close, return the length
+/// of the line.
+fn vsock_hello(vsock: &mut VirtIoVsock<VirtIoHeader>, port: u32) -> Result<usize> {
+    use device::vsock::HOST_CID;
+    const HELLO: &[u8] = b"hello from virtio-vsock\n";
+    let id = vsock.connect(HOST_CID, port)?;
+    let result = vsock.send(id, HELLO);
+    vsock.close(id)?;
+    result.map(|_| HELLO.len())
+}
+
+/// How long to wait for the host to act on a marker.
+const HOST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Print `marker` as a line and wait until the host answers with a line
+/// on the console.
+///
+/// xtask watches the output for markers and acts on them through the QEMU
+/// monitor. Returns false if nobody answered, e.g. when QEMU runs by hand.
+fn wait_for_host(marker: &str) -> bool {
+    // 标记和回答都走 SBI 控制台，切换到 virtio-console 之后也一样
+    console::print_host(format_args!("{}\n", marker));
+    let mut line = [0u8; 16];
+    console::read_host_line(&mut line, HOST_TIMEOUT).is_ok()
+}
+
+/// Make virtio-rng the kernel entropy source, and draw from it twice.
+fn test_rng(device: &mmio::DeviceInfo) {
+    let rng = VirtIoRng::new(unsafe { device.header() }).expect("create virtio-rng driver");
+    random::init(rng);
+    let mut first = [0u8; 32];
+    let mut second = [0u8; 32];
+    random::fill_random(&mut first).expect("fill random");
+    random::fill_random(&mut second).expect("fill random");
+    println!("<< Kernel: virtio-rng random bytes = {:02x?}", first);
+    // 32 字节全零或者两次相同的概率可以忽略
+    assert!(first.iter().any(|&b| b != 0), "virtio-rng returned all zeros");
+    assert_ne!(first, second, "virtio-rng returned the same bytes twice");
+}
+
+/// Switch the console over to virtio-console, then read a line from it.
+fn test_console(device: &mmio::DeviceInfo) {
+    let mut console = VirtIoConsole::new(unsafe { device.header() }).expect("create virtio-console driver");
+    println!("<< Kernel: virtio-console size = {:?}, switch output to it", console.size());
+    console.send(b"<< Kernel: hello from virtio-console\n").expect("send to virtio-console");
+    console::use_virtio(console);
+    println!("<< Kernel: print through virtio-console");
+    let mut line = [0u8; 64];
+    match console::read_line(&mut line, Duration::from_secs(1)) {
+        Ok(len) => {
+            println!("<< Kernel: virtio-console read line {:?}", core::str::from_utf8(&line[..len]));
+        }
+        Err(e) => {
+            println!("<< Kernel: virtio-console read line: {:?}", e);
+        }
+    }
+}
+
+/// The address QEMU user networking gives to the guest.
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+/// The host as seen by the guest. In socket mode, xtask answers for it.
+const HOST_IP: [u8; 4] = [10, 0, 2, 2];
+/// Keep in sync with `UDP_ECHO_PORT` in xtask
+const UDP_ECHO_PORT: u16 = 5555;
+const UDP_LOCAL_PORT: u16 = 4321;
+
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+
+/// Resolve the host with ARP, then send a UDP datagram and expect it back.
+fn test_net(device: &mmio::DeviceInfo) {
+    use device::phy::{Device, TxToken};
+    let mut net = VirtIoNet::new(unsafe { device.header() }).expect("create virtio-net driver");
+    net.use_interrupt(device.irq);
+    let mac = net.mac();
+    println!(
+        "<< Kernel: virtio-net mac = {:02x?}, link up = {}, mtu = {}",
+        mac, net.link_up(), net.capabilities().max_transmission_unit
+    );
+
+    let mut arp = [0u8; 42];
+    arp_packet(&mut arp, 1, mac, GUEST_IP, [0xff; 6], HOST_IP);
+    // 请求里的目标硬件地址未知，填零
+    arp[32..38].copy_from_slice(&[0; 6]);
+    net.transmit().expect("virtio-net transmit token")
+        .consume(arp.len(), |buf| { buf.copy_from_slice(&arp); Ok(()) })
+        .expect("send ARP request");
+    let reply = net_wait_frame(&mut net, |frame| {
+        frame[12..14] == ETHERTYPE_ARP && frame.len() >= 42 && frame[20..22] == [0, 2] && frame[28..32] == HOST_IP
+    });
+    let mut host_mac = [0u8; 6];
+    host_mac.copy_from_slice(&reply[22..28]);
+    println!("<< Kernel: virtio-net ARP {:?} is at {:02x?}", HOST_IP, host_mac);
+
+    let payload = b"hello from virtio-net";
+    let udp_len = 8 + payload.len();
+    let ip_len = 20 + udp_len;
+    let mut frame = alloc::vec![0u8; 14 + ip_len];
+    frame[..6].copy_from_slice(&host_mac);
+    frame[6..12].copy_from_slice(&mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4);
+    {
+        let ip = &mut frame[14..34];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&(ip_len as u16).to_be_bytes());
+        ip[8] = 64;
+        ip[9] = 17;
+        ip[12..16].copy_from_slice(&GUEST_IP);
+        ip[16..20].copy_from_slice(&HOST_IP);
+        let checksum = ipv4_checksum(ip);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+    // UDP 校验和填零表示不校验
+    frame[34..36].copy_from_slice(&UDP_LOCAL_PORT.to_be_bytes());
+    frame[36..38].copy_from_slice(&UDP_ECHO_PORT.to_be_bytes());
+    frame[38..40].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    frame[42..].copy_from_slice(payload);
+    net.send(&frame).expect("send UDP datagram");
+    let echo = net_wait_frame(&mut net, |frame| {
+        frame[12..14] == ETHERTYPE_IPV4 && frame.len() >= 42 && frame[23] == 17
+            && frame[26..30] == HOST_IP && frame[36..38] == UDP_LOCAL_PORT.to_be_bytes()
+    });
+    assert_eq!(&echo[42..], &payload[..], "virtio-net UDP echo mismatch");
+    println!("<< Kernel: virtio-net UDP echo from {:?}:{} OK", HOST_IP, UDP_ECHO_PORT);
+}
+
+/// Receive frames until one matches `filter`, answering ARP requests for
+/// the guest on the way. Panics after 3 seconds.
+fn net_wait_frame(net: &mut VirtIoNet<VirtIoHeader>, filter: impl Fn(&[u8]) -> bool) -> Vec<u8> {
+    use device::phy::{Device, RxToken, TxToken};
+    let own_mac = net.mac();
+    let deadline = clock::now() + Duration::from_secs(3);
+    loop {
+        let now = clock::now();
+        assert!(now < deadline, "virtio-net: no reply");
+        if net.wait_recv(deadline - now).is_err() {
+            continue;
+        }
+        let (rx, tx) = match net.receive() {
+            Some(tokens) => tokens,
+            None => continue,
+        };
+        let frame = rx.consume(|frame| Ok(frame.to_vec())).unwrap();
+        if frame.len() < 14 {
+            continue;
+        }
+        if filter(&frame) {
+            return frame;
+        }
+        // 用户网络在回复 IP 包之前可能会先问客户机的 MAC 地址
+        if frame[12..14] == ETHERTYPE_ARP && frame.len() >= 42 && frame[20..22] == [0, 1] && frame[38..42] == GUEST_IP {
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(&frame[6..12]);
+            let mut peer_ip = [0u8; 4];
+            peer_ip.copy_from_slice(&frame[28..32]);
+            let _ = tx.consume(42, |buf| {
+                arp_packet(buf, 2, own_mac, GUEST_IP, mac, peer_ip);
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Fill `buf` with an ethernet frame carrying an ARP packet. The ethernet
+/// destination is `target_mac`.
+fn arp_packet(buf: &mut [u8], op: u16, sender_mac: [u8; 6], sender_ip: [u8; 4], target_mac: [u8; 6], target_ip: [u8; 4]) {
+    buf[..6].copy_from_slice(&target_mac);
+    buf[6..12].copy_from_slice(&sender_mac);
+    buf[12..14].copy_from_slice(&ETHERTYPE_ARP);
+    // hardware type ethernet, protocol IPv4, address lengths 6 and 4
+    buf[14..20].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+    buf[20..22].copy_from_slice(&op.to_be_bytes());
+    buf[22..28].copy_from_slice(&sender_mac);
+    buf[28..32].copy_from_slice(&sender_ip);
+    buf[32..38].copy_from_slice(&target_mac);
+    buf[38..42].copy_from_slice(&target_ip);
+}
+
+/// The one's complement checksum of an IPv4 header.
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for word in header.chunks(2) {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Get the id of the current hart, kept in `tp` since boot.
+pub fn hart_id() -> usize {
+    let hartid;
+    unsafe { asm!("mv {}, tp", out(reg) hartid) };
+    hartid
+}
+
+/// The kernel services the drivers use.
+struct KernelHal;
+
+impl hal::Hal for KernelHal {
+    fn now(&self) -> Duration {
+        clock::now()
+    }
+
+    fn wait_for(&self, timeout: Duration, sleep: bool, condition: &dyn Fn() -> bool) -> Result {
+        clock::wait_for(timeout, sleep, condition)
+    }
+
+    fn hart_id(&self) -> usize {
+        hart_id()
+    }
+
+    fn interrupt_enabled(&self) -> bool {
+        interrupt::enabled()
+    }
+
+    unsafe fn register_interrupt(&self, irq: u32, transport: &mut (dyn transport::Transport + 'static)) {
+        interrupt::register(irq, transport)
+    }
 
+    fn unregister_interrupt(&self, irq: u32) {
+        interrupt::unregister(irq)
+    }
+
+    fn interrupt_status(&self, irq: u32) -> u32 {
+        interrupt::status(irq)
+    }
+
+    fn take_interrupt_status(&self, irq: u32, mask: u32) -> u32 {
+        interrupt::take_status(irq, mask)
+    }
 }
 
 use core::panic::PanicInfo;
@@ -74,12 +1077,12 @@ use core::panic::PanicInfo;
 #[cfg_attr(not(test), panic_handler)]
 #[allow(unused)]
 fn panic(info: &PanicInfo) -> ! {
-    println!("!! Kernel: {}", info);
-    println!("!! Kernel: Test failed due to panic");
-    sbi::shutdown()
+    console::print_panic(format_args!("!! Kernel: {}\n", info));
+    console::print_panic(format_args!("!! Kernel: Test failed due to panic\n"));
+    exit::failure(exit::Failure::Panic)
 }
 
-const BOOT_STACK_SIZE: usize = 4096 * 4 * 8;
+const BOOT_STACK_SIZE: usize = 4096 * 4 * MAX_HARTS;
 
 static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
@@ -88,6 +1091,8 @@ static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 #[export_name = "_start"]
 unsafe extern "C" fn entry() -> ! {
     asm!("
+    # 0. keep hartid in tp
+    mv      tp, a0
     # 1. set sp
     # sp = bootstack + (hartid + 1) * 0x10000
     add     t0, a0, 1
@@ -105,123 +1110,25 @@ unsafe extern "C" fn entry() -> ! {
     options(noreturn))
 }
 
-#[cfg(target_pointer_width = "64")]
-macro_rules! define_store_load {
-    () => {
-        ".altmacro
-        .macro STORE reg, offset
-            sd  \\reg, \\offset* {REGBYTES} (sp)
-        .endm
-        .macro LOAD reg, offset
-            ld  \\reg, \\offset* {REGBYTES} (sp)
-        .endm"
-    };
-}
-
-#[cfg(target_pointer_width = "32")]
-macro_rules! define_store_load {
-    () => {
-        ".altmacro
-        .macro STORE reg, offset
-            sw  \\reg, \\offset* {REGBYTES} (sp)
-        .endm
-        .macro LOAD reg, offset
-            lw  \\reg, \\offset* {REGBYTES} (sp)
-        .endm"
-    };
-}
-
+/// Where secondary harts started by HSM begin, with the same stack layout.
 #[naked]
-#[link_section = ".text"]
-unsafe extern "C" fn start_trap() {
-    asm!(define_store_load!(), "
-    .p2align 2
-    addi    sp, sp, -16 * {REGBYTES}
-    STORE   ra, 0
-    STORE   t0, 1
-    STORE   t1, 2
-    STORE   t2, 3
-    STORE   t3, 4
-    STORE   t4, 5
-    STORE   t5, 6
-    STORE   t6, 7
-    STORE   a0, 8
-    STORE   a1, 9
-    STORE   a2, 10
-    STORE   a3, 11
-    STORE   a4, 12
-    STORE   a5, 13
-    STORE   a6, 14
-    STORE   a7, 15
-    mv      a0, sp
-    call    {rust_trap_exception}
-    LOAD    ra, 0
-    LOAD    t0, 1
-    LOAD    t1, 2
-    LOAD    t2, 3
-    LOAD    t3, 4
-    LOAD    t4, 5
-    LOAD    t5, 6
-    LOAD    t6, 7
-    LOAD    a0, 8
-    LOAD    a1, 9
-    LOAD    a2, 10
-    LOAD    a3, 11
-    LOAD    a4, 12
-    LOAD    a5, 13
-    LOAD    a6, 14
-    LOAD    a7, 15
-    addi    sp, sp, 16 * {REGBYTES}
-    sret
+unsafe extern "C" fn secondary_entry() -> ! {
+    asm!("
+    mv      tp, a0
+    add     t0, a0, 1
+    slli    t0, t0, 14
+1:  auipc   sp, %pcrel_hi({boot_stack})
+    addi    sp, sp, %pcrel_lo(1b)
+    add     sp, sp, t0
+1:  auipc   t0, %pcrel_hi({rust_main_secondary})
+    addi    t0, t0, %pcrel_lo(1b)
+    jr      t0
     ",
-    REGBYTES = const core::mem::size_of::<usize>(),
-    rust_trap_exception = sym rust_trap_exception,
+    boot_stack = sym BOOT_STACK,
+    rust_main_secondary = sym rust_main_secondary,
     options(noreturn))
 }
 
-#[macro_use]
-mod console {
-    use super::sbi::*;
-    use core::fmt::{self, Write};
-    use spin::Mutex;
-
-    struct Stdout;
-
-    impl Write for Stdout {
-        fn write_str(&mut self, s: &str) -> fmt::Result {
-            let mut buffer = [0u8; 4];
-            for c in s.chars() {
-                for code_point in c.encode_utf8(&mut buffer).as_bytes().iter() {
-                    console_putchar(*code_point as usize);
-                }
-            }
-            Ok(())
-        }
-    }
-
-    #[allow(unused)]
-    pub fn print(args: fmt::Arguments) {
-        STDOUT.lock().write_fmt(args).unwrap();
-    }
-
-    lazy_static::lazy_static! {
-        static ref STDOUT: Mutex<Stdout> = Mutex::new(Stdout);
-    }
-
-    #[macro_export]
-    macro_rules! print {
-        ($fmt: literal $(, $($arg: tt)+)?) => {
-            $crate::console::print(format_args!($fmt $(, $($arg)+)?));
-        }
-    }
-
-    #[macro_export]
-    macro_rules! println {
-        ($fmt: literal $(, $($arg: tt)+)?) => {
-            $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
-        }
-    }
-}
 #[allow(unused)]
 mod sbi {
     pub const EXTENSION_BASE: usize = 0x10;
@@ -239,6 +1146,19 @@ mod sbi {
     const FUNCTION_BASE_GET_MARCHID: usize = 0x5;
     const FUNCTION_BASE_GET_MIMPID: usize = 0x6;
 
+    const FUNCTION_TIMER_SET_TIMER: usize = 0x0;
+
+    const FUNCTION_IPI_SEND_IPI: usize = 0x0;
+
+    const FUNCTION_RFENCE_REMOTE_FENCE_I: usize = 0x0;
+    const FUNCTION_RFENCE_REMOTE_SFENCE_VMA: usize = 0x1;
+
+    const FUNCTION_HSM_HART_START: usize = 0x0;
+    const FUNCTION_HSM_HART_STOP: usize = 0x1;
+    const FUNCTION_HSM_HART_GET_STATUS: usize = 0x2;
+
+    const FUNCTION_SRST_SYSTEM_RESET: usize = 0x0;
+
     #[repr(C)]
     pub struct SbiRet {
         /// Error number
@@ -247,6 +1167,65 @@ mod sbi {
         pub value: usize,
     }
 
+    impl SbiRet {
+        /// Map the error number to `SbiError`.
+        pub fn into_result(self) -> Result<usize, SbiError> {
+            match self.error as isize {
+                0 => Ok(self.value),
+                -1 => Err(SbiError::Failed),
+                -2 => Err(SbiError::NotSupported),
+                -3 => Err(SbiError::InvalidParam),
+                -4 => Err(SbiError::Denied),
+                -5 => Err(SbiError::InvalidAddress),
+                -6 => Err(SbiError::AlreadyAvailable),
+                -7 => Err(SbiError::AlreadyStarted),
+                -8 => Err(SbiError::AlreadyStopped),
+                error => Err(SbiError::Unknown(error)),
+            }
+        }
+    }
+
+    /// Standard SBI error codes.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum SbiError {
+        Failed,
+        NotSupported,
+        InvalidParam,
+        Denied,
+        InvalidAddress,
+        AlreadyAvailable,
+        AlreadyStarted,
+        AlreadyStopped,
+        Unknown(isize),
+    }
+
+    /// Status of a hart, as seen by the HSM extension.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum HartStatus {
+        Started,
+        Stopped,
+        StartPending,
+        StopPending,
+        Unknown(usize),
+    }
+
+    /// Type of `system_reset`.
+    #[repr(u32)]
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum ResetType {
+        Shutdown = 0,
+        ColdReboot = 1,
+        WarmReboot = 2,
+    }
+
+    /// Reason of `system_reset`.
+    #[repr(u32)]
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum ResetReason {
+        NoReason = 0,
+        SystemFailure = 1,
+    }
+
     #[inline(always)]
     fn sbi_call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
         let (error, value);
@@ -267,6 +1246,26 @@ mod sbi {
         SbiRet { error, value }
     }
 
+    #[inline(always)]
+    fn sbi_call_4(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> SbiRet {
+        let (error, value);
+        match () {
+            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+            () => unsafe { asm!(
+                "ecall", 
+                in("a0") arg0, in("a1") arg1, in("a2") arg2, in("a3") arg3,
+                in("a6") function, in("a7") extension,
+                lateout("a0") error, lateout("a1") value,
+            ) },
+            #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+            () => {
+                drop((extension, function, arg0, arg1, arg2, arg3));
+                unimplemented!("not RISC-V instruction set architecture")
+            }
+        };
+        SbiRet { error, value }
+    }
+
     #[inline]
     pub fn get_spec_version() -> usize {
         sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_SPEC_VERSION, 0, 0, 0).value
@@ -302,6 +1301,63 @@ mod sbi {
         sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_MIMPID, 0, 0, 0).value
     }
 
+    #[inline]
+    pub fn sbi_set_timer(stime_value: u64) -> Result<(), SbiError> {
+        sbi_call(EXTENSION_TIMER, FUNCTION_TIMER_SET_TIMER, stime_value as usize, 0, 0).into_result().map(drop)
+    }
+
+    /// Send an inter-processor interrupt to the harts in `hart_mask`.
+    ///
+    /// Bit i of `hart_mask` stands for hart `hart_mask_base + i`.
+    #[inline]
+    pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+        sbi_call(EXTENSION_IPI, FUNCTION_IPI_SEND_IPI, hart_mask, hart_mask_base, 0).into_result().map(drop)
+    }
+
+    /// Execute `fence.i` on the harts in the mask.
+    #[inline]
+    pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+        sbi_call(EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_FENCE_I, hart_mask, hart_mask_base, 0).into_result().map(drop)
+    }
+
+    /// Execute `sfence.vma` for the address range on the harts in the mask.
+    #[inline]
+    pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start_addr: usize, size: usize) -> Result<(), SbiError> {
+        sbi_call_4(EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_SFENCE_VMA, hart_mask, hart_mask_base, start_addr, size)
+            .into_result().map(drop)
+    }
+
+    /// Start hart `hartid` at `start_addr` in supervisor mode, with the hart
+    /// id in `a0` and `opaque` in `a1`.
+    #[inline]
+    pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+        sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_START, hartid, start_addr, opaque).into_result().map(drop)
+    }
+
+    /// Stop the calling hart. Does not return on success.
+    #[inline]
+    pub fn hart_stop() -> Result<(), SbiError> {
+        sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_STOP, 0, 0, 0).into_result().map(drop)
+    }
+
+    #[inline]
+    pub fn hart_get_status(hartid: usize) -> Result<HartStatus, SbiError> {
+        sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_GET_STATUS, hartid, 0, 0).into_result().map(|status| match status {
+            0 => HartStatus::Started,
+            1 => HartStatus::Stopped,
+            2 => HartStatus::StartPending,
+            3 => HartStatus::StopPending,
+            status => HartStatus::Unknown(status),
+        })
+    }
+
+    /// Reset the system. Does not return on success.
+    #[inline]
+    pub fn system_reset(reset_type: ResetType, reset_reason: ResetReason) -> Result<(), SbiError> {
+        sbi_call(EXTENSION_SRST, FUNCTION_SRST_SYSTEM_RESET, reset_type as usize, reset_reason as usize, 0)
+            .into_result().map(drop)
+    }
+
     #[inline(always)]
     fn sbi_call_legacy(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
         let ret;
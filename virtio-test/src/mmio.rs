@@ -1,8 +1,17 @@
+use alloc::vec::Vec;
 use volatile_register::{RO, WO, RW};
 
-/// MMIO Device Legacy Register Interface.
+use crate::transport::Transport;
+
+/// The page size in bytes of the guest, used by the legacy queue layout.
+pub const PAGE_SIZE: usize = 4096;
+
+/// MMIO Device Register Interface, the `Transport` of virtio-mmio devices.
 ///
-/// Ref: 4.2.4 Legacy interface
+/// The same layout serves both the legacy (version 0x1) and the modern
+/// (version 0x2) interface; registers only one of them uses are noted.
+///
+/// Ref: 4.2.2 MMIO Device Register Layout, 4.2.4 Legacy interface
 #[repr(C)]
 pub struct VirtIoHeader {
     /// Magic value
@@ -10,7 +19,7 @@ pub struct VirtIoHeader {
 
     /// Device version number
     ///
-    /// Legacy device returns value 0x1.
+    /// Legacy device returns value 0x1, modern device returns value 0x2.
     version: RO<u32>,
 
     /// Virtio Subsystem Device ID
@@ -146,13 +155,293 @@ pub struct VirtIoHeader {
 impl VirtIoHeader {
     /// Verify a valid header.
     pub fn verify(&self) -> bool {
-        self.magic.read() == 0x7472_6976 && self.version.read() == 1 && self.device_id.read() != 0
+        self.magic.read() == 0x7472_6976
+            && (self.version.read() == LEGACY_VERSION || self.version.read() == MODERN_VERSION)
+            && self.device_id.read() != 0
+    }
+
+    /// Get the virtio subsystem device ID.
+    pub fn device_id(&self) -> u32 {
+        self.device_id.read()
+    }
+
+    /// Get the virtio subsystem vendor ID.
+    pub fn vendor_id(&self) -> u32 {
+        self.vendor_id.read()
+    }
+
+    /// Get the device version, 0x1 for legacy or 0x2 for modern.
+    pub fn version(&self) -> u32 {
+        self.version.read()
+    }
+
+    /// Get the generation of the device configuration space.
+    ///
+    /// The device changes it every time the configuration noticeably
+    /// changes. Legacy devices have no such register and always return 0.
+    pub fn config_generation(&self) -> u32 {
+        if self.is_legacy() {
+            0
+        } else {
+            self.config_generation.read()
+        }
+    }
+}
+
+impl Transport for VirtIoHeader {
+    fn device_type(&self) -> DeviceType {
+        DeviceType::from(self.device_id.read())
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version.read() == LEGACY_VERSION
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        unsafe { self.device_features_sel.write(0) };
+        let low = self.device_features.read() as u64;
+        unsafe { self.device_features_sel.write(1) };
+        let high = self.device_features.read() as u64;
+        (high << 32) | low
+    }
+
+    fn write_driver_features(&mut self, features: u64) {
+        unsafe {
+            self.driver_features_sel.write(0);
+            self.driver_features.write(features as u32);
+            self.driver_features_sel.write(1);
+            self.driver_features.write((features >> 32) as u32);
+        }
+    }
+
+    fn status(&self) -> DeviceStatus {
+        self.status.read()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        unsafe { self.status.write(status) };
+    }
+
+    fn queue_used(&mut self, queue: u32) -> bool {
+        unsafe { self.queue_sel.write(queue) };
+        if self.is_legacy() {
+            self.queue_pfn.read() != 0
+        } else {
+            self.queue_ready.read() != 0
+        }
+    }
+
+    fn max_queue_size(&mut self, queue: u32) -> u32 {
+        unsafe { self.queue_sel.write(queue) };
+        self.queue_num_max.read()
+    }
+
+    /// Legacy devices only take the page number of the descriptor table, so
+    /// the three parts must follow the legacy layout with page alignment.
+    fn queue_set(&mut self, queue: u32, size: u32, desc: usize, avail: usize, used: usize) {
+        unsafe {
+            self.queue_sel.write(queue);
+            self.queue_num.write(size);
+        }
+        if self.is_legacy() {
+            debug_assert_eq!(desc % PAGE_SIZE, 0);
+            debug_assert_eq!(used % PAGE_SIZE, 0);
+            unsafe {
+                self.guest_page_size.write(PAGE_SIZE as u32);
+                self.queue_align.write(PAGE_SIZE as u32);
+                self.queue_pfn.write((desc / PAGE_SIZE) as u32);
+            }
+        } else {
+            unsafe {
+                self.queue_desc_low.write(desc as u32);
+                self.queue_desc_high.write((desc as u64 >> 32) as u32);
+                self.queue_avail_low.write(avail as u32);
+                self.queue_avail_high.write((avail as u64 >> 32) as u32);
+                self.queue_used_low.write(used as u32);
+                self.queue_used_high.write((used as u64 >> 32) as u32);
+                self.queue_ready.write(1);
+            }
+        }
     }
+
+    fn notify(&mut self, queue: u32) {
+        unsafe { self.queue_notify.write(queue) };
+    }
+
+    /// Bit 0 means a used buffer notification, bit 1 means a configuration
+    /// change notification.
+    fn ack_interrupt(&mut self) -> u32 {
+        let status = self.interrupt_status.read();
+        if status != 0 {
+            unsafe { self.interrupt_ack.write(status) };
+        }
+        status
+    }
+
+    fn config_space(&self) -> *mut u8 {
+        (self as *const _ as usize + CONFIG_SPACE_OFFSET) as _
+    }
+}
+
+/// Base address of the first virtio-mmio slot on QEMU virt.
+pub const VIRTIO_MMIO_BASE: usize = 0x1000_1000;
+/// Size of each virtio-mmio slot.
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+/// Number of virtio-mmio slots on QEMU virt.
+pub const VIRTIO_MMIO_SLOTS: usize = 8;
+/// Interrupt line of the first slot, the others follow in order.
+pub const VIRTIO_MMIO_IRQ_BASE: u32 = 1;
+
+/// A virtio device discovered in a virtio-mmio slot.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceInfo {
+    /// Base address of the register interface
+    pub base: usize,
+    /// Device type
+    pub device_type: DeviceType,
+    /// Virtio subsystem vendor ID
+    pub vendor_id: u32,
+    /// Version of the register interface
+    pub version: u32,
+    /// Interrupt line on the PLIC
+    pub irq: u32,
 }
 
+impl DeviceInfo {
+    /// Get the register interface of the device.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure only one driver uses the device at a time.
+    pub unsafe fn header(&self) -> &'static mut VirtIoHeader {
+        &mut *(self.base as *mut VirtIoHeader)
+    }
+}
+
+/// Where a virtio-mmio register interface may live.
+#[derive(Debug, Clone, Copy)]
+pub struct Slot {
+    /// Base address of the register interface
+    pub base: usize,
+    /// Interrupt line on the PLIC
+    pub irq: u32,
+}
+
+/// The virtio-mmio slots of QEMU virt, for when no device tree describes them.
+pub fn default_slots() -> Vec<Slot> {
+    (0..VIRTIO_MMIO_SLOTS)
+        .map(|i| Slot {
+            base: VIRTIO_MMIO_BASE + i * VIRTIO_MMIO_SIZE,
+            irq: VIRTIO_MMIO_IRQ_BASE + i as u32,
+        })
+        .collect()
+}
+
+/// Scan every virtio-mmio slot, return the devices found.
+///
+/// Empty slots, where the device ID reads zero, are skipped.
+pub fn probe(slots: &[Slot]) -> Vec<DeviceInfo> {
+    let mut devices = Vec::new();
+    for slot in slots {
+        let header = unsafe { &*(slot.base as *const VirtIoHeader) };
+        if !header.verify() {
+            continue;
+        }
+        devices.push(DeviceInfo {
+            base: slot.base,
+            device_type: header.device_type(),
+            vendor_id: header.vendor_id(),
+            version: header.version(),
+            irq: slot.irq,
+        });
+    }
+    devices
+}
+
+/// Types of virtio devices.
+///
+/// Ref: 5 Device Types
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DeviceType {
+    Invalid,
+    Network,
+    Block,
+    Console,
+    EntropySource,
+    MemoryBallooning,
+    IoMemory,
+    Rpmsg,
+    ScsiHost,
+    _9P,
+    Mac80211,
+    RprocSerial,
+    VirtioCaif,
+    MemoryBalloon,
+    Gpu,
+    Timer,
+    Input,
+    Socket,
+    Crypto,
+    SignalDistributionModule,
+    Pstore,
+    Iommu,
+    Memory,
+    Sound,
+    FileSystem,
+    Unknown(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(device_id: u32) -> Self {
+        match device_id {
+            0 => DeviceType::Invalid,
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::EntropySource,
+            5 => DeviceType::MemoryBallooning,
+            6 => DeviceType::IoMemory,
+            7 => DeviceType::Rpmsg,
+            8 => DeviceType::ScsiHost,
+            9 => DeviceType::_9P,
+            10 => DeviceType::Mac80211,
+            11 => DeviceType::RprocSerial,
+            12 => DeviceType::VirtioCaif,
+            13 => DeviceType::MemoryBalloon,
+            16 => DeviceType::Gpu,
+            17 => DeviceType::Timer,
+            18 => DeviceType::Input,
+            19 => DeviceType::Socket,
+            20 => DeviceType::Crypto,
+            21 => DeviceType::SignalDistributionModule,
+            22 => DeviceType::Pstore,
+            23 => DeviceType::Iommu,
+            24 => DeviceType::Memory,
+            25 => DeviceType::Sound,
+            26 => DeviceType::FileSystem,
+            id => DeviceType::Unknown(id),
+        }
+    }
+}
+
+/// The offset of the device-specific configuration space from the header.
+const CONFIG_SPACE_OFFSET: usize = 0x100;
+
+const LEGACY_VERSION: u32 = 1;
+const MODERN_VERSION: u32 = 2;
+
+/// The feature bit a modern device offers to tell it complies with the
+/// virtio 1.0 specification, and a modern driver must accept.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Interrupt status bit of a used buffer notification.
+pub const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+/// Interrupt status bit of a configuration change notification.
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
 bitflags::bitflags! {
     /// The device status field.
-    struct DeviceStatus: u32 {
+    pub struct DeviceStatus: u32 {
         /// Indicates that the guest OS has found the device and recognized it
         /// as a valid virtio device.
         const ACKNOWLEDGE = 1;
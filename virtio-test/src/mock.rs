@@ -0,0 +1,483 @@
+//! A virtio device in host memory, for testing the drivers without QEMU.
+//!
+//! The driver holds the `MockTransport`, the test keeps the `MockDevice`
+//! handle to play the device side. Whenever a queue is notified, the device
+//! takes the new available buffers and offers them to the handler of the
+//! queue, which completes them into the used ring or keeps them for later.
+//! Faults are injected through `MockDevice::inject`. Interrupts registered
+//! by a driver are acknowledged on its transport when the driver looks at
+//! their status, as if the interrupt handler had just run.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::sync::atomic::{fence, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::hal::{self, Hal};
+use crate::mmio::{DeviceStatus, DeviceType, INTERRUPT_CONFIG_CHANGE, INTERRUPT_USED_BUFFER, VIRTIO_F_VERSION_1};
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+/// The number of queues of a mock device.
+const MAX_QUEUES: usize = 8;
+
+/// The max size of every queue.
+const MAX_QUEUE_SIZE: u32 = 16;
+
+/// The size of the configuration space in bytes.
+const CONFIG_SPACE_SIZE: usize = 256;
+
+/// Completes the buffers of a queue, return the number of bytes written
+/// into them, or `None` to keep them for later.
+type Handler = Box<dyn FnMut(&mut Chain) -> Option<u32>>;
+
+/// Something a misbehaving device does.
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    /// Clear FEATURES_OK again when the driver sets it, refusing the features.
+    RejectFeatures,
+    /// Never complete the buffers of the queue, as if the device hung.
+    Hang(u32),
+    /// Report the queue as unavailable, with a max size of zero.
+    NoQueue(u32),
+}
+
+/// A chain of buffers made available by the driver.
+pub struct Chain {
+    /// The device-readable buffers, one after another
+    pub input: Vec<u8>,
+    /// The device-writable buffers
+    outputs: Vec<(*mut u8, usize)>,
+}
+
+impl Chain {
+    /// The total length of the device-writable buffers.
+    pub fn output_len(&self) -> usize {
+        self.outputs.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Write `data` into the device-writable buffers from byte `offset` on,
+    /// return the number of bytes written.
+    pub fn write(&mut self, mut offset: usize, data: &[u8]) -> usize {
+        let mut written = 0;
+        for &(addr, len) in &self.outputs {
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let count = (len - offset).min(data.len() - written);
+            unsafe { ptr::copy_nonoverlapping(data[written..].as_ptr(), addr.add(offset), count) };
+            written += count;
+            offset = 0;
+            if written == data.len() {
+                break;
+            }
+        }
+        written
+    }
+}
+
+/// The transport of a mock device, what the driver holds.
+pub struct MockTransport {
+    state: Rc<RefCell<State>>,
+}
+
+/// The device side of a mock device, what the test holds.
+#[derive(Clone)]
+pub struct MockDevice {
+    state: Rc<RefCell<State>>,
+}
+
+impl MockTransport {
+    /// Create a modern device of `device_type`, offering `device_features`
+    /// and VIRTIO_F_VERSION_1.
+    pub fn new(device_type: DeviceType, device_features: u64) -> (Self, MockDevice) {
+        Self::create(device_type, device_features | VIRTIO_F_VERSION_1, false)
+    }
+
+    /// Create a legacy device of `device_type`, offering `device_features`.
+    pub fn new_legacy(device_type: DeviceType, device_features: u64) -> (Self, MockDevice) {
+        Self::create(device_type, device_features, true)
+    }
+
+    fn create(device_type: DeviceType, device_features: u64, legacy: bool) -> (Self, MockDevice) {
+        hal::init(&MockHal);
+        let state = Rc::new(RefCell::new(State {
+            device_type,
+            legacy,
+            device_features,
+            driver_features: 0,
+            status: DeviceStatus::empty(),
+            reject_features: false,
+            config: Box::new([0; CONFIG_SPACE_SIZE / 8]),
+            queues: (0..MAX_QUEUES).map(|_| QueueState::default()).collect(),
+            interrupt_status: 0,
+        }));
+        (MockTransport { state: state.clone() }, MockDevice { state })
+    }
+}
+
+impl MockDevice {
+    /// Complete the buffers of `queue` with `handler` from now on.
+    ///
+    /// `handler` returns the number of bytes it wrote into the chain, or
+    /// `None` to keep the chain until the next time the queue is processed.
+    pub fn on_queue(&self, queue: u32, handler: impl FnMut(&mut Chain) -> Option<u32> + 'static) {
+        self.state.borrow_mut().queues[queue as usize].handler = Some(Box::new(handler));
+    }
+
+    /// Make the device misbehave.
+    pub fn inject(&self, fault: Fault) {
+        let mut state = self.state.borrow_mut();
+        match fault {
+            Fault::RejectFeatures => state.reject_features = true,
+            Fault::Hang(queue) => state.queues[queue as usize].hung = true,
+            Fault::NoQueue(queue) => state.queues[queue as usize].max_size = 0,
+        }
+    }
+
+    /// Write `data` into the configuration space at `offset`.
+    pub fn set_config(&self, offset: usize, data: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        let config = state.config.as_mut_ptr() as *mut u8;
+        assert!(offset + data.len() <= CONFIG_SPACE_SIZE);
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), config.add(offset), data.len()) };
+    }
+
+    /// Read the configuration space at `offset` into `data`.
+    pub fn read_config(&self, offset: usize, data: &mut [u8]) {
+        let state = self.state.borrow();
+        let config = state.config.as_ptr() as *const u8;
+        assert!(offset + data.len() <= CONFIG_SPACE_SIZE);
+        unsafe { ptr::copy_nonoverlapping(config.add(offset), data.as_mut_ptr(), data.len()) };
+    }
+
+    /// Raise a configuration change interrupt, as the device does after it
+    /// changed its configuration.
+    pub fn config_changed(&self) {
+        self.state.borrow_mut().interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+    }
+
+    /// Offer the buffers of `queue` to its handler again, as the device does
+    /// when the queue is notified.
+    pub fn process(&self, queue: u32) {
+        self.state.borrow_mut().process(queue);
+    }
+
+    /// The number of chains of `queue` the device keeps for later.
+    pub fn pending(&self, queue: u32) -> usize {
+        self.state.borrow().queues[queue as usize].pending.len()
+    }
+
+    /// The number of notifications `queue` received.
+    pub fn notifications(&self, queue: u32) -> usize {
+        self.state.borrow().queues[queue as usize].notifications
+    }
+
+    /// The device status set by the driver.
+    pub fn status(&self) -> DeviceStatus {
+        self.state.borrow().status
+    }
+
+    /// The features accepted by the driver.
+    pub fn driver_features(&self) -> u64 {
+        self.state.borrow().driver_features
+    }
+}
+
+impl Transport for MockTransport {
+    fn device_type(&self) -> DeviceType {
+        self.state.borrow().device_type
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.state.borrow().legacy
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.state.borrow().device_features
+    }
+
+    fn write_driver_features(&mut self, features: u64) {
+        self.state.borrow_mut().driver_features = features;
+    }
+
+    fn status(&self) -> DeviceStatus {
+        self.state.borrow().status
+    }
+
+    fn set_status(&mut self, mut status: DeviceStatus) {
+        let mut state = self.state.borrow_mut();
+        if status.is_empty() {
+            state.reset();
+            return;
+        }
+        if state.reject_features {
+            status.remove(DeviceStatus::FEATURES_OK);
+        }
+        state.status = status;
+    }
+
+    fn queue_used(&mut self, queue: u32) -> bool {
+        self.state.borrow().queues[queue as usize].ready
+    }
+
+    fn max_queue_size(&mut self, queue: u32) -> u32 {
+        self.state.borrow().queues[queue as usize].max_size
+    }
+
+    fn queue_set(&mut self, queue: u32, size: u32, desc: usize, avail: usize, used: usize) {
+        let mut state = self.state.borrow_mut();
+        let queue = &mut state.queues[queue as usize];
+        queue.size = size as u16;
+        queue.desc = desc;
+        queue.avail = avail;
+        queue.used = used;
+        queue.ready = true;
+    }
+
+    fn notify(&mut self, queue: u32) {
+        let mut state = self.state.borrow_mut();
+        state.queues[queue as usize].notifications += 1;
+        state.process(queue);
+    }
+
+    fn ack_interrupt(&mut self) -> u32 {
+        let mut state = self.state.borrow_mut();
+        let status = state.interrupt_status;
+        state.interrupt_status = 0;
+        status
+    }
+
+    fn config_space(&self) -> *mut u8 {
+        // 配置空间在堆上，地址不随 RefCell 借用变化
+        self.state.borrow().config.as_ptr() as *mut u8
+    }
+}
+
+struct State {
+    device_type: DeviceType,
+    legacy: bool,
+    device_features: u64,
+    driver_features: u64,
+    status: DeviceStatus,
+    reject_features: bool,
+    /// Aligned like the registers of a real configuration space
+    config: Box<[u64; CONFIG_SPACE_SIZE / 8]>,
+    queues: Vec<QueueState>,
+    interrupt_status: u32,
+}
+
+impl State {
+    /// Reset the device, so it forgets the queues and the features.
+    fn reset(&mut self) {
+        self.status = DeviceStatus::empty();
+        self.driver_features = 0;
+        for queue in &mut self.queues {
+            queue.ready = false;
+            queue.last_avail_idx = 0;
+            queue.used_idx = 0;
+            queue.pending.clear();
+        }
+    }
+
+    /// Take the new available chains of `queue`, and offer all kept chains
+    /// to its handler.
+    fn process(&mut self, queue: u32) {
+        if !self.status.contains(DeviceStatus::DRIVER_OK) {
+            return;
+        }
+        let queue = &mut self.queues[queue as usize];
+        if !queue.ready {
+            return;
+        }
+        let avail_idx = unsafe { ptr::read_volatile((queue.avail as *const u16).add(1)) };
+        // read barrier
+        fence(Ordering::SeqCst);
+        while queue.last_avail_idx != avail_idx {
+            let slot = queue.last_avail_idx & (queue.size - 1);
+            let head = unsafe { ptr::read_volatile((queue.avail as *const u16).add(2 + slot as usize)) };
+            queue.pending.push_back(head);
+            queue.last_avail_idx = queue.last_avail_idx.wrapping_add(1);
+        }
+        if queue.hung {
+            return;
+        }
+        let handler = match queue.handler.as_mut() {
+            Some(handler) => handler,
+            None => return,
+        };
+        let mut kept = VecDeque::new();
+        let mut completed = false;
+        while let Some(head) = queue.pending.pop_front() {
+            let mut chain = read_chain(queue.desc, head);
+            match handler(&mut chain) {
+                Some(len) => {
+                    let slot = queue.used_idx & (queue.size - 1);
+                    // used ring: flags, idx, then `struct { id: u32, len: u32 }` elements
+                    let elem = unsafe { (queue.used as *mut u32).add(1 + 2 * slot as usize) };
+                    unsafe {
+                        ptr::write_volatile(elem, head as u32);
+                        ptr::write_volatile(elem.add(1), len);
+                    }
+                    // write barrier
+                    fence(Ordering::SeqCst);
+                    queue.used_idx = queue.used_idx.wrapping_add(1);
+                    unsafe { ptr::write_volatile((queue.used as *mut u16).add(1), queue.used_idx) };
+                    completed = true;
+                }
+                None => kept.push_back(head),
+            }
+        }
+        queue.pending = kept;
+        if completed {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+}
+
+struct QueueState {
+    max_size: u32,
+    size: u16,
+    desc: usize,
+    avail: usize,
+    used: usize,
+    ready: bool,
+    hung: bool,
+    /// The next index the device reads in the available ring
+    last_avail_idx: u16,
+    /// The next index the device writes to in the used ring
+    used_idx: u16,
+    /// Heads of the chains kept for later
+    pending: VecDeque<u16>,
+    notifications: usize,
+    handler: Option<Handler>,
+}
+
+impl Default for QueueState {
+    fn default() -> Self {
+        QueueState {
+            max_size: MAX_QUEUE_SIZE,
+            size: 0,
+            desc: 0,
+            avail: 0,
+            used: 0,
+            ready: false,
+            hung: false,
+            last_avail_idx: 0,
+            used_idx: 0,
+            pending: VecDeque::new(),
+            notifications: 0,
+            handler: None,
+        }
+    }
+}
+
+/// Follow the chain starting at descriptor `head` of the table at `desc`.
+fn read_chain(desc: usize, head: u16) -> Chain {
+    let mut chain = Chain {
+        input: Vec::new(),
+        outputs: Vec::new(),
+    };
+    let mut index = head;
+    loop {
+        let desc = unsafe { ptr::read_volatile((desc as *const Descriptor).add(index as usize)) };
+        if desc.flags & VRING_DESC_F_WRITE != 0 {
+            chain.outputs.push((desc.addr as *mut u8, desc.len as usize));
+        } else {
+            let buf = unsafe { slice::from_raw_parts(desc.addr as *const u8, desc.len as usize) };
+            chain.input.extend_from_slice(buf);
+        }
+        if desc.flags & VRING_DESC_F_NEXT == 0 {
+            return chain;
+        }
+        index = desc.next;
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+
+/// A registered interrupt line and the status bits seen on it.
+struct Interrupt {
+    irq: u32,
+    transport: *mut dyn Transport,
+    status: u32,
+}
+
+thread_local! {
+    // 每个测试在自己的线程里运行，中断注册互不影响
+    static INTERRUPTS: RefCell<Vec<Interrupt>> = RefCell::new(Vec::new());
+}
+
+/// The kernel services for drivers under test.
+struct MockHal;
+
+impl Hal for MockHal {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    fn wait_for(&self, _timeout: Duration, _sleep: bool, condition: &dyn Fn() -> bool) -> Result {
+        // 模拟设备在通知时就处理完请求，之后再等也不会有变化
+        if condition() {
+            Ok(())
+        } else {
+            Err(Error::Timeout)
+        }
+    }
+
+    fn hart_id(&self) -> usize {
+        0
+    }
+
+    fn interrupt_enabled(&self) -> bool {
+        true
+    }
+
+    unsafe fn register_interrupt(&self, irq: u32, transport: &mut (dyn Transport + 'static)) {
+        self.unregister_interrupt(irq);
+        let transport = transport as *mut dyn Transport;
+        INTERRUPTS.with(|interrupts| interrupts.borrow_mut().push(Interrupt { irq, transport, status: 0 }));
+    }
+
+    fn unregister_interrupt(&self, irq: u32) {
+        INTERRUPTS.with(|interrupts| interrupts.borrow_mut().retain(|interrupt| interrupt.irq != irq));
+    }
+
+    fn interrupt_status(&self, irq: u32) -> u32 {
+        INTERRUPTS.with(|interrupts| {
+            let mut interrupts = interrupts.borrow_mut();
+            match interrupts.iter_mut().find(|interrupt| interrupt.irq == irq) {
+                Some(interrupt) => {
+                    // transport 在注销之前一直有效
+                    interrupt.status |= unsafe { (*interrupt.transport).ack_interrupt() };
+                    interrupt.status
+                }
+                None => 0,
+            }
+        })
+    }
+
+    fn take_interrupt_status(&self, irq: u32, mask: u32) -> u32 {
+        let status = self.interrupt_status(irq) & mask;
+        INTERRUPTS.with(|interrupts| {
+            for interrupt in interrupts.borrow_mut().iter_mut().filter(|interrupt| interrupt.irq == irq) {
+                interrupt.status &= !mask;
+            }
+        });
+        status
+    }
+}
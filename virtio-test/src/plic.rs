@@ -0,0 +1,76 @@
+//! Platform-Level Interrupt Controller.
+//!
+//! Ref: RISC-V PLIC Specification, chapter Memory Map
+
+use core::ptr::{read_volatile, write_volatile};
+
+const PRIORITY_OFFSET: usize = 0x0;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM_COMPLETE: usize = 0x4;
+
+/// The register interface of a PLIC.
+#[derive(Debug, Clone, Copy)]
+pub struct Plic {
+    base: usize,
+}
+
+impl Plic {
+    /// Create a PLIC driver at `base`.
+    pub const fn new(base: usize) -> Self {
+        Plic { base }
+    }
+
+    /// Set the priority of an interrupt source, 0 disables the source.
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        self.write(PRIORITY_OFFSET + 4 * irq as usize, priority);
+    }
+
+    /// Enable an interrupt source for a context.
+    pub fn enable(&self, context: usize, irq: u32) {
+        let offset = ENABLE_OFFSET + ENABLE_STRIDE * context + 4 * (irq as usize / 32);
+        let bits = self.read(offset);
+        self.write(offset, bits | 1 << (irq % 32));
+    }
+
+    /// Disable an interrupt source for a context.
+    pub fn disable(&self, context: usize, irq: u32) {
+        let offset = ENABLE_OFFSET + ENABLE_STRIDE * context + 4 * (irq as usize / 32);
+        let bits = self.read(offset);
+        self.write(offset, bits & !(1 << (irq % 32)));
+    }
+
+    /// Set the priority threshold of a context. Only interrupts with a
+    /// priority above the threshold are delivered.
+    pub fn set_threshold(&self, context: usize, threshold: u32) {
+        self.write(CONTEXT_OFFSET + CONTEXT_STRIDE * context + THRESHOLD, threshold);
+    }
+
+    /// Claim the highest-priority pending interrupt of a context, 0 if none.
+    pub fn claim(&self, context: usize) -> u32 {
+        self.read(CONTEXT_OFFSET + CONTEXT_STRIDE * context + CLAIM_COMPLETE)
+    }
+
+    /// Tell the PLIC the interrupt of a context has been handled.
+    pub fn complete(&self, context: usize, irq: u32) {
+        self.write(CONTEXT_OFFSET + CONTEXT_STRIDE * context + CLAIM_COMPLETE, irq);
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+}
+
+/// The PLIC context of a hart in supervisor mode.
+///
+/// QEMU virt gives each hart an M-mode context followed by an S-mode one.
+pub fn supervisor_context(hartid: usize) -> usize {
+    2 * hartid + 1
+}
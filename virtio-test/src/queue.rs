@@ -0,0 +1,378 @@
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::mmio::PAGE_SIZE;
+use crate::transport::Transport;
+use crate::{Error, Result};
+
+/// The mechanism for bulk data transport on virtio devices.
+///
+/// Each device can have zero or more virtqueues. The descriptor table, the
+/// available ring and the used ring live in one physically-contiguous
+/// region, with the used ring starting on the next page boundary. Legacy
+/// devices require this layout, modern devices accept it as well.
+///
+/// Dropping the queue frees that region, so the owning driver must reset
+/// the device first, which drivers do in their `Drop`.
+///
+/// Ref: 2.6 Split Virtqueues
+pub struct VirtQueue {
+    /// Start of the queue memory, aligned to a page
+    base: *mut u8,
+    /// Layout used to allocate the queue memory
+    layout: Layout,
+    /// Descriptor table
+    desc: *mut Descriptor,
+    /// Available ring
+    avail: *mut u16,
+    /// Used ring
+    used: *mut u16,
+
+    /// The index of queue
+    queue_idx: u32,
+    /// The size of queue
+    queue_size: u16,
+    /// The number of used descriptors
+    num_used: u16,
+    /// The head desc index of the free list
+    free_head: u16,
+    /// The next index the driver will write to in the available ring
+    avail_idx: u16,
+    /// The last index of the used ring the driver has seen
+    last_used_idx: u16,
+    /// The number of descriptors in the chain starting at each head, zero
+    /// if no chain made available starts there
+    chain_len: Vec<u16>,
+}
+
+impl VirtQueue {
+    /// Create a new virtqueue and register it to the device.
+    pub fn new<T: Transport>(header: &mut T, idx: usize, size: u16) -> Result<Self> {
+        if header.queue_used(idx as u32) {
+            return Err(Error::AlreadyUsed);
+        }
+        if !size.is_power_of_two() || header.max_queue_size(idx as u32) < size as u32 {
+            return Err(Error::InvalidParam);
+        }
+        let queue_layout = QueueLayout::new(size);
+        let layout = Layout::from_size_align(queue_layout.size, PAGE_SIZE)
+            .map_err(|_| Error::InvalidParam)?;
+        // 队列所在的内存必须清零，设备会把可用环和已用环的初始下标看作零
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            return Err(Error::DmaError);
+        }
+        let desc = base as *mut Descriptor;
+        let avail = unsafe { base.add(queue_layout.avail_offset) } as *mut u16;
+        let used = unsafe { base.add(queue_layout.used_offset) } as *mut u16;
+        // 内核里物理地址和虚拟地址相同
+        header.queue_set(
+            idx as u32,
+            size as u32,
+            desc as usize,
+            avail as usize,
+            used as usize,
+        );
+        // link descriptors together
+        for i in 0..(size - 1) {
+            unsafe { (*desc.add(i as usize)).next = i + 1 };
+        }
+
+        Ok(VirtQueue {
+            base,
+            layout,
+            desc,
+            avail,
+            used,
+            queue_idx: idx as u32,
+            queue_size: size,
+            num_used: 0,
+            free_head: 0,
+            avail_idx: 0,
+            last_used_idx: 0,
+            chain_len: vec![0; size as usize],
+        })
+    }
+
+    /// Add buffers to the virtqueue, return a token.
+    ///
+    /// Buffers in `inputs` are read by the device, buffers in `outputs` are
+    /// written by the device. Ref: linux virtio_ring.c virtqueue_add
+    pub fn add(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result<u16> {
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        if inputs.len() + outputs.len() + self.num_used as usize > self.queue_size as usize {
+            return Err(Error::BufferTooSmall);
+        }
+
+        // allocate descriptors from free list
+        let head = self.free_head;
+        let mut last = self.free_head;
+        for input in inputs.iter() {
+            last = self.free_head;
+            let desc = self.desc_mut(last);
+            desc.set_buf(input);
+            desc.flags = DescFlags::NEXT.bits();
+            let next = desc.next;
+            self.free_head = next;
+        }
+        for output in outputs.iter() {
+            last = self.free_head;
+            let desc = self.desc_mut(last);
+            desc.set_buf(output);
+            desc.flags = (DescFlags::NEXT | DescFlags::WRITE).bits();
+            let next = desc.next;
+            self.free_head = next;
+        }
+        // set last_elem.next = NULL
+        {
+            let desc = self.desc_mut(last);
+            desc.flags &= !DescFlags::NEXT.bits();
+        }
+        self.num_used += (inputs.len() + outputs.len()) as u16;
+        self.chain_len[head as usize] = (inputs.len() + outputs.len()) as u16;
+
+        let avail_slot = self.avail_idx & (self.queue_size - 1);
+        unsafe { ptr::write_volatile(self.avail.add(2 + avail_slot as usize), head) };
+
+        // write barrier
+        fence(Ordering::SeqCst);
+
+        // increase head of avail ring
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { ptr::write_volatile(self.avail.add(1), self.avail_idx) };
+        Ok(head)
+    }
+
+    /// Notify the device that new buffers are available, unless the device
+    /// asked not to be notified.
+    pub fn notify<T: Transport>(&self, header: &mut T) {
+        // read barrier
+        fence(Ordering::SeqCst);
+        let flags = unsafe { ptr::read_volatile(self.used) };
+        if flags & VRING_USED_F_NO_NOTIFY == 0 {
+            header.notify(self.queue_idx);
+        }
+    }
+
+    /// Whether there is a used element that can pop.
+    pub fn can_pop(&self) -> bool {
+        self.last_used_idx != self.used_idx()
+    }
+
+    /// The number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        (self.queue_size - self.num_used) as usize
+    }
+
+    /// Get a token from device used buffers, return (token, len).
+    ///
+    /// The descriptors of the returned chain go back to the free list. A used
+    /// element naming no chain made available is skipped with `IoError`.
+    pub fn pop_used(&mut self) -> Result<(u16, u32)> {
+        if !self.can_pop() {
+            return Err(Error::NotReady);
+        }
+        // read barrier
+        fence(Ordering::SeqCst);
+
+        let last_used_slot = self.last_used_idx & (self.queue_size - 1);
+        // used ring: flags, idx, then `struct { id: u32, len: u32 }` elements
+        let elem = unsafe { (self.used as *mut u32).add(1 + 2 * last_used_slot as usize) };
+        let id = unsafe { ptr::read_volatile(elem) };
+        let len = unsafe { ptr::read_volatile(elem.add(1)) };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // 设备给出的下标不可信，只回收确实交给过设备的描述符链
+        if id >= self.queue_size as u32 || self.chain_len[id as usize] == 0 {
+            return Err(Error::IoError);
+        }
+        let index = id as u16;
+        self.recycle_descriptors(index);
+
+        Ok((index, len))
+    }
+
+    /// Return size of the queue.
+    pub fn size(&self) -> u16 {
+        self.queue_size
+    }
+
+    /// Recycle descriptors in the list specified by head.
+    ///
+    /// This will push all linked descriptors at the front of the free list.
+    /// `head` must start a chain made available.
+    fn recycle_descriptors(&mut self, head: u16) {
+        let chain_len = core::mem::replace(&mut self.chain_len[head as usize], 0);
+        let origin_free_head = self.free_head;
+        self.free_head = head;
+        self.num_used -= chain_len;
+        let mut last = head;
+        for _ in 1..chain_len {
+            last = self.desc_mut(last).next;
+        }
+        self.desc_mut(last).next = origin_free_head;
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { ptr::read_volatile(self.used.add(1)) }
+    }
+
+    fn desc_mut(&mut self, index: u16) -> &mut Descriptor {
+        unsafe { &mut *self.desc.add(index as usize) }
+    }
+}
+
+// 队列内存只通过 &mut self 访问，可以交给别的 hart
+unsafe impl Send for VirtQueue {}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        // 驱动已经复位设备，设备不再访问这块内存
+        unsafe { dealloc(self.base, self.layout) }
+    }
+}
+
+/// The inner layout of a VirtQueue.
+///
+/// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
+struct QueueLayout {
+    avail_offset: usize,
+    used_offset: usize,
+    size: usize,
+}
+
+impl QueueLayout {
+    fn new(queue_size: u16) -> Self {
+        let queue_size = queue_size as usize;
+        let desc = size_of::<Descriptor>() * queue_size;
+        // flags, idx, ring[queue_size], used_event
+        let avail = size_of::<u16>() * (3 + queue_size);
+        // flags, idx, ring[queue_size], avail_event
+        let used = size_of::<u16>() * 3 + size_of::<u32>() * 2 * queue_size;
+        let used_offset = align_up(desc + avail);
+        QueueLayout {
+            avail_offset: desc,
+            used_offset,
+            size: used_offset + align_up(used),
+        }
+    }
+}
+
+fn align_up(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[repr(C, align(16))]
+#[derive(Debug)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl Descriptor {
+    fn set_buf(&mut self, buf: &[u8]) {
+        self.addr = buf.as_ptr() as u64;
+        self.len = buf.len() as u32;
+    }
+}
+
+bitflags::bitflags! {
+    /// Descriptor flags
+    struct DescFlags: u16 {
+        /// This marks a buffer as continuing via the next field.
+        const NEXT = 1;
+        /// This marks a buffer as device write-only (otherwise device read-only).
+        const WRITE = 2;
+        /// This means the buffer contains a list of buffer descriptors.
+        const INDIRECT = 4;
+    }
+}
+
+/// The device uses this in used->flags to advise the driver: don't kick me
+/// when you add a buffer.
+const VRING_USED_F_NO_NOTIFY: u16 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::DeviceType;
+    use crate::mock::{Fault, MockTransport};
+
+    #[test]
+    fn invalid_queues() {
+        let (mut transport, device) = MockTransport::new(DeviceType::Block, 0);
+        assert_eq!(VirtQueue::new(&mut transport, 0, 3).err(), Some(Error::InvalidParam));
+        assert_eq!(VirtQueue::new(&mut transport, 0, 32).err(), Some(Error::InvalidParam));
+        let _queue = VirtQueue::new(&mut transport, 0, 4).unwrap();
+        assert_eq!(VirtQueue::new(&mut transport, 0, 4).err(), Some(Error::AlreadyUsed));
+        device.inject(Fault::NoQueue(1));
+        assert_eq!(VirtQueue::new(&mut transport, 1, 4).err(), Some(Error::InvalidParam));
+    }
+
+    #[test]
+    fn descriptors_run_out_and_come_back() {
+        let (mut transport, device) = MockTransport::new(DeviceType::Block, 0);
+        transport.finish_init();
+        let mut queue = VirtQueue::new(&mut transport, 0, 4).unwrap();
+        let (a, b) = ([1u8; 4], [2u8; 4]);
+        let mut out = [0u8; 4];
+        assert_eq!(queue.add(&[], &[]).err(), Some(Error::InvalidParam));
+        let first = queue.add(&[&a, &b], &[&mut out]).unwrap();
+        assert_eq!(queue.available_desc(), 1);
+        assert_eq!(queue.add(&[&a], &[&mut out]).err(), Some(Error::BufferTooSmall));
+        assert!(!queue.can_pop());
+        assert_eq!(queue.pop_used().err(), Some(Error::NotReady));
+
+        device.on_queue(0, |chain| {
+            assert_eq!(chain.input, [1, 1, 1, 1, 2, 2, 2, 2]);
+            Some(chain.write(0, b"done") as u32)
+        });
+        queue.notify(&mut transport);
+        assert_eq!(queue.pop_used(), Ok((first, 4)));
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(&out, b"done");
+    }
+
+    /// Complete `head` in the used ring behind the back of the mock device.
+    fn fake_used(queue: &mut VirtQueue, head: u32) {
+        let slot = queue.last_used_idx & (queue.queue_size - 1);
+        unsafe {
+            let elem = (queue.used as *mut u32).add(1 + 2 * slot as usize);
+            ptr::write_volatile(elem, head);
+            ptr::write_volatile(elem.add(1), 0);
+            ptr::write_volatile(queue.used.add(1), queue.last_used_idx.wrapping_add(1));
+        }
+    }
+
+    #[test]
+    fn bogus_used_elements() {
+        let (mut transport, _device) = MockTransport::new(DeviceType::Block, 0);
+        let mut queue = VirtQueue::new(&mut transport, 0, 4).unwrap();
+        let mut out = [0u8; 4];
+        let token = queue.add(&[], &[&mut out]).unwrap();
+
+        fake_used(&mut queue, 4);
+        assert_eq!(queue.pop_used().err(), Some(Error::IoError));
+        fake_used(&mut queue, 0x1_0000 + token as u32);
+        assert_eq!(queue.pop_used().err(), Some(Error::IoError));
+        fake_used(&mut queue, token as u32 + 1);
+        assert_eq!(queue.pop_used().err(), Some(Error::IoError));
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 3);
+
+        fake_used(&mut queue, token as u32);
+        assert_eq!(queue.pop_used(), Ok((token, 0)));
+        fake_used(&mut queue, token as u32);
+        assert_eq!(queue.pop_used().err(), Some(Error::IoError));
+        assert_eq!(queue.available_desc(), 4);
+    }
+}
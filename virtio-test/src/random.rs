@@ -0,0 +1,24 @@
+//! Kernel entropy source, backed by a virtio-rng device once probed.
+
+use spin::Mutex;
+
+use crate::device::rng::VirtIoRng;
+use crate::mmio::VirtIoHeader;
+use crate::{Error, Result};
+
+static RNG: Mutex<Option<VirtIoRng<'static, VirtIoHeader>>> = Mutex::new(None);
+
+/// Take random bytes from `rng` from now on.
+pub fn init(rng: VirtIoRng<'static, VirtIoHeader>) {
+    *RNG.lock() = Some(rng);
+}
+
+/// Fill `buf` with random bytes.
+///
+/// Fails with `Error::NotReady` if no entropy device has been probed.
+pub fn fill_random(buf: &mut [u8]) -> Result {
+    match RNG.lock().as_mut() {
+        Some(rng) => rng.fill(buf),
+        None => Err(Error::NotReady),
+    }
+}
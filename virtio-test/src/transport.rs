@@ -0,0 +1,175 @@
+use crate::mmio::{DeviceStatus, DeviceType, VIRTIO_F_VERSION_1};
+use crate::{Error, Result};
+
+/// The interface a driver uses to reach its device.
+///
+/// Drivers are written once against this trait, so the same driver works
+/// over the legacy and the modern virtio-mmio register interface, or over
+/// anything else that moves the same bits, like a mock device.
+///
+/// Ref: 4 Virtio Transport Options
+pub trait Transport {
+    /// Get the type of the device.
+    fn device_type(&self) -> DeviceType;
+
+    /// Whether the device speaks the legacy interface, so it never takes
+    /// VIRTIO_F_VERSION_1.
+    fn is_legacy(&self) -> bool;
+
+    /// Read all 64 feature bits offered by the device.
+    fn read_device_features(&mut self) -> u64;
+
+    /// Write all 64 feature bits accepted by the driver.
+    fn write_driver_features(&mut self, features: u64);
+
+    /// Get the device status.
+    fn status(&self) -> DeviceStatus;
+
+    /// Set the device status. Writing an empty status resets the device.
+    fn set_status(&mut self, status: DeviceStatus);
+
+    /// Whether the queue is in use by the device.
+    fn queue_used(&mut self, queue: u32) -> bool;
+
+    /// Get the max size of the queue, zero if it is not available.
+    fn max_queue_size(&mut self, queue: u32) -> u32;
+
+    /// Set up the queue with its size and the physical addresses of its
+    /// descriptor table, available ring and used ring.
+    fn queue_set(&mut self, queue: u32, size: u32, desc: usize, avail: usize, used: usize);
+
+    /// Notify the device that the queue has new buffers.
+    fn notify(&mut self, queue: u32);
+
+    /// Acknowledge the interrupt, return the interrupt status.
+    fn ack_interrupt(&mut self) -> u32;
+
+    /// Get the pointer to the device-specific configuration space.
+    fn config_space(&self) -> *mut u8;
+
+    /// View the device-specific configuration space as `C`.
+    ///
+    /// Fields of `C` read and write the device through `volatile_register`
+    /// types, as the configuration may change under the driver.
+    ///
+    /// # Safety
+    ///
+    /// `C` must follow the configuration layout of the device, and not be
+    /// larger than the configuration space.
+    unsafe fn config<C>(&self) -> &C
+    where
+        Self: Sized,
+    {
+        &*(self.config_space() as *const C)
+    }
+
+    /// Reset the device, so it stops using all its queues.
+    fn reset(&mut self) {
+        self.set_status(DeviceStatus::empty());
+    }
+
+    /// Reset the device, then walk the status steps up to FEATURES_OK.
+    ///
+    /// `negotiate_features` receives the features offered by the device and
+    /// returns the ones the driver accepts. Features not offered by the
+    /// device are dropped. VIRTIO_F_VERSION_1 is accepted for modern devices
+    /// and never for legacy ones. Returns the accepted features.
+    ///
+    /// Ref: 3.1.1 Driver Requirements: Device Initialization
+    fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) -> Result<u64>
+    where
+        Self: Sized,
+    {
+        // 1. Reset the device.
+        self.reset();
+        // 2. Set the ACKNOWLEDGE status bit: the guest OS has noticed the device.
+        self.set_status(DeviceStatus::ACKNOWLEDGE);
+        // 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+        self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        // 4. Read device feature bits, and write the subset of feature bits
+        //    understood by the OS and driver to the device.
+        let device_features = self.read_device_features();
+        let mut driver_features = negotiate_features(device_features);
+        if self.is_legacy() {
+            driver_features &= !VIRTIO_F_VERSION_1;
+        } else {
+            driver_features |= VIRTIO_F_VERSION_1;
+        }
+        let driver_features = driver_features & device_features;
+        self.write_driver_features(driver_features);
+        // 5. Set the FEATURES_OK status bit.
+        self.set_status(
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
+        );
+        // 6. Re-read device status to ensure the FEATURES_OK bit is still
+        //    set: otherwise, the device does not support our subset of
+        //    features and the device is unusable.
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            self.set_status(self.status() | DeviceStatus::FAILED);
+            return Err(Error::FeaturesNotAccepted);
+        }
+        Ok(driver_features)
+    }
+
+    /// Finish initializing the device after its virtqueues are set up.
+    fn finish_init(&mut self) {
+        // 8. Set the DRIVER_OK status bit. At this point the device is "live".
+        self.set_status(self.status() | DeviceStatus::DRIVER_OK);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Fault, MockTransport};
+
+    const FEATURE_A: u64 = 1 << 0;
+    const FEATURE_B: u64 = 1 << 5;
+    const FEATURE_C: u64 = 1 << 40;
+
+    #[test]
+    fn modern_device_gets_version_1() {
+        let (mut transport, device) = MockTransport::new(DeviceType::Block, FEATURE_A | FEATURE_B);
+        let features = transport.begin_init(|offered| {
+            assert_eq!(offered, FEATURE_A | FEATURE_B | VIRTIO_F_VERSION_1);
+            FEATURE_A | FEATURE_C
+        });
+        // 设备没提供的特性被丢掉，VERSION_1 总是接受
+        assert_eq!(features, Ok(FEATURE_A | VIRTIO_F_VERSION_1));
+        assert_eq!(device.driver_features(), FEATURE_A | VIRTIO_F_VERSION_1);
+        assert_eq!(
+            device.status(),
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK
+        );
+        transport.finish_init();
+        assert!(device.status().contains(DeviceStatus::DRIVER_OK));
+    }
+
+    #[test]
+    fn legacy_device_never_gets_version_1() {
+        // 即使传统设备提供了 VERSION_1 也不能接受
+        let (mut transport, device) = MockTransport::new_legacy(DeviceType::Block, FEATURE_A | VIRTIO_F_VERSION_1);
+        let features = transport.begin_init(|offered| offered);
+        assert_eq!(features, Ok(FEATURE_A));
+        assert_eq!(device.driver_features(), FEATURE_A);
+    }
+
+    #[test]
+    fn rejected_features_fail_the_device() {
+        let (mut transport, device) = MockTransport::new(DeviceType::Block, FEATURE_A);
+        device.inject(Fault::RejectFeatures);
+        assert_eq!(transport.begin_init(|offered| offered), Err(Error::FeaturesNotAccepted));
+        assert!(device.status().contains(DeviceStatus::FAILED));
+        assert!(!device.status().contains(DeviceStatus::FEATURES_OK));
+    }
+
+    #[test]
+    fn begin_init_resets_first() {
+        let (mut transport, device) = MockTransport::new(DeviceType::Block, FEATURE_A);
+        transport.begin_init(|offered| offered).unwrap();
+        transport.finish_init();
+        transport.begin_init(|_| 0).unwrap();
+        assert!(!device.status().contains(DeviceStatus::DRIVER_OK));
+        assert_eq!(device.driver_features(), VIRTIO_F_VERSION_1);
+    }
+}
@@ -0,0 +1,238 @@
+//! Supervisor trap entry and handler.
+
+use core::fmt;
+
+use riscv::register::scause;
+use riscv::register::stvec::{self, TrapMode};
+
+use crate::{clock, interrupt};
+
+/// Registers saved by `start_trap`, in the order they are stored.
+#[repr(C)]
+pub struct TrapFrame {
+    pub ra: usize,
+    pub t: [usize; 7],
+    pub a: [usize; 8],
+    pub sstatus: usize,
+    pub sepc: usize,
+    pub stval: usize,
+    /// Keeps the frame 16-byte aligned
+    _padding: usize,
+}
+
+impl TrapFrame {
+    /// The stack pointer before the trap.
+    pub fn sp(&self) -> usize {
+        self as *const _ as usize + core::mem::size_of::<Self>()
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "sepc = {:#018x} sstatus = {:#018x} stval = {:#018x}", self.sepc, self.sstatus, self.stval)?;
+        writeln!(f, "ra   = {:#018x} sp      = {:#018x}", self.ra, self.sp())?;
+        for (i, t) in self.t.iter().enumerate() {
+            write!(f, "t{}   = {:#018x}", i, t)?;
+            f.write_str(if i % 4 == 3 { "\n" } else { " " })?;
+        }
+        f.write_str("\n")?;
+        for (i, a) in self.a.iter().enumerate() {
+            write!(f, "a{}   = {:#018x}", i, a)?;
+            f.write_str(if i % 4 == 3 { "\n" } else { " " })?;
+        }
+        Ok(())
+    }
+}
+
+/// Cause of a trap, decoded from `scause`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Trap {
+    Interrupt(Interrupt),
+    Exception(Exception),
+}
+
+/// Supervisor interrupts.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoft,
+    SupervisorTimer,
+    SupervisorExternal,
+    Unknown(usize),
+}
+
+/// Supervisor exceptions.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadFault,
+    StoreMisaligned,
+    StoreFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    Unknown(usize),
+}
+
+impl Trap {
+    /// Decode the value of `scause`.
+    ///
+    /// Ref: RISC-V Privileged Spec, 4.1.9 Supervisor Cause Register
+    pub fn from_bits(bits: usize) -> Self {
+        const INTERRUPT_BIT: usize = 1 << (core::mem::size_of::<usize>() * 8 - 1);
+        let code = bits & !INTERRUPT_BIT;
+        if bits & INTERRUPT_BIT != 0 {
+            Trap::Interrupt(match code {
+                1 => Interrupt::SupervisorSoft,
+                5 => Interrupt::SupervisorTimer,
+                9 => Interrupt::SupervisorExternal,
+                code => Interrupt::Unknown(code),
+            })
+        } else {
+            Trap::Exception(match code {
+                0 => Exception::InstructionMisaligned,
+                1 => Exception::InstructionFault,
+                2 => Exception::IllegalInstruction,
+                3 => Exception::Breakpoint,
+                4 => Exception::LoadMisaligned,
+                5 => Exception::LoadFault,
+                6 => Exception::StoreMisaligned,
+                7 => Exception::StoreFault,
+                8 => Exception::UserEnvCall,
+                9 => Exception::SupervisorEnvCall,
+                12 => Exception::InstructionPageFault,
+                13 => Exception::LoadPageFault,
+                15 => Exception::StorePageFault,
+                code => Exception::Unknown(code),
+            })
+        }
+    }
+}
+
+/// Point `stvec` at the trap entry of this module.
+pub fn init() {
+    unsafe { stvec::write(start_trap as usize, TrapMode::Direct) };
+}
+
+extern "C" fn rust_trap_exception(frame: &mut TrapFrame) {
+    match Trap::from_bits(scause::read().bits()) {
+        Trap::Interrupt(Interrupt::SupervisorExternal) => interrupt::handle_external(),
+        Trap::Interrupt(Interrupt::SupervisorTimer) => clock::handle_timer(),
+        Trap::Interrupt(interrupt) => {
+            println!("!! Kernel: unexpected interrupt {:?}", interrupt);
+        }
+        Trap::Exception(Exception::Breakpoint) => {
+            println!("<< Kernel: breakpoint at {:#x}", frame.sepc);
+            frame.sepc += instruction_len(frame.sepc);
+        }
+        Trap::Exception(Exception::UserEnvCall) | Trap::Exception(Exception::SupervisorEnvCall) => {
+            // 没有系统调用要处理，跳过 ecall 指令
+            println!("<< Kernel: ignored ecall a7 = {:#x} at {:#x}", frame.a[7], frame.sepc);
+            frame.sepc += 4;
+        }
+        Trap::Exception(exception) => fatal(exception, frame),
+    }
+}
+
+/// Report an exception the kernel can't recover from, then shut down.
+fn fatal(exception: Exception, frame: &TrapFrame) -> ! {
+    println!("!! Kernel: unhandled exception {:?} at {:#x}, stval = {:#x}", exception, frame.sepc, frame.stval);
+    print!("{}", frame);
+    println!("!! Kernel: Test failed due to exception");
+    crate::exit::failure(crate::exit::Failure::Exception)
+}
+
+/// Length in bytes of the instruction at `addr`, 2 if compressed.
+fn instruction_len(addr: usize) -> usize {
+    let low = unsafe { core::ptr::read_volatile(addr as *const u16) };
+    if low & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+#[cfg(target_pointer_width = "64")]
+macro_rules! define_store_load {
+    () => {
+        ".altmacro
+        .macro STORE reg, offset
+            sd  \\reg, \\offset* {REGBYTES} (sp)
+        .endm
+        .macro LOAD reg, offset
+            ld  \\reg, \\offset* {REGBYTES} (sp)
+        .endm"
+    };
+}
+
+#[cfg(target_pointer_width = "32")]
+macro_rules! define_store_load {
+    () => {
+        ".altmacro
+        .macro STORE reg, offset
+            sw  \\reg, \\offset* {REGBYTES} (sp)
+        .endm
+        .macro LOAD reg, offset
+            lw  \\reg, \\offset* {REGBYTES} (sp)
+        .endm"
+    };
+}
+
+#[naked]
+#[link_section = ".text"]
+unsafe extern "C" fn start_trap() {
+    asm!(define_store_load!(), "
+    .p2align 2
+    addi    sp, sp, -20 * {REGBYTES}
+    STORE   ra, 0
+    STORE   t0, 1
+    STORE   t1, 2
+    STORE   t2, 3
+    STORE   t3, 4
+    STORE   t4, 5
+    STORE   t5, 6
+    STORE   t6, 7
+    STORE   a0, 8
+    STORE   a1, 9
+    STORE   a2, 10
+    STORE   a3, 11
+    STORE   a4, 12
+    STORE   a5, 13
+    STORE   a6, 14
+    STORE   a7, 15
+    csrr    t0, sstatus
+    csrr    t1, sepc
+    csrr    t2, stval
+    STORE   t0, 16
+    STORE   t1, 17
+    STORE   t2, 18
+    mv      a0, sp
+    call    {rust_trap_exception}
+    LOAD    t0, 16
+    LOAD    t1, 17
+    csrw    sstatus, t0
+    csrw    sepc, t1
+    LOAD    ra, 0
+    LOAD    t0, 1
+    LOAD    t1, 2
+    LOAD    t2, 3
+    LOAD    t3, 4
+    LOAD    t4, 5
+    LOAD    t5, 6
+    LOAD    t6, 7
+    LOAD    a0, 8
+    LOAD    a1, 9
+    LOAD    a2, 10
+    LOAD    a3, 11
+    LOAD    a4, 12
+    LOAD    a5, 13
+    LOAD    a6, 14
+    LOAD    a7, 15
+    addi    sp, sp, 20 * {REGBYTES}
+    sret
+    ",
+    REGBYTES = const core::mem::size_of::<usize>(),
+    rust_trap_exception = sym rust_trap_exception,
+    options(noreturn))
+}
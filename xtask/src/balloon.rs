@@ -0,0 +1,41 @@
+//! Host side of the virtio-balloon test: change the balloon target through
+//! the monitor, and check the guest followed.
+
+use crate::monitor::{Hook, Monitor};
+
+/// QEMU virt has 128 MiB of RAM unless told otherwise
+const RAM_MB: u32 = 128;
+
+/// How much memory the host takes away while inflated
+const INFLATE_MB: u32 = 1;
+
+/// Shrink the guest by `INFLATE_MB`, then give it all back.
+pub fn hooks() -> Vec<Hook> {
+    vec![
+        Hook {
+            marker: "virtio-balloon ready to inflate",
+            action: Box::new(|monitor| set_target(monitor, RAM_MB - INFLATE_MB)),
+        },
+        Hook {
+            marker: "virtio-balloon ready to deflate",
+            action: Box::new(|monitor| {
+                // 内核上一步已经充好气球，主机这边应该看得到
+                let info = monitor.command("info balloon").map_err(|e| format!("info balloon: {}", e))?;
+                let expected = format!("actual={}", RAM_MB - INFLATE_MB);
+                if !info.contains(&expected) {
+                    return Err(format!("info balloon: {}, expect {}", info.trim(), expected));
+                }
+                set_target(monitor, RAM_MB)
+            }),
+        },
+    ]
+}
+
+fn set_target(monitor: &mut Monitor, mb: u32) -> Result<(), String> {
+    let command = format!("balloon {}", mb);
+    let output = monitor.command(&command).map_err(|e| format!("{}: {}", command, e))?;
+    if !output.trim().is_empty() {
+        return Err(format!("{}: {}", command, output.trim()));
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+//! Host side of the virtio-gpu test: capture the display with `screendump`
+//! and check the quadrants painted by the kernel.
+
+use std::{fs, path::PathBuf};
+
+use crate::monitor::Hook;
+
+/// Printed by the kernel once the frame is on the display
+const FRAME_READY: &str = "virtio-gpu frame ready";
+
+/// Colors of the top left, top right, bottom left and bottom right
+/// quadrants, as R, G, B.
+///
+/// Keep in sync with `QUADRANT_COLORS` in virtio-test
+const QUADRANT_COLORS: [[u8; 3]; 4] = [[0xff, 0, 0], [0, 0xff, 0], [0, 0, 0xff], [0xff, 0xff, 0xff]];
+
+/// Take a screendump to `path` when the frame is ready, and verify it.
+pub fn screendump_hook(path: PathBuf) -> Hook {
+    Hook {
+        marker: FRAME_READY,
+        action: Box::new(move |monitor| {
+            let output = monitor
+                .command(&format!("screendump {}", path.display()))
+                .map_err(|e| format!("screendump: {}", e))?;
+            if !output.trim().is_empty() {
+                return Err(format!("screendump: {}", output.trim()));
+            }
+            let ppm = fs::read(&path).map_err(|e| format!("read {}: {}", path.display(), e))?;
+            check_quadrants(&ppm)?;
+            println!("xtask: screendump {} matches", path.display());
+            Ok(())
+        }),
+    }
+}
+
+/// Check the pixel in the middle of each quadrant of a binary PPM image.
+fn check_quadrants(ppm: &[u8]) -> Result<(), String> {
+    let (width, height, pixels) = parse_ppm(ppm).ok_or("not a binary PPM with 8-bit colors")?;
+    for (quadrant, expected) in QUADRANT_COLORS.iter().enumerate() {
+        let x = width / 4 + (quadrant % 2) * width / 2;
+        let y = height / 4 + (quadrant / 2) * height / 2;
+        let offset = (y * width + x) * 3;
+        let actual = &pixels[offset..offset + 3];
+        if actual != expected {
+            return Err(format!("pixel ({}, {}) is {:02x?}, expect {:02x?}", x, y, actual, expected));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a `P6` image with a maximum value of 255, return its width, height
+/// and RGB pixels.
+fn parse_ppm(ppm: &[u8]) -> Option<(usize, usize, &[u8])> {
+    // 头部是四个空白分隔的字段，最后一个空白之后就是像素
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while ppm.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while !ppm.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        fields.push(std::str::from_utf8(&ppm[start..pos]).ok()?);
+    }
+    pos += 1;
+    let width = fields[1].parse().ok()?;
+    let height = fields[2].parse().ok()?;
+    if fields[0] != "P6" || fields[3] != "255" || ppm.len() < pos + width * height * 3 {
+        return None;
+    }
+    Some((width, height, &ppm[pos..]))
+}
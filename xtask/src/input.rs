@@ -0,0 +1,63 @@
+//! Host side of the virtio-input test: inject key, relative and absolute
+//! events over QMP once the kernel listens.
+
+use std::{cell::RefCell, path::PathBuf, rc::Rc};
+
+use crate::monitor::{Hook, Qmp};
+
+/// QEMU devices attached by `--input`, one for each kind of event
+pub const DEVICES: [&str; 3] = ["virtio-keyboard-device", "virtio-mouse-device", "virtio-tablet-device"];
+
+/// The marker the kernel prints once a device waits for events of a kind,
+/// and the `input-send-event` events of that kind.
+///
+/// QEMU routes each kind to the one device taking it, so only the device
+/// listening gets events.
+const EVENTS: [(&str, &[&str]); 3] = [
+    (
+        "virtio-input ready for key events",
+        &[
+            r#"{"type": "key", "data": {"down": true, "key": {"type": "qcode", "data": "a"}}}"#,
+            r#"{"type": "key", "data": {"down": false, "key": {"type": "qcode", "data": "a"}}}"#,
+        ],
+    ),
+    (
+        "virtio-input ready for rel events",
+        &[
+            r#"{"type": "rel", "data": {"axis": "x", "value": 10}}"#,
+            r#"{"type": "rel", "data": {"axis": "y", "value": -10}}"#,
+        ],
+    ),
+    (
+        "virtio-input ready for abs events",
+        &[
+            r#"{"type": "abs", "data": {"axis": "x", "value": 16384}}"#,
+            r#"{"type": "abs", "data": {"axis": "y", "value": 8192}}"#,
+        ],
+    ),
+];
+
+/// Send each input device the events of its kind once it listens, through
+/// the QMP socket at `socket`.
+pub fn inject_hooks(socket: PathBuf) -> Vec<Hook> {
+    let qmp: Rc<RefCell<Option<Qmp>>> = Rc::new(RefCell::new(None));
+    EVENTS
+        .iter()
+        .map(|&(marker, events)| {
+            let qmp = qmp.clone();
+            let socket = socket.clone();
+            let action = move |_: &mut _| {
+                let mut qmp = qmp.borrow_mut();
+                if qmp.is_none() {
+                    *qmp = Some(Qmp::connect(&socket).map_err(|e| format!("can't connect to QMP: {}", e))?);
+                }
+                let arguments = format!(r#"{{"events": [{}]}}"#, events.join(", "));
+                qmp.as_mut()
+                    .unwrap()
+                    .execute("input-send-event", &arguments)
+                    .map_err(|e| format!("input-send-event: {}", e))
+            };
+            Hook { marker, action: Box::new(action) }
+        })
+        .collect()
+}
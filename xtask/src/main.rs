@@ -1,5 +1,5 @@
 use std::{
-    env,
+    env, fs,
     path::{Path, PathBuf},
     process::{self, Command},
 };
@@ -7,6 +7,17 @@ use std::{
 #[macro_use]
 extern crate clap;
 
+mod balloon;
+mod gpu;
+mod input;
+mod monitor;
+mod net;
+mod share;
+mod sound;
+mod vhost_user;
+mod virtiofs;
+mod vsock;
+
 const DEFAULT_TARGET: &'static str = "riscv64imac-unknown-none-elf";
 
 fn main() {    
@@ -25,15 +36,30 @@ fn main() {
         )
         (@subcommand qemu =>
             (about: "Run QEMU")
+            (@arg modern: --modern "Use the modern (version 2) virtio-mmio transport instead of legacy")
+            (@arg smp: --smp +takes_value "Number of harts, up to 8")
+            (@arg console: --console +takes_value "Attach a virtio-console writing to the given file")
+            (@arg console_input: --("console-input") +takes_value requires[console] "Feed the virtio-console from the given file")
+            (@arg rng: --rng +takes_value min_values(0) "Attach a virtio-rng reading a seed file, /dev/urandom by default")
+            (@arg gpu: --gpu "Attach a virtio-gpu device")
+            (@arg screendump: --screendump +takes_value requires[gpu] "Save the display as PPM to the given file and check it")
+            (@arg input: --input "Attach virtio keyboard, mouse and tablet devices, and inject events into them")
+            (@arg balloon: --balloon "Attach a virtio-balloon device, and inflate and deflate it")
+            (@arg share: --share +takes_value "Export the given directory over virtio-9p, and check the kernel writes to it")
+            (@arg crypto: --crypto requires[modern] "Attach a virtio-crypto device with two data queues on the builtin backend")
+            (@arg sound: --sound +takes_value requires[modern] "Attach a virtio-sound device recording to the given WAV file, and check the tone in it")
+            (@arg fs: --fs +takes_value requires[modern] "Export the given directory over virtio-fs through virtiofsd")
+            (@arg vsock: --vsock +takes_value possible_value[vhost user] "Attach a virtio-vsock device with the given backend, and run test commands over it")
+            (@arg net: --net +takes_value possible_value[user socket] "Attach a virtio-net device with the given netdev backend")
         )
     ).get_matches();
     if let Some(_matches) = matches.subcommand_matches("build") {
         xtask_build();
         xtask_binary();
-    } else if let Some(_matches) = matches.subcommand_matches("qemu") {
+    } else if let Some(matches) = matches.subcommand_matches("qemu") {
         xtask_build();
         xtask_binary();
-        xtask_qemu();
+        xtask_qemu(matches);
     } else if let Some(_matches) = matches.subcommand_matches("asm") {
         xtask_build();
         xtask_asm();
@@ -104,7 +130,7 @@ build: firmware
     }
 }
 
-fn xtask_qemu() {
+fn xtask_qemu(matches: &clap::ArgMatches) {
     /*
     qemu: build
     @qemu-system-riscv64 \
@@ -115,23 +141,193 @@ fn xtask_qemu() {
             -device loader,file={{test-kernel-bin}},addr=0x80200000 \
             -smp threads={{threads}}
     */
-    let status = Command::new("qemu-system-riscv64")
-        .current_dir(dist_dir())
+    let mut command = Command::new("qemu-system-riscv64");
+    command.current_dir(dist_dir())
         .args(&["-machine", "virt"])
         .args(&["-bios", "none"])
         .arg("-nographic")
         .args(&["-device", "loader,file=../../../bootloader/rustsbi-qemu.bin,addr=0x80000000"])
         .args(&["-device", "loader,file=virtio-test.bin,addr=0x80200000"])
         .args(&["-drive", "file=../../../drives/raw1.img,if=none,format=raw,id=x0"])
-        .args(&["-device","virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0"])
-        .status().unwrap();
-    
-    if !status.success() {
-        println!("qemu failed");
+        .args(&["-device","virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0"]);
+    if matches.is_present("modern") {
+        command.args(&["-global", "virtio-mmio.force-legacy=false"]);
+    }
+    if let Some(smp) = matches.value_of("smp") {
+        command.args(&["-smp", smp]);
+    }
+    match matches.value_of("net") {
+        Some("user") => {
+            net::spawn_udp_echo();
+            command.args(&["-netdev", "user,id=net0"]);
+        }
+        Some("socket") => {
+            net::spawn_socket_peer();
+            command.args(&["-netdev", &format!(
+                "socket,id=net0,udp={},localaddr={}",
+                net::SOCKET_PEER_ADDR, net::SOCKET_QEMU_ADDR
+            )]);
+        }
+        _ => {}
+    }
+    if matches.is_present("net") {
+        command.args(&["-device", "virtio-net-device,netdev=net0"]);
+    }
+    if matches.is_present("rng") {
+        // 种子文件读到结尾后设备就没有熵了，要比测试用量大
+        let source = matches.value_of("rng").map(absolute_path).unwrap_or_else(|| PathBuf::from("/dev/urandom"));
+        command.args(&["-object", &format!("rng-random,id=rng0,filename={}", source.display())])
+            .args(&["-device", "virtio-rng-device,rng=rng0"]);
+    }
+    if matches.is_present("crypto") {
+        // 内置后端完全在 QEMU 里运行，不需要主机的加密设备
+        command.args(&["-object", "cryptodev-backend-builtin,id=cryptodev0,queues=2"])
+            .args(&["-device", "virtio-crypto-device,cryptodev=cryptodev0"]);
+    }
+    let sound = matches.value_of("sound").map(absolute_path);
+    if let Some(wav) = &sound {
+        command.args(sound::args(wav));
+    }
+    let mut hooks = Vec::new();
+    if matches.is_present("gpu") {
+        command.args(&["-device", "virtio-gpu-device"]);
+    }
+    if let Some(screendump) = matches.value_of("screendump") {
+        hooks.push(gpu::screendump_hook(absolute_path(screendump)));
+    }
+    if matches.is_present("input") {
+        for device in &input::DEVICES {
+            command.args(&["-device", device]);
+        }
+        let socket = dist_dir().join("qemu-qmp.sock");
+        command.args(&["-qmp", &monitor::Qmp::arg(&socket)]);
+        hooks.extend(input::inject_hooks(socket));
+    }
+    if matches.is_present("balloon") {
+        command.args(&["-device", "virtio-balloon-device"]);
+        hooks.extend(balloon::hooks());
+    }
+    let console = matches.value_of("console").map(absolute_path);
+    if let Some(console) = &console {
+        let mut chardev = format!("file,id=con0,path={}", console.display());
+        if let Some(input) = matches.value_of("console_input") {
+            chardev += &format!(",input-path={}", absolute_path(input).display());
+        }
+        command.args(&["-chardev", &chardev])
+            .args(&["-device", "virtio-serial-device"])
+            .args(&["-device", "virtconsole,chardev=con0"]);
+    }
+    let share = matches.value_of("share").map(absolute_path);
+    if let Some(share) = &share {
+        if let Err(e) = share::prepare(share) {
+            println!("xtask: {}", e);
+            process::exit(1);
+        }
+        command.args(share::args(share));
+    }
+    // vhost-user 后端和 QEMU 共享客户机内存
+    let mut backends = Vec::new();
+    let vsock_session = match matches.value_of("vsock") {
+        Some(backend) => {
+            let backend = if backend == "vhost" { vsock::Backend::Vhost } else { vsock::Backend::User };
+            let (args, child) = backend.start().unwrap_or_else(|e| {
+                println!("xtask: can't start {:?} vsock backend: {}", backend, e);
+                process::exit(1);
+            });
+            command.args(args);
+            backends.extend(child);
+            let (hook, session) = vsock::control_hook(backend);
+            hooks.push(hook);
+            Some(session)
+        }
+        None => None,
+    };
+    let fs_dir = matches.value_of("fs").map(absolute_path);
+    if let Some(dir) = &fs_dir {
+        match virtiofs::start(dir) {
+            Ok((args, child)) => {
+                command.args(args);
+                backends.push(child);
+            }
+            Err(e) => {
+                println!("xtask: can't start virtiofsd: {}", e);
+                virtiofs::cleanup(dir);
+                vhost_user::stop(backends);
+                process::exit(1);
+            }
+        }
+    }
+    if !backends.is_empty() {
+        command.args(vhost_user::memory_args());
+    }
+    let (status, mut errors) = if hooks.is_empty() {
+        (command.status().unwrap(), Vec::new())
+    } else {
+        monitor::run_with_hooks(&mut command, dist_dir().join("qemu-monitor.sock"), hooks)
+    };
+    if let Some(console) = &console {
+        // 内核切换到 virtio-console 之后的输出都在文件里
+        match fs::read_to_string(console) {
+            Ok(output) => print!("{}", output),
+            Err(e) => println!("can't read virtio-console output {}: {}", console.display(), e),
+        }
+    }
+    if let Some(session) = vsock_session {
+        if let Err(e) = session.finish() {
+            println!("xtask: {}", e);
+            errors.push(e);
+        }
+    }
+    vhost_user::stop(backends);
+    if let Some(dir) = &fs_dir {
+        virtiofs::cleanup(dir);
+    }
+    if let Some(wav) = &sound {
+        if let Err(e) = sound::check_tone(wav) {
+            println!("xtask: {}", e);
+            errors.push(e);
+        }
+    }
+    if let Some(share) = &share {
+        if let Err(e) = share::check_output(share) {
+            println!("xtask: {}", e);
+            errors.push(e);
+        }
+    }
+
+    match status.code() {
+        Some(0) => {}
+        Some(code) => {
+            println!("test failed: {}, qemu exit code {}", failure_reason(code), code);
+            process::exit(code);
+        }
+        None => {
+            println!("qemu terminated by signal");
+            process::exit(1);
+        }
+    }
+    if !errors.is_empty() {
+        println!("test failed: {} host check(s) failed", errors.len());
         process::exit(1);
     }
 }
 
+// Keep in sync with `exit::Failure` in virtio-test
+fn failure_reason(code: i32) -> &'static str {
+    match code {
+        1 => "qemu error",
+        0x21 => "panic",
+        0x22 => "out of memory",
+        0x23 => "unhandled exception",
+        _ => "unknown reason",
+    }
+}
+
+// QEMU 在 dist 目录里运行，命令行给的路径要先转成绝对路径
+fn absolute_path(path: &str) -> PathBuf {
+    env::current_dir().unwrap().join(path)
+}
+
 fn project_root() -> PathBuf {
     Path::new(&env!("CARGO_MANIFEST_DIR"))
         .ancestors()
@@ -0,0 +1,181 @@
+//! Drive QEMU through its human monitor while the test kernel runs.
+//!
+//! The kernel prints a marker line when it wants the host to do something,
+//! then waits for a line on its console. xtask scans the QEMU output for
+//! markers, runs the matching hook on the monitor, and answers with a line
+//! on QEMU's stdin. Hooks needing commands the human monitor lacks talk
+//! QMP on a socket of their own.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The prompt the human monitor prints after each command.
+const PROMPT: &str = "(qemu) ";
+
+/// How long QEMU may take to create a monitor socket.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to the QEMU human monitor over a unix socket.
+pub struct Monitor {
+    stream: UnixStream,
+}
+
+impl Monitor {
+    /// The QEMU `-monitor` argument for a socket at `path`.
+    pub fn arg(path: &Path) -> String {
+        format!("unix:{},server,nowait", path.display())
+    }
+
+    /// Connect to the monitor socket at `path`, waiting for QEMU to create it.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let stream = connect(path)?;
+        let mut monitor = Monitor { stream };
+        // 跳过欢迎信息
+        monitor.read_until_prompt()?;
+        Ok(monitor)
+    }
+
+    /// Run a monitor command, return what it printed.
+    pub fn command(&mut self, command: &str) -> io::Result<String> {
+        writeln!(self.stream, "{}", command)?;
+        let output = self.read_until_prompt()?;
+        // 监视器会先回显命令本身
+        Ok(output.lines().skip(1).collect::<Vec<_>>().join("\n"))
+    }
+
+    fn read_until_prompt(&mut self) -> io::Result<String> {
+        let mut output = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !output.ends_with(PROMPT.as_bytes()) {
+            let len = self.stream.read(&mut buf)?;
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            output.extend_from_slice(&buf[..len]);
+        }
+        output.truncate(output.len() - PROMPT.len());
+        // 去掉终端控制序列
+        let output = String::from_utf8_lossy(&output).replace("\x1b[D", "").replace("\x1b[K", "");
+        Ok(output.replace('\r', ""))
+    }
+}
+
+/// A connection to the QEMU machine protocol over a unix socket.
+pub struct Qmp {
+    stream: BufReader<UnixStream>,
+}
+
+impl Qmp {
+    /// The QEMU `-qmp` argument for a socket at `path`.
+    pub fn arg(path: &Path) -> String {
+        format!("unix:{},server,nowait", path.display())
+    }
+
+    /// Connect to the QMP socket at `path`, waiting for QEMU to create it.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let mut qmp = Qmp { stream: BufReader::new(connect(path)?) };
+        // 跳过欢迎信息，协商之后才能执行别的命令
+        qmp.read_line()?;
+        qmp.execute("qmp_capabilities", "{}")?;
+        Ok(qmp)
+    }
+
+    /// Run `command` with `arguments` given as a JSON object, fail with the
+    /// error QEMU replied.
+    pub fn execute(&mut self, command: &str, arguments: &str) -> io::Result<()> {
+        writeln!(self.stream.get_mut(), r#"{{"execute": "{}", "arguments": {}}}"#, command, arguments)?;
+        loop {
+            let line = self.read_line()?;
+            if line.starts_with(r#"{"return""#) {
+                return Ok(());
+            }
+            if line.starts_with(r#"{"error""#) {
+                return Err(io::Error::new(io::ErrorKind::Other, line));
+            }
+            // 其余的是异步事件
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim().to_string())
+    }
+}
+
+/// Connect to the unix socket at `path`, waiting for QEMU to create it.
+fn connect(path: &Path) -> io::Result<UnixStream> {
+    let start = Instant::now();
+    loop {
+        match UnixStream::connect(path) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if start.elapsed() > CONNECT_TIMEOUT => return Err(e),
+            Err(_) => thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// Monitor commands run for a marker, failing with a message.
+pub type Action = Box<dyn FnMut(&mut Monitor) -> Result<(), String>>;
+
+/// What to do on the monitor when the kernel prints a line with `marker`.
+pub struct Hook {
+    pub marker: &'static str,
+    pub action: Action,
+}
+
+/// Run QEMU with a monitor socket at `socket`, serving `hooks` until it exits.
+///
+/// QEMU output is passed through to stdout. Returns the exit status and the
+/// errors of failed hooks.
+pub fn run_with_hooks(command: &mut Command, socket: PathBuf, mut hooks: Vec<Hook>) -> (ExitStatus, Vec<String>) {
+    let _ = std::fs::remove_file(&socket);
+    let mut child = command
+        .args(&["-monitor", &Monitor::arg(&socket)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut monitor = None;
+    let mut errors = Vec::new();
+    for line in stdout.split(b'\n') {
+        let line = match line {
+            Ok(line) => String::from_utf8_lossy(&line).into_owned(),
+            Err(_) => break,
+        };
+        println!("{}", line);
+        let hook = match hooks.iter_mut().find(|hook| line.contains(hook.marker)) {
+            Some(hook) => hook,
+            None => continue,
+        };
+        if monitor.is_none() {
+            match Monitor::connect(&socket) {
+                Ok(connected) => monitor = Some(connected),
+                Err(e) => {
+                    errors.push(format!("can't connect to QEMU monitor: {}", e));
+                    continue;
+                }
+            }
+        }
+        if let Err(e) = (hook.action)(monitor.as_mut().unwrap()) {
+            println!("xtask: {}: {}", hook.marker, e);
+            errors.push(e);
+        }
+        // 内核在等一行输入，之后才会继续
+        let _ = stdin.write_all(b"\n");
+        let _ = stdin.flush();
+    }
+    let status = child.wait().unwrap();
+    let _ = std::fs::remove_file(&socket);
+    (status, errors)
+}
@@ -0,0 +1,100 @@
+//! Host side of the virtio-net test.
+//!
+//! The kernel resolves 10.0.2.2 with ARP, then sends a UDP datagram to
+//! port `UDP_ECHO_PORT` there and expects it back. With `-netdev user`
+//! QEMU answers ARP itself and forwards the datagram to the host loopback,
+//! where `spawn_udp_echo` sends it back. With `-netdev socket` QEMU sends
+//! raw frames to `SOCKET_PEER_ADDR`, where `spawn_socket_peer` plays 10.0.2.2.
+
+use std::{net::UdpSocket, thread};
+
+/// Keep in sync with `UDP_ECHO_PORT` in virtio-test
+pub const UDP_ECHO_PORT: u16 = 5555;
+
+/// Where QEMU sends frames in socket mode
+pub const SOCKET_PEER_ADDR: &str = "127.0.0.1:5556";
+/// Where QEMU receives frames in socket mode
+pub const SOCKET_QEMU_ADDR: &str = "127.0.0.1:5557";
+
+const HOST_IP: [u8; 4] = [10, 0, 2, 2];
+/// The MAC QEMU user networking uses for the host
+const HOST_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Echo UDP datagrams on the host loopback, for `-netdev user`.
+pub fn spawn_udp_echo() {
+    let socket = match UdpSocket::bind(("127.0.0.1", UDP_ECHO_PORT)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("can't bind UDP echo port {}: {}", UDP_ECHO_PORT, e);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+            let _ = socket.send_to(&buf[..len], peer);
+        }
+    });
+}
+
+/// Answer ARP and echo UDP for 10.0.2.2 on raw frames, for `-netdev socket`.
+pub fn spawn_socket_peer() {
+    let socket = match UdpSocket::bind(SOCKET_PEER_ADDR) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("can't bind socket netdev peer {}: {}", SOCKET_PEER_ADDR, e);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        while let Ok(len) = socket.recv(&mut buf) {
+            let frame = &mut buf[..len];
+            let answered = answer_arp(frame) || echo_udp(frame);
+            if answered {
+                let _ = socket.send_to(frame, SOCKET_QEMU_ADDR);
+            }
+        }
+    });
+}
+
+/// Turn an ARP request for the host into the reply, in place.
+fn answer_arp(frame: &mut [u8]) -> bool {
+    if frame.len() < 42 || frame[12..14] != ETHERTYPE_ARP || frame[20..22] != [0, 1] || frame[38..42] != HOST_IP {
+        return false;
+    }
+    let mut sender = [0u8; 10];
+    sender.copy_from_slice(&frame[22..32]);
+    frame[..6].copy_from_slice(&sender[..6]);
+    frame[6..12].copy_from_slice(&HOST_MAC);
+    frame[21] = 2;
+    frame[22..28].copy_from_slice(&HOST_MAC);
+    frame[28..32].copy_from_slice(&HOST_IP);
+    frame[32..42].copy_from_slice(&sender);
+    true
+}
+
+/// Turn a UDP datagram to the echo port of the host into the echo, in place.
+///
+/// Swapping the addresses and ports leaves both checksums valid.
+fn echo_udp(frame: &mut [u8]) -> bool {
+    if frame.len() < 42 || frame[12..14] != ETHERTYPE_IPV4 || frame[23] != IP_PROTOCOL_UDP {
+        return false;
+    }
+    let ihl = (frame[14] & 0xf) as usize * 4;
+    let udp = 14 + ihl;
+    if frame[30..34] != HOST_IP || frame.len() < udp + 8 || frame[udp + 2..udp + 4] != UDP_ECHO_PORT.to_be_bytes() {
+        return false;
+    }
+    let (eth_dst, eth_src) = frame[..12].split_at_mut(6);
+    eth_dst.swap_with_slice(eth_src);
+    let (ip_src, ip_dst) = frame[26..34].split_at_mut(4);
+    ip_src.swap_with_slice(ip_dst);
+    let (src_port, dst_port) = frame[udp..udp + 4].split_at_mut(2);
+    src_port.swap_with_slice(dst_port);
+    true
+}
@@ -0,0 +1,44 @@
+//! Host side of the virtio-9p test: export a directory to the kernel, and
+//! check what it wrote there.
+
+use std::{fs, path::Path};
+
+/// The tag the directory is exported under
+pub const MOUNT_TAG: &str = "share";
+
+/// Created empty before the run for the kernel to write into.
+///
+/// Keep in sync with `P9_OUTPUT_FILE` in virtio-test
+const OUTPUT_FILE: &str = "virtio-9p-output.txt";
+const EXPECTED_OUTPUT: &str = "hello from virtio-9p\n";
+
+/// The QEMU arguments exporting `dir` over virtio-9p.
+pub fn args(dir: &Path) -> Vec<String> {
+    // security_model=none 不需要 root，也不会因为改不了属主而报错
+    vec![
+        "-fsdev".into(),
+        format!("local,id=fs0,path={},security_model=none", dir.display()),
+        "-device".into(),
+        format!("virtio-9p-device,fsdev=fs0,mount_tag={}", MOUNT_TAG),
+    ]
+}
+
+/// Create the empty output file in `dir`.
+pub fn prepare(dir: &Path) -> Result<(), String> {
+    let path = dir.join(OUTPUT_FILE);
+    fs::write(&path, "").map_err(|e| format!("create {}: {}", path.display(), e))
+}
+
+/// Check the kernel wrote the output file in `dir`, then remove it.
+pub fn check_output(dir: &Path) -> Result<(), String> {
+    let path = dir.join(OUTPUT_FILE);
+    let output = fs::read_to_string(&path).map_err(|e| format!("read {}: {}", path.display(), e));
+    let _ = fs::remove_file(&path);
+    match output? {
+        output if output == EXPECTED_OUTPUT => {
+            println!("xtask: virtio-9p output {:?} matches", output);
+            Ok(())
+        }
+        output => Err(format!("virtio-9p output is {:?}, expect {:?}", output, EXPECTED_OUTPUT)),
+    }
+}
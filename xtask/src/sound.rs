@@ -0,0 +1,89 @@
+//! Host side of the virtio-sound test: record the playback to a WAV file
+//! and check the sine tone in it.
+
+use std::{fs, path::Path};
+
+/// The tone played by the kernel.
+///
+/// Keep in sync with `SOUND_TONE_HZ` and `SOUND_AMPLITUDE` in virtio-test
+const TONE_HZ: f64 = 441.0;
+const AMPLITUDE: f64 = 16383.0;
+
+/// The kernel plays one second, allow for a clipped start and end
+const MIN_SECONDS: f64 = 0.5;
+
+/// How far the measured frequency and amplitude may be off, as a ratio
+const TOLERANCE: f64 = 0.05;
+
+/// The QEMU arguments recording virtio-sound output to `wav`, in the
+/// format the kernel plays.
+pub fn args(wav: &Path) -> Vec<String> {
+    vec![
+        "-audiodev".into(),
+        format!("wav,id=snd0,path={},out.frequency=44100,out.channels=2,out.format=s16", wav.display()),
+        "-device".into(),
+        "virtio-sound-device,audiodev=snd0".into(),
+    ]
+}
+
+/// Check the first channel of `wav` holds the tone.
+pub fn check_tone(wav: &Path) -> Result<(), String> {
+    let bytes = fs::read(wav).map_err(|e| format!("read {}: {}", wav.display(), e))?;
+    let (rate, samples) = parse_wav(&bytes).ok_or("not a 16-bit PCM WAV file")?;
+    // 只看声音出现到消失之间的部分
+    let loud = |s: &i16| (*s as f64).abs() > AMPLITUDE / 4.0;
+    let start = samples.iter().position(loud).ok_or("the recording is silent")?;
+    let end = samples.len() - samples.iter().rev().position(loud).unwrap();
+    let tone = &samples[start..end];
+    let seconds = tone.len() as f64 / rate as f64;
+    if seconds < MIN_SECONDS {
+        return Err(format!("the tone lasts {:.3}s, expect at least {}s", seconds, MIN_SECONDS));
+    }
+    let rising = tone.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count();
+    let frequency = rising as f64 / seconds;
+    let peak = tone.iter().map(|&s| (s as f64).abs()).fold(0.0, f64::max);
+    println!("xtask: virtio-sound tone of {:.3}s, {:.1} Hz, peak {}", seconds, frequency, peak);
+    if (frequency - TONE_HZ).abs() > TONE_HZ * TOLERANCE {
+        return Err(format!("the tone is {:.1} Hz, expect {} Hz", frequency, TONE_HZ));
+    }
+    if (peak - AMPLITUDE).abs() > AMPLITUDE * TOLERANCE {
+        return Err(format!("the tone peaks at {}, expect {}", peak, AMPLITUDE));
+    }
+    Ok(())
+}
+
+/// Parse a WAV file of 16-bit PCM, return its sample rate and the samples
+/// of the first channel.
+fn parse_wav(bytes: &[u8]) -> Option<(u32, Vec<i16>)> {
+    if bytes.get(0..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let u16_at = |pos: usize| Some(u16::from_le_bytes([*bytes.get(pos)?, *bytes.get(pos + 1)?]));
+    let u32_at = |pos: usize| Some(u16_at(pos)? as u32 | (u16_at(pos + 2)? as u32) << 16);
+    let mut format = None;
+    let mut pos = 12;
+    // 逐个块查找 "fmt " 和 "data"，块按两字节对齐
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = u32_at(pos + 4)? as usize;
+        let body = pos + 8;
+        match id {
+            b"fmt " => format = Some((u16_at(body)?, u16_at(body + 2)?, u32_at(body + 4)?, u16_at(body + 14)?)),
+            b"data" => {
+                let (audio_format, channels, rate, bits) = format?;
+                if audio_format != 1 || bits != 16 || channels == 0 {
+                    return None;
+                }
+                let end = (body + size).min(bytes.len());
+                let samples = bytes[body..end]
+                    .chunks_exact(2 * channels as usize)
+                    .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+                    .collect();
+                return Some((rate, samples));
+            }
+            _ => {}
+        }
+        pos = body + size + size % 2;
+    }
+    None
+}
@@ -0,0 +1,54 @@
+//! Devices emulated by a vhost-user backend process next to QEMU.
+//!
+//! The backend reaches into guest memory directly, so the memory has to be
+//! a shared memfd instead of QEMU's private allocation.
+
+use std::{
+    io,
+    path::Path,
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// QEMU virt has 128 MiB of RAM unless told otherwise
+const RAM_SIZE: &str = "128M";
+
+/// How long a backend may take to create its socket.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The QEMU arguments putting guest memory in a shared memfd.
+pub fn memory_args() -> Vec<String> {
+    vec![
+        "-object".into(),
+        format!("memory-backend-memfd,id=mem0,size={},share=on", RAM_SIZE),
+        "-machine".into(),
+        "memory-backend=mem0".into(),
+    ]
+}
+
+/// Start a backend, and wait until it listens on `socket` for QEMU.
+pub fn spawn_backend(command: &mut Command, socket: &Path) -> io::Result<Child> {
+    let _ = std::fs::remove_file(socket);
+    let mut child = command.spawn()?;
+    let start = Instant::now();
+    while !socket.exists() {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("backend exited with {}", status)));
+        }
+        if start.elapsed() > START_TIMEOUT {
+            let _ = child.kill();
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(child)
+}
+
+/// Stop the backends after QEMU exits.
+pub fn stop(backends: Vec<Child>) {
+    for mut backend in backends {
+        let _ = backend.kill();
+        let _ = backend.wait();
+    }
+}
@@ -0,0 +1,51 @@
+//! Host side of the virtio-fs test: export a directory through virtiofsd,
+//! with a fixture in it for the kernel to read.
+
+use std::{
+    env, fs, io,
+    path::Path,
+    process::{Child, Command},
+};
+
+use crate::vhost_user;
+
+/// The tag the directory is exported under
+const TAG: &str = "fs";
+
+/// Put in the directory for the run.
+///
+/// Keep in sync with `FS_FIXTURE_FILE` and `FS_FIXTURE` in virtio-test
+const FIXTURE_FILE: &str = "virtio-fs-fixture.txt";
+const FIXTURE: &str = "hello from virtio-fs\n";
+
+/// Put the fixture in `dir`, and start virtiofsd exporting it. Return the
+/// QEMU arguments and the virtiofsd process.
+///
+/// virtiofsd is looked up in `PATH`, or given by the `VIRTIOFSD`
+/// environment variable, since distributions often keep it in libexec.
+pub fn start(dir: &Path) -> io::Result<(Vec<String>, Child)> {
+    fs::write(dir.join(FIXTURE_FILE), FIXTURE)?;
+    let socket = crate::dist_dir().join("virtiofsd.sock");
+    let virtiofsd = env::var("VIRTIOFSD").unwrap_or_else(|_| "virtiofsd".into());
+    // 不用命名空间沙箱，这样不需要 root
+    let child = vhost_user::spawn_backend(
+        Command::new(virtiofsd)
+            .arg(format!("--socket-path={}", socket.display()))
+            .arg(format!("--shared-dir={}", dir.display()))
+            .arg("--cache=never")
+            .arg("--sandbox=none"),
+        &socket,
+    )?;
+    let args = vec![
+        "-chardev".into(),
+        format!("socket,id=fs0,path={}", socket.display()),
+        "-device".into(),
+        format!("vhost-user-fs-device,chardev=fs0,tag={}", TAG),
+    ];
+    Ok((args, child))
+}
+
+/// Remove the fixture from `dir`.
+pub fn cleanup(dir: &Path) {
+    let _ = fs::remove_file(dir.join(FIXTURE_FILE));
+}
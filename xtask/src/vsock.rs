@@ -0,0 +1,287 @@
+//! Host side of the virtio-vsock test: connect to the control port of the
+//! kernel, run test commands and check the results.
+//!
+//! With `vhost` QEMU hands the device to the host kernel, and xtask uses
+//! `AF_VSOCK`. Without vhost, `vhost-device-vsock` emulates the device and
+//! maps vsock ports to unix sockets.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    os::unix::{
+        io::{FromRawFd, IntoRawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    process::{Child, Command},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use crate::monitor::Hook;
+use crate::vhost_user;
+
+/// The CID given to the guest
+const GUEST_CID: u32 = 3;
+
+/// The port the kernel takes test commands on.
+///
+/// Keep in sync with `VSOCK_CONTROL_PORT` in virtio-test
+const CONTROL_PORT: u32 = 1234;
+
+/// The port the kernel connects back to for the `connect` command
+const HOST_PORT: u32 = 1235;
+
+/// Printed by the kernel once it listens on `CONTROL_PORT`
+const LISTENING: &str = "virtio-vsock listening on port";
+
+/// Commands sent to the kernel, and how the replies start.
+const COMMANDS: [(&str, &str); 4] = [
+    ("ping", "ok pong"),
+    ("echo hello vsock", "ok hello vsock"),
+    ("heap", "ok total="),
+    ("quit", "ok bye"),
+];
+
+/// What the kernel sends after connecting to `HOST_PORT`
+const HELLO: &str = "hello from virtio-vsock\n";
+
+/// How long to wait for the session after QEMU exits
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backend {
+    /// `vhost-vsock-device`, needs `/dev/vhost-vsock`
+    Vhost,
+    /// `vhost-user-vsock-device` with `vhost-device-vsock`
+    User,
+}
+
+impl Backend {
+    /// Start what the backend needs on the host, return the QEMU arguments
+    /// and the backend process if any.
+    pub fn start(self) -> io::Result<(Vec<String>, Option<Child>)> {
+        match self {
+            Backend::Vhost => {
+                let args = vec!["-device".into(), format!("vhost-vsock-device,guest-cid={}", GUEST_CID)];
+                Ok((args, None))
+            }
+            Backend::User => {
+                let socket = crate::dist_dir().join("vhost-vsock.sock");
+                let child = vhost_user::spawn_backend(
+                    Command::new("vhost-device-vsock")
+                        .arg(format!("--guest-cid={}", GUEST_CID))
+                        .arg(format!("--socket={}", socket.display()))
+                        .arg(format!("--uds-path={}", uds_path().display())),
+                    &socket,
+                )?;
+                let args = vec![
+                    "-chardev".into(),
+                    format!("socket,id=vsock0,path={}", socket.display()),
+                    "-device".into(),
+                    "vhost-user-vsock-device,chardev=vsock0".into(),
+                ];
+                Ok((args, Some(child)))
+            }
+        }
+    }
+}
+
+/// The results of the control session, which runs in its own thread.
+pub struct Session {
+    result: Receiver<Result<(), String>>,
+}
+
+impl Session {
+    /// Wait for the session to end, return whether all commands passed.
+    pub fn finish(self) -> Result<(), String> {
+        let result = match self.result.recv_timeout(FINISH_TIMEOUT) {
+            Ok(result) => result,
+            Err(_) => Err("session did not run".into()),
+        };
+        result.map_err(|e| format!("virtio-vsock: {}", e))
+    }
+}
+
+/// Start the control session when the kernel listens.
+pub fn control_hook(backend: Backend) -> (Hook, Session) {
+    let (sender, result) = mpsc::channel();
+    let hook = Hook {
+        marker: LISTENING,
+        action: Box::new(move |_monitor| {
+            let sender = sender.clone();
+            // 内核要等钩子返回才会 accept，会话只能放在另一个线程里
+            thread::spawn(move || {
+                let _ = sender.send(run_session(backend));
+            });
+            Ok(())
+        }),
+    };
+    (hook, Session { result })
+}
+
+fn run_session(backend: Backend) -> Result<(), String> {
+    let stream = connect(backend, CONTROL_PORT).map_err(|e| format!("connect to port {}: {}", CONTROL_PORT, e))?;
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream);
+    if let Backend::User = backend {
+        // vhost-device-vsock 先回应一行 "OK <本地端口>"
+        let reply = request(&mut writer, &mut reader, &format!("CONNECT {}", CONTROL_PORT))?;
+        if !reply.starts_with("OK") {
+            return Err(format!("vhost-device-vsock refused: {:?}", reply));
+        }
+    }
+    let listener = HostListener::bind(backend, HOST_PORT).map_err(|e| format!("listen on port {}: {}", HOST_PORT, e))?;
+    let reply = request(&mut writer, &mut reader, &format!("connect {}", HOST_PORT))?;
+    check_reply("connect", &reply, "ok sent=")?;
+    let mut hello = String::new();
+    listener
+        .accept()
+        .and_then(|mut stream| stream.read_to_string(&mut hello))
+        .map_err(|e| format!("accept on port {}: {}", HOST_PORT, e))?;
+    if hello != HELLO {
+        return Err(format!("guest sent {:?}, expect {:?}", hello, HELLO));
+    }
+    for (command, expected) in &COMMANDS {
+        let reply = request(&mut writer, &mut reader, command)?;
+        check_reply(command, &reply, expected)?;
+    }
+    println!("xtask: virtio-vsock session passed");
+    Ok(())
+}
+
+/// Send a line, return the reply line.
+fn request(writer: &mut File, reader: &mut BufReader<File>, line: &str) -> Result<String, String> {
+    writeln!(writer, "{}", line).map_err(|e| format!("send {:?}: {}", line, e))?;
+    let mut reply = String::new();
+    match reader.read_line(&mut reply) {
+        Ok(0) => Err(format!("connection closed after {:?}", line)),
+        Ok(_) => Ok(reply.trim_end().to_string()),
+        Err(e) => Err(format!("reply to {:?}: {}", line, e)),
+    }
+}
+
+fn check_reply(command: &str, reply: &str, expected: &str) -> Result<(), String> {
+    println!("xtask: virtio-vsock {:?} -> {:?}", command, reply);
+    if reply.starts_with(expected) {
+        Ok(())
+    } else {
+        Err(format!("{:?} replied {:?}, expect {:?}", command, reply, expected))
+    }
+}
+
+/// Where vhost-device-vsock takes host connections, and connects to for
+/// guest connections to port `P` at `<path>_P`.
+fn uds_path() -> PathBuf {
+    crate::dist_dir().join("vsock.sock")
+}
+
+/// Open a connection to `port` of the guest.
+///
+/// Both kinds of sockets end up as `File`, which only reads and writes.
+fn connect(backend: Backend, port: u32) -> io::Result<File> {
+    match backend {
+        Backend::Vhost => {
+            let fd = vsock_socket()?;
+            let addr = vsock_addr(GUEST_CID, port);
+            let ret = unsafe { libc::connect(fd.0, &addr as *const _ as _, mem::size_of_val(&addr) as _) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(fd.into_file())
+        }
+        Backend::User => {
+            let stream = UnixStream::connect(uds_path())?;
+            Ok(unsafe { File::from_raw_fd(stream.into_raw_fd()) })
+        }
+    }
+}
+
+/// Takes connections from the guest to a host port.
+enum HostListener {
+    Vhost(Fd),
+    User(UnixListener, PathBuf),
+}
+
+impl HostListener {
+    fn bind(backend: Backend, port: u32) -> io::Result<Self> {
+        match backend {
+            Backend::Vhost => {
+                let fd = vsock_socket()?;
+                let addr = vsock_addr(libc::VMADDR_CID_ANY, port);
+                unsafe {
+                    if libc::bind(fd.0, &addr as *const _ as _, mem::size_of_val(&addr) as _) < 0
+                        || libc::listen(fd.0, 1) < 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(HostListener::Vhost(fd))
+            }
+            Backend::User => {
+                let path = PathBuf::from(format!("{}_{}", uds_path().display(), port));
+                let _ = std::fs::remove_file(&path);
+                Ok(HostListener::User(UnixListener::bind(&path)?, path))
+            }
+        }
+    }
+
+    fn accept(&self) -> io::Result<File> {
+        match self {
+            HostListener::Vhost(fd) => {
+                let ret = unsafe { libc::accept(fd.0, std::ptr::null_mut(), std::ptr::null_mut()) };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Fd(ret).into_file())
+            }
+            HostListener::User(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                Ok(unsafe { File::from_raw_fd(stream.into_raw_fd()) })
+            }
+        }
+    }
+}
+
+impl Drop for HostListener {
+    fn drop(&mut self) {
+        if let HostListener::User(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A file descriptor closed on drop.
+struct Fd(libc::c_int);
+
+impl Fd {
+    fn into_file(self) -> File {
+        let file = unsafe { File::from_raw_fd(self.0) };
+        mem::forget(self);
+        file
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn vsock_socket() -> io::Result<Fd> {
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Fd(fd))
+}
+
+fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}